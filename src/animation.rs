@@ -0,0 +1,106 @@
+//! Static rendering of CSS animations.
+//!
+//! Rather than running animations over time, this computes the values of animated properties at a
+//! single moment, so that one frame of an animation can be rendered.
+
+use css::{Keyframes, Value, Unit, LengthContext};
+use style::StyledNode;
+use std::num::Float;
+
+/// Apply the `@keyframes` animations named by each node's `animation-name` property, as they
+/// appear `time` seconds after the animations started.
+///
+/// `context` is the one the tree was styled with, which computes the lengths in the keyframes.
+pub fn apply_animations(node: &mut StyledNode, keyframes: &[Keyframes], time: f32, context: &LengthContext) {
+    if let Some(Value::Keyword(name)) = node.value("animation-name") {
+        if let Some(animation) = keyframes.iter().find(|k| k.name == name) {
+            let duration = match node.value("animation-duration") {
+                Some(Value::Length(t, Unit::S)) => t,
+                Some(Value::Length(t, Unit::Ms)) => t / 1000.0,
+                _ => 0.0
+            };
+            // A zero-length animation jumps straight to its end state.
+            let progress = if duration > 0.0 { (time / duration).max(0.0).min(1.0) } else { 1.0 };
+
+            for property in property_names(animation).iter() {
+                if let Some(value) = animated_value(node, animation, property.as_slice(), progress, context) {
+                    node.specified_values.insert(property.clone(), value);
+                }
+            }
+        }
+    }
+
+    // Rems are relative to the root element's font size, as in `style::style_tree`.
+    let child_context = if node.node.parent().is_none() {
+        context.below_root(node.specified_values.get("font-size"))
+    } else {
+        *context
+    };
+    for child in node.children.iter_mut() {
+        apply_animations(child, keyframes, time, &child_context);
+    }
+}
+
+/// All the properties that appear in any frame of an animation.
+fn property_names(animation: &Keyframes) -> Vec<String> {
+    let mut names: Vec<String> = Vec::new();
+    for &(_, ref declarations) in animation.frames.iter() {
+        for declaration in declarations.iter() {
+            if !names.contains(&declaration.name) {
+                names.push(declaration.name.clone());
+            }
+        }
+    }
+    names
+}
+
+/// The value of property `name` at `progress` (from 0 to 1) through an animation.
+///
+/// The value is interpolated between the nearest keyframes before and after `progress` that set
+/// the property.  If there is no such keyframe, the node's own value is used in its place.
+/// Keyframe values are computed with `context` first, so that they interpolate with px values.
+fn animated_value(node: &StyledNode, animation: &Keyframes, name: &str, progress: f32, context: &LengthContext) -> Option<Value> {
+    let base = node.value(name);
+    let mut from = (0f32, base.clone());
+    let mut to = (1f32, base);
+    let mut found_to = false;
+
+    for &(offset, ref declarations) in animation.frames.iter() {
+        // The last declaration of a property in a keyframe wins.
+        let value = match declarations.iter().rev().find(|d| d.name == name) {
+            Some(declaration) => declaration.value.compute_lengths(context),
+            None => continue
+        };
+        if offset <= progress {
+            from = (offset, Some(value));
+        } else if !found_to {
+            to = (offset, Some(value));
+            found_to = true;
+        }
+    }
+
+    match (from, to) {
+        ((from_offset, Some(from_value)), (to_offset, Some(to_value))) => {
+            if to_offset <= from_offset {
+                return Some(from_value);
+            }
+            let t = (progress - from_offset) / (to_offset - from_offset);
+            Some(interpolate(&from_value, &to_value, t))
+        }
+        ((_, from_value), (_, to_value)) => from_value.or(to_value)
+    }
+}
+
+/// Interpolate between two values.  Lengths in the same unit and colors change smoothly; anything
+/// else switches from one value to the other halfway through.
+fn interpolate(from: &Value, to: &Value, t: f32) -> Value {
+    match (from, to) {
+        (&Value::Length(a, ref unit_a), &Value::Length(b, ref unit_b)) if unit_a == unit_b => {
+            Value::Length(a + (b - a) * t, unit_a.clone())
+        }
+        (&Value::ColorValue(a), &Value::ColorValue(b)) => {
+            Value::ColorValue(a.lerp(b, t))
+        }
+        _ => if t < 0.5 { from.clone() } else { to.clone() }
+    }
+}
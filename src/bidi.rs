@@ -0,0 +1,104 @@
+//! A minimal version of the Unicode bidirectional algorithm, enough to display a line of text
+//! that mixes left-to-right and right-to-left scripts.
+//!
+//! http://www.unicode.org/reports/tr9/
+//!
+//! There are no embedding levels, explicit formatting characters, or mirrored brackets.  Every
+//! letter or digit is strongly left-to-right unless it is from a right-to-left script, and
+//! everything else takes the direction of the text around it.
+
+use std::iter::range;
+
+#[derive(Show, Clone, PartialEq)]
+pub enum Direction {
+    Ltr,
+    Rtl,
+}
+
+impl Copy for Direction {}
+
+/// The direction of a character that has one of its own, or `None` for spaces, punctuation and
+/// other neutral characters.
+fn strong_direction(c: char) -> Option<Direction> {
+    match c {
+        // Hebrew, Arabic, Syriac, Thaana, N'Ko, Samaritan, Mandaic, and their presentation forms.
+        '\u{0590}'...'\u{08FF}' | '\u{FB1D}'...'\u{FDFF}' | '\u{FE70}'...'\u{FEFF}' => Some(Direction::Rtl),
+        c if c.is_alphanumeric() => Some(Direction::Ltr),
+        _ => None
+    }
+}
+
+/// Reorder a line of text from the order it is stored in to the order it is displayed in, from
+/// left to right, in a paragraph whose direction is `base`.
+pub fn reorder(text: &str, base: Direction) -> String {
+    let chars: Vec<char> = text.chars().collect();
+    let strong: Vec<Option<Direction>> = chars.iter().map(|&c| strong_direction(c)).collect();
+
+    // A neutral character between two characters of the same direction takes that direction.
+    // Any other neutral character takes the paragraph's direction.
+    let mut resolved = Vec::with_capacity(chars.len());
+    for i in range(0, chars.len()) {
+        resolved.push(match strong[i] {
+            Some(direction) => direction,
+            None => {
+                let before = strong.slice_to(i).iter().rev().filter_map(|d| *d).next();
+                let after = strong.slice_from(i + 1).iter().filter_map(|d| *d).next();
+                match (before, after) {
+                    (Some(a), Some(b)) if a == b => a,
+                    _ => base
+                }
+            }
+        });
+    }
+
+    // Split the line into runs of one direction.  Right-to-left runs are displayed backwards.
+    let mut runs: Vec<(Direction, Vec<char>)> = Vec::new();
+    for (&c, &direction) in chars.iter().zip(resolved.iter()) {
+        let same_run = match runs.last() {
+            Some(&(run_direction, _)) => run_direction == direction,
+            None => false
+        };
+        if same_run {
+            runs.last_mut().unwrap().1.push(c);
+        } else {
+            runs.push((direction, vec![c]));
+        }
+    }
+    for run in runs.iter_mut() {
+        if run.0 == Direction::Rtl {
+            run.1.reverse();
+        }
+    }
+
+    // In a right-to-left paragraph, the runs themselves go from right to left.
+    if base == Direction::Rtl {
+        runs.reverse();
+    }
+    runs.into_iter().flat_map(|(_, run)| run.into_iter()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{reorder, Direction};
+
+    #[test]
+    fn hebrew_inside_latin_is_reversed_in_place() {
+        assert_eq!(reorder("abc אבג def", Direction::Ltr).as_slice(), "abc גבא def");
+    }
+
+    #[test]
+    fn neutrals_between_right_to_left_letters_move_with_them() {
+        assert_eq!(reorder("אב, גד", Direction::Ltr).as_slice(), "דג ,בא");
+    }
+
+    #[test]
+    fn right_to_left_paragraph_puts_runs_right_to_left() {
+        assert_eq!(reorder("אבג abc", Direction::Rtl).as_slice(), "abc גבא");
+        assert_eq!(reorder("abc def", Direction::Rtl).as_slice(), "abc def");
+    }
+
+    #[test]
+    fn left_to_right_text_is_unchanged() {
+        assert_eq!(reorder("one, two 3", Direction::Ltr).as_slice(), "one, two 3");
+    }
+}
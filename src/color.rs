@@ -1,5 +1,7 @@
 
 use std::collections::HashMap;
+use std::num::{FromStrRadix, Float};
+use std::ascii::AsciiExt; // for `to_ascii_lowercase`
 
 #[derive(Show, Clone, PartialEq, Default)]
 pub struct Color {
@@ -14,8 +16,245 @@ impl Color {
     pub fn new(r: u8, g: u8, b: u8, a: u8) -> Color {
         Color { r: r, g: g, b: b, a: a, }
     }
+
+    /// Parse a hexadecimal color in `rgb`, `rgba`, `rrggbb`, or `rrggbbaa` form, without the
+    /// leading `#`.  Without an alpha component the color is opaque.
+    pub fn from_hex(hex: &str) -> Option<Color> {
+        let mut digits = String::new();
+        match hex.len() {
+            3 | 4 => for c in hex.chars() { digits.push(c); digits.push(c); },
+            6 | 8 => digits.push_str(hex),
+            _ => return None,
+        }
+        if digits.len() == 6 {
+            digits.push_str("ff");
+        }
+
+        let r: Option<u8> = FromStrRadix::from_str_radix(digits.slice(0, 2), 0x10);
+        let g: Option<u8> = FromStrRadix::from_str_radix(digits.slice(2, 4), 0x10);
+        let b: Option<u8> = FromStrRadix::from_str_radix(digits.slice(4, 6), 0x10);
+        let a: Option<u8> = FromStrRadix::from_str_radix(digits.slice(6, 8), 0x10);
+        match (r, g, b, a) {
+            (Some(r), Some(g), Some(b), Some(a)) => Some(Color::new(r, g, b, a)),
+            _ => None,
+        }
+    }
+
+    /// The color a fraction `t` of the way from this color to `other`, interpolating each channel,
+    /// including alpha, separately.
+    pub fn lerp(self, other: Color, t: f32) -> Color {
+        let lerp = |&: a: u8, b: u8| -> u8 {
+            (a as f32 + (b as f32 - a as f32) * t).round() as u8
+        };
+        Color {
+            r: lerp(self.r, other.r),
+            g: lerp(self.g, other.g),
+            b: lerp(self.b, other.b),
+            a: lerp(self.a, other.a),
+        }
+    }
+
+    /// Composite this color over `dst`, using this color's alpha channel as its opacity.
+    ///
+    /// With `gamma_correct`, the channels are blended in linear light rather than directly on
+    /// their sRGB values, which keeps anti-aliased edges from looking too dark.  A translucent
+    /// `dst` contributes in proportion to its own alpha, so painting over a transparent layer
+    /// leaves the color unchanged.
+    pub fn blend_over(self, dst: Color, gamma_correct: bool) -> Color {
+        let alpha = self.a as f32 / 255.0;
+        let dst_alpha = dst.a as f32 / 255.0 * (1.0 - alpha);
+        let out_alpha = alpha + dst_alpha;
+        if out_alpha == 0.0 {
+            return dst;
+        }
+        let blend = |&: src: u8, dst: u8| -> u8 {
+            if gamma_correct {
+                let linear = (to_linear(src) * alpha + to_linear(dst) * dst_alpha) / out_alpha;
+                from_linear(linear)
+            } else {
+                ((src as f32 * alpha + dst as f32 * dst_alpha) / out_alpha).round() as u8
+            }
+        };
+        Color {
+            r: blend(self.r, dst.r),
+            g: blend(self.g, dst.g),
+            b: blend(self.b, dst.b),
+            a: (out_alpha * 255.0).round() as u8,
+        }
+    }
+}
+
+/// Convert an sRGB channel value to linear light, from 0 to 1.
+fn to_linear(channel: u8) -> f32 {
+    let c = channel as f32 / 255.0;
+    if c <= 0.04045 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Convert linear light, from 0 to 1, back to an sRGB channel value.
+fn from_linear(linear: f32) -> u8 {
+    let c = if linear <= 0.0031308 { linear * 12.92 } else { 1.055 * linear.powf(1.0 / 2.4) - 0.055 };
+    (c.max(0.0).min(1.0) * 255.0).round() as u8
+}
+
+/// Parse a color given as `#hex`, a named color, or `transparent`.
+pub fn parse(source: &str) -> Option<Color> {
+    if source.starts_with("#") {
+        return Color::from_hex(source.slice_from(1));
+    }
+    ColorMap::new().get_color(source).map(|color| *color)
 }
 
+/// The CSS named colors, as (name, red, green, blue).
+static NAMED_COLORS: [(&'static str, u8, u8, u8); 147] = [
+    ("aliceblue", 240, 248, 255),
+    ("antiquewhite", 250, 235, 215),
+    ("aqua", 0, 255, 255),
+    ("aquamarine", 127, 255, 212),
+    ("azure", 240, 255, 255),
+    ("beige", 245, 245, 220),
+    ("bisque", 255, 228, 196),
+    ("black", 0, 0, 0),
+    ("blanchedalmond", 255, 235, 205),
+    ("blue", 0, 0, 255),
+    ("blueviolet", 138, 43, 226),
+    ("brown", 165, 42, 42),
+    ("burlywood", 222, 184, 135),
+    ("cadetblue", 95, 158, 160),
+    ("chartreuse", 127, 255, 0),
+    ("chocolate", 210, 105, 30),
+    ("coral", 255, 127, 80),
+    ("cornflowerblue", 100, 149, 237),
+    ("cornsilk", 255, 248, 220),
+    ("crimson", 220, 20, 60),
+    ("cyan", 0, 255, 255),
+    ("darkblue", 0, 0, 139),
+    ("darkcyan", 0, 139, 139),
+    ("darkgoldenrod", 184, 134, 11),
+    ("darkgray", 169, 169, 169),
+    ("darkgreen", 0, 100, 0),
+    ("darkgrey", 169, 169, 169),
+    ("darkkhaki", 189, 183, 107),
+    ("darkmagenta", 139, 0, 139),
+    ("darkolivegreen", 85, 107, 47),
+    ("darkorange", 255, 140, 0),
+    ("darkorchid", 153, 50, 204),
+    ("darkred", 139, 0, 0),
+    ("darksalmon", 233, 150, 122),
+    ("darkseagreen", 143, 188, 143),
+    ("darkslateblue", 72, 61, 139),
+    ("darkslategray", 47, 79, 79),
+    ("darkslategrey", 47, 79, 79),
+    ("darkturquoise", 0, 206, 209),
+    ("darkviolet", 148, 0, 211),
+    ("deeppink", 255, 20, 147),
+    ("deepskyblue", 0, 191, 255),
+    ("dimgray", 105, 105, 105),
+    ("dimgrey", 105, 105, 105),
+    ("dodgerblue", 30, 144, 255),
+    ("firebrick", 178, 34, 34),
+    ("floralwhite", 255, 250, 240),
+    ("forestgreen", 34, 139, 34),
+    ("fuchsia", 255, 0, 255),
+    ("gainsboro", 220, 220, 220),
+    ("ghostwhite", 248, 248, 255),
+    ("gold", 255, 215, 0),
+    ("goldenrod", 218, 165, 32),
+    ("gray", 128, 128, 128),
+    ("green", 0, 128, 0),
+    ("greenyellow", 173, 255, 47),
+    ("grey", 128, 128, 128),
+    ("honeydew", 240, 255, 240),
+    ("hotpink", 255, 105, 180),
+    ("indianred", 205, 92, 92),
+    ("indigo", 75, 0, 130),
+    ("ivory", 255, 255, 240),
+    ("khaki", 240, 230, 140),
+    ("lavender", 230, 230, 250),
+    ("lavenderblush", 255, 240, 245),
+    ("lawngreen", 124, 252, 0),
+    ("lemonchiffon", 255, 250, 205),
+    ("lightblue", 173, 216, 230),
+    ("lightcoral", 240, 128, 128),
+    ("lightcyan", 224, 255, 255),
+    ("lightgoldenrodyellow", 250, 250, 210),
+    ("lightgray", 211, 211, 211),
+    ("lightgreen", 144, 238, 144),
+    ("lightgrey", 211, 211, 211),
+    ("lightpink", 255, 182, 193),
+    ("lightsalmon", 255, 160, 122),
+    ("lightseagreen", 32, 178, 170),
+    ("lightskyblue", 135, 206, 250),
+    ("lightslategray", 119, 136, 153),
+    ("lightslategrey", 119, 136, 153),
+    ("lightsteelblue", 176, 196, 222),
+    ("lightyellow", 255, 255, 224),
+    ("lime", 0, 255, 0),
+    ("limegreen", 50, 205, 50),
+    ("linen", 250, 240, 230),
+    ("magenta", 255, 0, 255),
+    ("maroon", 128, 0, 0),
+    ("mediumaquamarine", 102, 205, 170),
+    ("mediumblue", 0, 0, 205),
+    ("mediumorchid", 186, 85, 211),
+    ("mediumpurple", 147, 112, 219),
+    ("mediumseagreen", 60, 179, 113),
+    ("mediumslateblue", 123, 104, 238),
+    ("mediumspringgreen", 0, 250, 154),
+    ("mediumturquoise", 72, 209, 204),
+    ("mediumvioletred", 199, 21, 133),
+    ("midnightblue", 25, 25, 112),
+    ("mintcream", 245, 255, 250),
+    ("mistyrose", 255, 228, 225),
+    ("moccasin", 255, 228, 181),
+    ("navajowhite", 255, 222, 173),
+    ("navy", 0, 0, 128),
+    ("oldlace", 253, 245, 230),
+    ("olive", 128, 128, 0),
+    ("olivedrab", 107, 142, 35),
+    ("orange", 255, 165, 0),
+    ("orangered", 255, 69, 0),
+    ("orchid", 218, 112, 214),
+    ("palegoldenrod", 238, 232, 170),
+    ("palegreen", 152, 251, 152),
+    ("paleturquoise", 175, 238, 238),
+    ("palevioletred", 219, 112, 147),
+    ("papayawhip", 255, 239, 213),
+    ("peachpuff", 255, 218, 185),
+    ("peru", 205, 133, 63),
+    ("pink", 255, 192, 203),
+    ("plum", 221, 160, 221),
+    ("powderblue", 176, 224, 230),
+    ("purple", 128, 0, 128),
+    ("red", 255, 0, 0),
+    ("rosybrown", 188, 143, 143),
+    ("royalblue", 65, 105, 225),
+    ("saddlebrown", 139, 69, 19),
+    ("salmon", 250, 128, 114),
+    ("sandybrown", 244, 164, 96),
+    ("seagreen", 46, 139, 87),
+    ("seashell", 255, 245, 238),
+    ("sienna", 160, 82, 45),
+    ("silver", 192, 192, 192),
+    ("skyblue", 135, 206, 235),
+    ("slateblue", 106, 90, 205),
+    ("slategray", 112, 128, 144),
+    ("slategrey", 112, 128, 144),
+    ("snow", 255, 250, 250),
+    ("springgreen", 0, 255, 127),
+    ("steelblue", 70, 130, 180),
+    ("tan", 210, 180, 140),
+    ("teal", 0, 128, 128),
+    ("thistle", 216, 191, 216),
+    ("tomato", 255, 99, 71),
+    ("turquoise", 64, 224, 208),
+    ("violet", 238, 130, 238),
+    ("wheat", 245, 222, 179),
+    ("white", 255, 255, 255),
+    ("whitesmoke", 245, 245, 245),
+    ("yellow", 255, 255, 0),
+    ("yellowgreen", 154, 205, 50),
+];
+
 pub struct ColorMap {
     map: HashMap<String, Color>,
 }
@@ -24,18 +263,85 @@ impl ColorMap {
     pub fn new() -> ColorMap {
         let mut map = HashMap::new();
 
-        map.insert("black".to_string(), Color::new(0, 0, 0, 255));
-        map.insert("white".to_string(), Color::new(255, 255, 255, 255));
-        map.insert("red".to_string(), Color::new(255, 0, 0, 255));
-        map.insert("green".to_string(), Color::new(0, 255, 0, 255));
-        map.insert("blue".to_string(), Color::new(0, 0, 255, 255));
+        for &(name, r, g, b) in NAMED_COLORS.iter() {
+            map.insert(name.to_string(), Color::new(r, g, b, 255));
+        }
+        map.insert("transparent".to_string(), Color::new(0, 0, 0, 0));
 
         ColorMap {
             map: map,
         }
     }
 
+    /// Look up a color name, ignoring case.
     pub fn get_color(&self, string: &str) -> Option<&Color> {
-        self.map.get(string)
+        self.map.get(&string.to_ascii_lowercase())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Color;
+
+    #[test]
+    fn from_hex_reads_short_forms() {
+        assert_eq!(Color::from_hex("f80"), Some(Color::new(0xff, 0x88, 0x00, 0xff)));
+        assert_eq!(Color::from_hex("f808"), Some(Color::new(0xff, 0x88, 0x00, 0x88)));
+    }
+
+    #[test]
+    fn from_hex_reads_long_forms() {
+        assert_eq!(Color::from_hex("12ab34"), Some(Color::new(0x12, 0xab, 0x34, 0xff)));
+        assert_eq!(Color::from_hex("12AB3480"), Some(Color::new(0x12, 0xab, 0x34, 0x80)));
+    }
+
+    #[test]
+    fn from_hex_keeps_alpha_through_a_round_trip() {
+        let color = Color::from_hex("0a0b0c0d").unwrap();
+        let hex = format!("{:02x}{:02x}{:02x}{:02x}", color.r, color.g, color.b, color.a);
+        assert_eq!(hex.as_slice(), "0a0b0c0d");
+        assert_eq!(Color::from_hex(hex.as_slice()), Some(color));
+    }
+
+    #[test]
+    fn from_hex_rejects_other_lengths_and_digits() {
+        assert_eq!(Color::from_hex(""), None);
+        assert_eq!(Color::from_hex("12345"), None);
+        assert_eq!(Color::from_hex("1234567"), None);
+        assert_eq!(Color::from_hex("12g"), None);
+    }
+
+    #[test]
+    fn lerp_returns_the_endpoints() {
+        let black = Color::new(0, 0, 0, 255);
+        let white = Color::new(255, 255, 255, 255);
+        assert_eq!(black.lerp(white, 0.0), black);
+        assert_eq!(black.lerp(white, 1.0), white);
+    }
+
+    #[test]
+    fn lerp_halfway_gives_mid_gray_and_mid_alpha() {
+        let black = Color::new(0, 0, 0, 255);
+        let white = Color::new(255, 255, 255, 255);
+        assert_eq!(black.lerp(white, 0.5), Color::new(128, 128, 128, 255));
+        let clear = Color::new(0, 0, 0, 0);
+        assert_eq!(clear.lerp(Color::new(200, 100, 0, 200), 0.5), Color::new(100, 50, 0, 100));
+    }
+
+    #[test]
+    fn blending_in_linear_light_differs_from_srgb() {
+        let black = Color::new(0, 0, 0, 255);
+        let half_white = Color::new(255, 255, 255, 128);
+        let srgb = half_white.blend_over(black, false);
+        let linear = half_white.blend_over(black, true);
+        assert_eq!(srgb, Color::new(128, 128, 128, 255));
+        // Half the light of white is much brighter than half its sRGB value.
+        assert!(linear.r > srgb.r + 40);
+        assert_eq!(linear.a, 255);
+
+        // Opaque colors replace what is under them either way.
+        let red = Color::new(255, 0, 0, 255);
+        assert_eq!(red.blend_over(black, false), red);
+        assert_eq!(red.blend_over(black, true), red);
     }
 }
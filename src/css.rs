@@ -5,7 +5,7 @@
 
 use std::ascii::OwnedAsciiExt; // for `into_ascii_lowercase`
 use std::str::FromStr;
-use std::num::FromStrRadix;
+use std::num::Float; // for `min` and `max`
 use color::{Color, ColorMap};
 use shorthand;
 
@@ -14,14 +14,34 @@ use shorthand;
 #[derive(Show)]
 pub struct Stylesheet {
     pub rules: Vec<Rule>,
+    pub keyframes: Vec<Keyframes>,
 }
 
 #[derive(Show)]
 pub struct Rule {
     pub selectors: Vec<Selector>,
     pub declarations: Vec<Declaration>,
+    pub origin: Origin,
 }
 
+/// A syntax error in a stylesheet, at a byte offset in its source.  The parser skips the
+/// declaration or rule the error is in, and carries on with the next one.
+#[derive(Show, Clone)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+/// Where a rule came from.  Author rules win over user agent rules in the cascade, except that
+/// user agent `!important` declarations win over author ones.
+#[derive(Show, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Origin {
+    UserAgent,
+    Author,
+}
+
+impl Copy for Origin {}
+
 #[derive(Show)]
 pub enum Selector {
     Simple(SimpleSelector),
@@ -33,14 +53,89 @@ pub struct SimpleSelector {
     pub tag_name: Option<String>,
     pub id: Option<String>,
     pub class: Vec<String>,
+    /// The pseudo-element this selector targets, like `first-letter` in `p::first-letter`.
+    pub pseudo_element: Option<String>,
+    pub pseudo_classes: Vec<PseudoClass>,
+    pub attributes: Vec<AttrSelector>,
+}
+
+/// An attribute selector, like `[type="text"]`.
+#[derive(Show)]
+pub struct AttrSelector {
+    pub name: String,
+    pub operator: AttrOperator,
+}
+
+#[derive(Show)]
+pub enum AttrOperator {
+    /// `[attr]`: the element has the attribute.
+    Exists,
+    /// `[attr=value]`: the attribute is exactly `value`.
+    Equals(String),
+    /// `[attr~=value]`: one of the attribute's whitespace-separated words is `value`.
+    Includes(String),
+    /// `[attr^=value]`: the attribute starts with `value`.
+    Prefix(String),
+}
+
+impl AttrOperator {
+    /// Does the attribute value `value` satisfy this operator?
+    pub fn matches(&self, value: &str) -> bool {
+        match *self {
+            AttrOperator::Exists => true,
+            AttrOperator::Equals(ref expected) => value == expected.as_slice(),
+            AttrOperator::Includes(ref word) => value.words().any(|w| w == word.as_slice()),
+            AttrOperator::Prefix(ref prefix) => !prefix.is_empty() && value.starts_with(prefix.as_slice()),
+        }
+    }
 }
 
-pub type DescendantSelector = Vec<SimpleSelector>;
+#[derive(Show)]
+pub enum PseudoClass {
+    /// `:is(...)` matches an element that any of its selectors match.  Its specificity is that of
+    /// its most specific selector.
+    Is(Vec<Selector>),
+    /// `:where(...)` matches like `:is()`, but adds nothing to the specificity.
+    Where(Vec<Selector>),
+    FirstChild,
+    LastChild,
+    /// `:nth-child(an+b)`, with `a` and `b`.  It matches an element whose position among its
+    /// parent's elements, counting from 1, is `an+b` for some `n` of 0 or more.
+    NthChild(i32, i32),
+}
+
+/// A chain of simple selectors, each paired with the combinator that joins it to the one before
+/// it.  The first combinator is unused.
+pub type DescendantSelector = Vec<(Combinator, SimpleSelector)>;
+
+#[derive(Show, PartialEq)]
+pub enum Combinator {
+    /// Whitespace, as in `ul li`: any ancestor.
+    Descendant,
+    /// `>`, as in `ul > li`: the parent.
+    Child,
+    /// `+`, as in `h1 + p`: the element just before.
+    NextSibling,
+    /// `~`, as in `h1 ~ p`: any element before, under the same parent.
+    SubsequentSibling,
+}
 
+impl Copy for Combinator {}
+
+/// The frames of a named `@keyframes` animation.
 #[derive(Show)]
+pub struct Keyframes {
+    pub name: String,
+    /// Declarations at each offset, from 0 (`from`) to 1 (`to`), sorted by offset.
+    pub frames: Vec<(f32, Vec<Declaration>)>,
+}
+
+#[derive(Show, Clone)]
 pub struct Declaration {
     pub name: String,
     pub value: Value,
+    /// Set by `!important`, which puts the declaration ahead of normal ones in the cascade.
+    pub important: bool,
 }
 
 #[derive(Show, Clone, PartialEq)]
@@ -48,159 +143,483 @@ pub enum Value {
     Keyword(String),
     Length(f32, Unit),
     ColorValue(Color),
+    /// A comma-separated list of values.
+    List(Vec<Value>),
+    /// A function like `fit-content(200px)`, with its arguments.
+    Function(String, Vec<Value>),
 }
 
 #[derive(Show, Clone, PartialEq)]
 pub enum Unit {
     Px,
     Em,
+    Rem,
+    Pt,
+    Vw,
+    Vh,
     Percent,
+    S,
+    Ms,
     Default,
 }
 
 pub type Specificity = (usize, usize, usize);
 
+/// The font size that rems, and ems in `font-size`, are relative to when no element sets one.
 static FONT_SIZE: f32 = 10.0f32;
 
+impl Stylesheet {
+    /// Add the rules and keyframes from `other` after this sheet's own, so that they win over
+    /// rules of equal specificity.
+    pub fn merge(&mut self, other: Stylesheet) {
+        self.rules.extend(other.rules.into_iter());
+        self.keyframes.extend(other.keyframes.into_iter());
+    }
+}
+
 impl Selector {
     pub fn specificity(&self) -> Specificity {
         // http://www.w3.org/TR/selectors/#specificity
         match *self {
-            Selector::Simple(ref simple) => {
-                let a = simple.id.iter().len();
-                let b = simple.class.len();
-                let c = simple.tag_name.iter().len();
-                return (a, b, c);
-            },
+            Selector::Simple(ref simple) => simple.specificity(),
             Selector::Descendant(ref descendant) => {
                 let mut specificity = (0, 0, 0);
-                for i in descendant.iter() {
-                    specificity.0 += i.id.iter().len();
-                    specificity.1 += i.class.len();
-                    specificity.2 += i.tag_name.iter().len();
+                for &(_, ref i) in descendant.iter() {
+                    let (a, b, c) = i.specificity();
+                    specificity.0 += a;
+                    specificity.1 += b;
+                    specificity.2 += c;
                 }
                 return specificity;
             }
         }
     }
+
+    /// The pseudo-element targeted by this selector, if any.
+    pub fn pseudo_element(&self) -> Option<&str> {
+        let subject = match *self {
+            Selector::Simple(ref simple) => simple,
+            Selector::Descendant(ref descendant) => &descendant.last().unwrap().1,
+        };
+        subject.pseudo_element.as_ref().map(|name| name.as_slice())
+    }
+}
+
+impl SimpleSelector {
+    pub fn specificity(&self) -> Specificity {
+        let mut a = self.id.iter().len();
+        let mut b = self.class.len() + self.attributes.len();
+        let mut c = self.tag_name.iter().len() + self.pseudo_element.iter().len();
+        for pseudo_class in self.pseudo_classes.iter() {
+            match *pseudo_class {
+                PseudoClass::Is(ref selectors) => {
+                    let (x, y, z) = selectors.iter().map(|selector| selector.specificity()).max().unwrap_or((0, 0, 0));
+                    a += x;
+                    b += y;
+                    c += z;
+                }
+                PseudoClass::Where(_) => {}
+                _ => b += 1,
+            }
+        }
+        (a, b, c)
+    }
+}
+
+/// What `rem`, `vw`, and `vh` lengths are relative to: the root element's font size and the
+/// size of the viewport.
+#[derive(Show, Clone)]
+pub struct LengthContext {
+    pub root_font_size: f32,
+    pub viewport_width: f32,
+    pub viewport_height: f32,
+}
+
+impl Copy for LengthContext {}
+
+impl LengthContext {
+    /// The context for the root element in a viewport of this size.  Its own rems are relative to
+    /// the default font size.
+    pub fn new(viewport_width: f32, viewport_height: f32) -> LengthContext {
+        LengthContext {
+            root_font_size: FONT_SIZE,
+            viewport_width: viewport_width,
+            viewport_height: viewport_height,
+        }
+    }
+
+    /// The context for the descendants of the root element, given the root's computed
+    /// `font-size`, if it has one.
+    pub fn below_root(&self, root_font_size: Option<&Value>) -> LengthContext {
+        match root_font_size.and_then(|size| size.to_px(self.root_font_size)) {
+            Some(size) => LengthContext { root_font_size: size, ..*self },
+            None => *self
+        }
+    }
 }
 
 impl Value {
-    /// Return the size of a length in px, or zero for non-lengths.
-    pub fn to_px(&self) -> Option<f32> {
+    /// The computed value: rems, `vw`, `vh`, and points converted to px using `context`, including
+    /// inside functions and lists.  A point is 1/72 of an inch, which is 96px.
+    ///
+    /// Ems and percentages are left as they are, since they depend on the element's font size and
+    /// its containing block.
+    pub fn compute_lengths(&self, context: &LengthContext) -> Value {
+        match *self {
+            Value::Length(f, Unit::Pt) => Value::Length(f * 96.0 / 72.0, Unit::Px),
+            Value::Length(f, Unit::Rem) => Value::Length(f * context.root_font_size, Unit::Px),
+            Value::Length(f, Unit::Vw) => Value::Length(f * context.viewport_width / 100.0, Unit::Px),
+            Value::Length(f, Unit::Vh) => Value::Length(f * context.viewport_height / 100.0, Unit::Px),
+            Value::Function(ref name, ref args) => {
+                Value::Function(name.clone(), args.iter().map(|arg| arg.compute_lengths(context)).collect())
+            }
+            Value::List(ref values) => Value::List(values.iter().map(|value| value.compute_lengths(context)).collect()),
+            _ => self.clone(),
+        }
+    }
+
+    /// Return the size of a computed length in px, resolving ems against `font_size`.
+    ///
+    /// Percentages and non-lengths like `auto` return `None`, since they can't be resolved without
+    /// more context.
+    pub fn to_px(&self, font_size: f32) -> Option<f32> {
         match *self {
             Value::Length(f, Unit::Px) => Some(f),
-            Value::Length(f, Unit::Em) => Some(f * FONT_SIZE),
-            Value::Length(_, Unit::Percent) => None,
-            _ => Some(0f32),
+            Value::Length(f, Unit::Em) => Some(f * font_size),
+            Value::Function(ref name, ref args) => {
+                let args: Option<Vec<f32>> = args.iter().map(|arg| arg.to_px(font_size)).collect();
+                args.and_then(|args| math_function(name.as_slice(), args.as_slice()))
+            }
+            _ => None,
         }
     }
 
-    pub fn percent_to_px(&self, container_width: f32) -> f32 {
-        if let Value::Length(f, Unit::Percent) = *self {
-            return container_width * f / 100f32;
-        } else {
-            return self.to_px().unwrap();
+    /// Return the size of a computed length in px, resolving percentages against `base` and ems
+    /// against `font_size`.
+    ///
+    /// Non-lengths like `auto` are treated as zero.
+    pub fn length_to_px(&self, base: f32, font_size: f32) -> f32 {
+        match *self {
+            Value::Length(f, Unit::Percent) => base * f / 100f32,
+            Value::Function(ref name, ref args) => {
+                let args: Vec<f32> = args.iter().map(|arg| arg.length_to_px(base, font_size)).collect();
+                math_function(name.as_slice(), args.as_slice()).unwrap_or(0f32)
+            }
+            _ => self.to_px(font_size).unwrap_or(0f32),
         }
     }
 }
 
-/// Parse a whole CSS stylesheet.
-pub fn parse(source: String) -> Stylesheet {
-    let mut parser = Parser { pos: 0, input: source, color_map: ColorMap::new() };
-    let mut style_sheet = Stylesheet { rules: parser.parse_rules() };
+/// Evaluate `min()`, `max()`, or `clamp()` on arguments already resolved to px.  Other functions
+/// and the wrong number of arguments return `None`.
+fn math_function(name: &str, args: &[f32]) -> Option<f32> {
+    match (name, args) {
+        ("min", _) if !args.is_empty() => Some(args.iter().fold(args[0], |a, &b| a.min(b))),
+        ("max", _) if !args.is_empty() => Some(args.iter().fold(args[0], |a, &b| a.max(b))),
+        // `clamp(min, val, max)` is `max(min, min(val, max))`.
+        ("clamp", [min, val, max]) => Some(min.max(val.min(max))),
+        _ => None,
+    }
+}
+
+/// Parse a whole CSS stylesheet, along with the errors in it.  The stylesheet holds every rule
+/// and declaration that could be parsed.
+pub fn parse(source: String) -> (Stylesheet, Vec<ParseError>) {
+    let mut parser = Parser::new(source);
+    let rules = parser.parse_rules();
+    let mut style_sheet = Stylesheet { rules: rules, keyframes: parser.keyframes };
     add_user_agent_style(&mut style_sheet);
-    style_sheet
+    (style_sheet, parser.errors)
 }
 
+/// Parse the declarations of a `style` attribute.  Invalid declarations are left out.
 pub fn parse_inline_style(source: String) -> Vec<Declaration> {
-    let mut parser = Parser { pos: 0, input: source, color_map: ColorMap::new() };
-    parser.parse_declarations()
+    let mut parser = Parser::new(source);
+    parser.parse_declarations().unwrap_or(Vec::new())
+}
+
+/// Parse a comma-separated list of selectors, such as `.card` or `div p`, outside of a rule.
+pub fn parse_selectors(source: String) -> Result<Vec<Selector>, ParseError> {
+    // The selector parser stops at the opening brace of a rule.
+    let mut parser = Parser::new(format!("{} {{", source));
+    parser.parse_all_selectors()
 }
 
 static USER_AGENT_STYLE: &'static str = "a { color: blue; text-decoration: underline; }";
 
 pub fn add_user_agent_style(styles: &mut Stylesheet) {
-    let mut parser = Parser { pos: 0, input: USER_AGENT_STYLE.to_string(), color_map: ColorMap::new() };
-    for rule in parser.parse_rules().into_iter() {
+    let mut parser = Parser::new(USER_AGENT_STYLE.to_string());
+    for mut rule in parser.parse_rules().into_iter() {
+        rule.origin = Origin::UserAgent;
         styles.rules.push(rule);
     }
 }
 
-struct Parser {
+/// A CSS parser, which can also be used to parse a stylesheet one rule at a time, as an iterator
+/// over its rules.
+pub struct Parser {
     pos: usize,
     input: String,
     color_map: ColorMap,
+    keyframes: Vec<Keyframes>,
+    errors: Vec<ParseError>,
+    /// Rules from an at-rule that haven't been returned by `parse_one_rule` yet, last first.
+    pending: Vec<Rule>,
+}
+
+impl Iterator for Parser {
+    type Item = Rule;
+
+    fn next(&mut self) -> Option<Rule> {
+        self.parse_one_rule()
+    }
 }
 
 impl Parser {
-    /// Parse a list of rule sets, separated by optional whitespace.
+    pub fn new(input: String) -> Parser {
+        Parser { pos: 0, input: input, color_map: ColorMap::new(), keyframes: Vec::new(), errors: Vec::new(), pending: Vec::new() }
+    }
+
+    /// The byte offset in the input that parsing has reached.  After `parse_one_rule`, this is
+    /// just past the rule it returned.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Parse the next rule set, or `None` at the end of the input.  At-rules that don't
+    /// contribute any rule sets, like `@keyframes`, are parsed along the way, and rules with
+    /// invalid selectors are skipped.
+    pub fn parse_one_rule(&mut self) -> Option<Rule> {
+        loop {
+            if let Some(rule) = self.pending.pop() {
+                return Some(rule);
+            }
+            self.consume_comment();
+            if self.eof() {
+                return None;
+            }
+            if self.next_char() == '@' {
+                let rules = self.parse_at_rule();
+                self.pending.extend(rules.into_iter().rev());
+            } else {
+                match self.parse_rule() {
+                    Ok(rule) => return Some(rule),
+                    Err(error) => {
+                        self.errors.push(error);
+                        self.skip_block();
+                    }
+                }
+            }
+        }
+    }
+
+    /// Parse a list of rule sets and at-rules, separated by optional whitespace.
     fn parse_rules(&mut self) -> Vec<Rule> {
         let mut rules = Vec::new();
+        while let Some(rule) = self.parse_one_rule() {
+            rules.push(rule);
+        }
+        return rules;
+    }
+
+    /// Parse an at-rule like `@media ...`, dispatching on its keyword.
+    ///
+    /// Returns any rule sets the at-rule contributes.  Unknown at-rules are skipped.
+    fn parse_at_rule(&mut self) -> Vec<Rule> {
+        assert!(self.consume_char() == '@');
+        let keyword = self.parse_identifier().into_ascii_lowercase();
+        match keyword.as_slice() {
+            "keyframes" => {
+                if let Err(error) = self.parse_keyframes() {
+                    self.errors.push(error);
+                    self.skip_block();
+                }
+                Vec::new()
+            }
+            _ => {
+                self.skip_at_rule();
+                Vec::new()
+            }
+        }
+    }
+
+    /// Parse the name and body of a `@keyframes` rule.
+    fn parse_keyframes(&mut self) -> Result<(), ParseError> {
+        self.consume_whitespace();
+        let name = self.parse_identifier();
+        self.consume_whitespace();
+        try!(self.expect_char('{'));
+
+        let mut frames = Vec::new();
+        loop {
+            self.consume_comment();
+            if self.eof() {
+                return self.error("Unexpected end of input in @keyframes".to_string());
+            }
+            if self.next_char() == '}' {
+                self.consume_char();
+                break;
+            }
+            match self.parse_keyframe_selectors() {
+                Ok(offsets) => {
+                    let declarations = try!(self.parse_declarations());
+                    for offset in offsets.into_iter() {
+                        frames.push((offset, declarations.clone()));
+                    }
+                }
+                Err(error) => {
+                    self.errors.push(error);
+                    self.skip_block();
+                }
+            }
+        }
+        // Offsets are checked to be between 0 and 1 as they are parsed, so none is NaN.
+        frames.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap());
+
+        self.keyframes.push(Keyframes { name: name, frames: frames });
+        Ok(())
+    }
+
+    /// Parse a comma-separated list of keyframe selectors like `from, 50%`, returning offsets
+    /// between 0 and 1.  Percentages outside 0% to 100% are an error.
+    fn parse_keyframe_selectors(&mut self) -> Result<Vec<f32>, ParseError> {
+        let mut offsets = Vec::new();
         loop {
             self.consume_whitespace();
-            if self.eof() { break }
-            rules.push(self.parse_rule());
+            if self.eof() {
+                return self.error("Unexpected end of input in keyframe selector list".to_string());
+            }
+            let offset = match try!(self.parse_value()) {
+                Value::Length(f, Unit::Percent) if f >= 0.0 && f <= 100.0 => f / 100f32,
+                Value::Keyword(ref s) if s.as_slice() == "from" => 0f32,
+                Value::Keyword(ref s) if s.as_slice() == "to" => 1f32,
+                v => return self.error(format!("Invalid keyframe selector {:?}", v))
+            };
+            offsets.push(offset);
+            self.consume_whitespace();
+            if self.eof() {
+                return self.error("Unexpected end of input in keyframe selector list".to_string());
+            }
+            match self.next_char() {
+                ',' => { self.consume_char(); }
+                '{' => break,
+                c   => return self.error(format!("Unexpected character {} in keyframe selector list", c))
+            }
         }
-        return rules;
+        Ok(offsets)
     }
 
-    /// Parse a rule set: `<selectors> { <declarations> }`.
-    fn parse_rule(&mut self) -> Rule {
-        Rule {
-            selectors: self.parse_all_selectors(),
-            declarations: self.parse_declarations(),
+    /// Skip the rest of an at-rule: either up to its `;`, or through its `{ ... }` block
+    /// including any nested blocks.
+    fn skip_at_rule(&mut self) {
+        self.consume_while(|c| c != ';' && c != '{');
+        if self.eof() {
+            return;
         }
+        if self.next_char() == ';' {
+            self.consume_char();
+            return;
+        }
+        self.skip_block();
+    }
+
+    /// Skip up to and through the next `{ ... }` block, including any nested blocks.  A stray `}`
+    /// before the block opens ends the skip instead, since it closes whatever came before.
+    fn skip_block(&mut self) {
+        let mut depth = 0us;
+        while !self.eof() {
+            match self.consume_char() {
+                '{' => depth += 1,
+                '}' => {
+                    if depth <= 1 { break }
+                    depth -= 1;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Parse a rule set: `<selectors> { <declarations> }`.
+    fn parse_rule(&mut self) -> Result<Rule, ParseError> {
+        Ok(Rule {
+            selectors: try!(self.parse_all_selectors()),
+            declarations: try!(self.parse_declarations()),
+            origin: Origin::Author,
+        })
     }
 
     /// Parse a comma-separated list of selectors.
-    fn parse_selectors(&mut self) -> Vec<Selector> {
+    fn parse_selectors(&mut self) -> Result<Vec<Selector>, ParseError> {
         let mut selectors = Vec::new();
         loop {
-            selectors.push(Selector::Simple(self.parse_simple_selector()));
+            selectors.push(Selector::Simple(try!(self.parse_simple_selector())));
             self.consume_whitespace();
+            if self.eof() {
+                return self.error("Unexpected end of input in selector list".to_string());
+            }
             match self.next_char() {
                 ',' => { self.consume_char(); self.consume_whitespace(); }
                 '{' => break,
-                c   => panic!("Unexpected character {} in selector list", c)
+                c   => return self.error(format!("Unexpected character {} in selector list", c))
             }
         }
         // Return selectors with highest specificity first, for use in matching.
         selectors.sort_by(|a,b| b.specificity().cmp(&a.specificity()));
-        return selectors;
+        Ok(selectors)
+    }
+
+    fn parse_all_selectors(&mut self) -> Result<Vec<Selector>, ParseError> {
+        self.parse_selector_list('{')
     }
 
-    fn parse_all_selectors(&mut self) -> Vec<Selector> {
+    /// Parse a comma-separated list of selectors, up to but not including `end`.
+    fn parse_selector_list(&mut self, end: char) -> Result<Vec<Selector>, ParseError> {
         let mut selectors: Vec<Selector> = Vec::new();
-        let mut simple;
 
         self.consume_comment();
-        while self.next_char() != '{' {
-            let mut descendant: Vec<SimpleSelector> = Vec::new();
+        while !self.eof() && self.next_char() != end {
+            let mut descendant: DescendantSelector = Vec::new();
+            let mut combinator = Combinator::Descendant;
             loop {
-                simple = self.parse_simple_selector();
+                descendant.push((combinator, try!(self.parse_simple_selector())));
+                let before_whitespace = self.pos;
                 self.consume_whitespace();
+                if self.eof() {
+                    return self.error("Unexpected end of input in selector list".to_string());
+                }
                 match self.next_char() {
                     ',' => { self.consume_char(); self.consume_whitespace(); break; },
-                    '{' => break,
-                    c => descendant.push(simple)
+                    c if c == end => break,
+                    c @ '>' | c @ '+' | c @ '~' => {
+                        self.consume_char();
+                        self.consume_whitespace();
+                        combinator = match c {
+                            '>' => Combinator::Child,
+                            '+' => Combinator::NextSibling,
+                            _ => Combinator::SubsequentSibling,
+                        };
+                    }
+                    _ if self.pos > before_whitespace => combinator = Combinator::Descendant,
+                    c => return self.error(format!("Unexpected character {} in selector list", c))
                 }
             }
-            if descendant.is_empty() {
-                selectors.push(Selector::Simple(simple));
+            if descendant.len() == 1 {
+                selectors.push(Selector::Simple(descendant.pop().unwrap().1));
             } else {
-                descendant.push(simple);
                 selectors.push(Selector::Descendant(descendant));
             }
         }
+        if self.eof() {
+            return self.error("Unexpected end of input in selector list".to_string());
+        }
         // Return selectors with highest specificity first, for use in matching.
         selectors.sort_by(|a,b| b.specificity().cmp(&a.specificity()));
-        return selectors;
+        Ok(selectors)
     }
 
     /// Parse one simple selector, e.g.: `type#id.class1.class2.class3`
-    fn parse_simple_selector(&mut self) -> SimpleSelector {
-        let mut selector = SimpleSelector { tag_name: None, id: None, class: Vec::new() };
+    fn parse_simple_selector(&mut self) -> Result<SimpleSelector, ParseError> {
+        let mut selector = SimpleSelector { tag_name: None, id: None, class: Vec::new(), pseudo_element: None, pseudo_classes: Vec::new(), attributes: Vec::new() };
         while !self.eof() {
             match self.next_char() {
                 '#' => {
@@ -215,122 +634,336 @@ impl Parser {
                     // universal selector
                     self.consume_char();
                 }
+                '[' => {
+                    selector.attributes.push(try!(self.parse_attribute_selector()));
+                }
+                ':' => {
+                    // A pseudo-class, or a pseudo-element like `::first-letter` (or the older
+                    // single-colon `:first-letter`).  Unsupported pseudo-classes end up as
+                    // pseudo-elements too, and never match anything.
+                    self.consume_char();
+                    if !self.eof() && self.next_char() == ':' {
+                        self.consume_char();
+                    }
+                    let name = self.parse_identifier().into_ascii_lowercase();
+                    if !self.eof() && self.next_char() == '(' && (name == "is" || name == "where") {
+                        self.consume_char();
+                        self.consume_whitespace();
+                        let selectors = try!(self.parse_selector_list(')'));
+                        try!(self.expect_char(')'));
+                        selector.pseudo_classes.push(match name.as_slice() {
+                            "is" => PseudoClass::Is(selectors),
+                            _ => PseudoClass::Where(selectors),
+                        });
+                    } else if !self.eof() && self.next_char() == '(' && name == "nth-child" {
+                        self.consume_char();
+                        let argument = self.consume_while(|c| c != ')');
+                        try!(self.expect_char(')'));
+                        // An invalid argument gives a pseudo-class that matches nothing.
+                        let (a, b) = parse_nth(argument.as_slice()).unwrap_or((0, 0));
+                        selector.pseudo_classes.push(PseudoClass::NthChild(a, b));
+                    } else if name == "first-child" {
+                        selector.pseudo_classes.push(PseudoClass::FirstChild);
+                    } else if name == "last-child" {
+                        selector.pseudo_classes.push(PseudoClass::LastChild);
+                    } else {
+                        selector.pseudo_element = Some(name);
+                    }
+                }
                 c if valid_identifier_char(c) => {
                     selector.tag_name = Some(self.parse_identifier().into_ascii_lowercase());
                 }
                 _ => break
             }
         }
-        return selector;
+        Ok(selector)
+    }
+
+    /// Parse an attribute selector, e.g.: `[href^="https"]`
+    fn parse_attribute_selector(&mut self) -> Result<AttrSelector, ParseError> {
+        try!(self.expect_char('['));
+        self.consume_whitespace();
+        let name = self.parse_identifier().into_ascii_lowercase();
+        self.consume_whitespace();
+
+        if self.eof() {
+            return self.error("Unexpected end of input in attribute selector".to_string());
+        }
+        let kind = self.next_char();
+        match kind {
+            ']' => {
+                self.consume_char();
+                return Ok(AttrSelector { name: name, operator: AttrOperator::Exists });
+            }
+            '=' => { self.consume_char(); }
+            '~' | '^' => {
+                self.consume_char();
+                try!(self.expect_char('='));
+            }
+            c => return self.error(format!("Unexpected character {} in attribute selector", c))
+        }
+        self.consume_whitespace();
+
+        if self.eof() {
+            return self.error("Unexpected end of input in attribute selector".to_string());
+        }
+        let value = match self.next_char() {
+            '"' | '\'' => match self.parse_string() {
+                Value::Keyword(value) => value,
+                _ => unreachable!()
+            },
+            _ => self.parse_identifier()
+        };
+        self.consume_whitespace();
+        try!(self.expect_char(']'));
+        let operator = match kind {
+            '~' => AttrOperator::Includes(value),
+            '^' => AttrOperator::Prefix(value),
+            _ => AttrOperator::Equals(value),
+        };
+        Ok(AttrSelector { name: name, operator: operator })
     }
 
-    /// Parse a list of declarations enclosed in `{ ... }`.
-    fn parse_declarations(&mut self) -> Vec<Declaration> {
-        assert!(self.consume_char() == '{');
+    /// Parse a list of declarations enclosed in `{ ... }`.  Invalid declarations are skipped, and
+    /// a block left open at the end of the input is closed.
+    fn parse_declarations(&mut self) -> Result<Vec<Declaration>, ParseError> {
+        try!(self.expect_char('{'));
         let mut declarations = Vec::new();
         loop {
             self.consume_whitespace();
+            if self.eof() {
+                break;
+            }
             if self.next_char() == '}' {
                 self.consume_char();
                 break;
             }
-            for decl in self.parse_declaration().into_iter() {
-                declarations.push(decl);
+            match self.parse_declaration() {
+                Ok(decls) => declarations.extend(decls.into_iter()),
+                Err(error) => {
+                    self.errors.push(error);
+                    self.skip_declaration();
+                }
+            }
+        }
+        Ok(declarations)
+    }
+
+    /// Skip the rest of an invalid declaration: through the next `;`, or up to the `}` that
+    /// closes its block.  Any `{ ... }` blocks nested in the declaration are skipped whole.
+    fn skip_declaration(&mut self) {
+        let mut depth = 0us;
+        while !self.eof() {
+            match self.next_char() {
+                ';' if depth == 0 => {
+                    self.consume_char();
+                    break;
+                }
+                '}' if depth == 0 => break,
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
             }
+            self.consume_char();
         }
-        return declarations;
     }
 
-    /// Parse one `<property>: <value>;` declaration.
-    fn parse_declaration(&mut self) -> Vec<Declaration> {
+    /// Parse one `<property>: <value>;` declaration.  The `;` may be left out before the `}` at
+    /// the end of a block.
+    fn parse_declaration(&mut self) -> Result<Vec<Declaration>, ParseError> {
         self.consume_comment();
 
         let property_name = self.parse_identifier();
         self.consume_whitespace();
-        assert!(self.consume_char() == ':');
+        try!(self.expect_char(':'));
         self.consume_whitespace();
 
         let mut declarations = Vec::new();
+        let value_start = self.pos;
+        let mut groups = try!(self.parse_comma_separated_values());
         if shorthand::is_shorthand(property_name.as_slice()) {
-            declarations = shorthand::parse_shorthand(property_name.as_slice(), self.parse_values());
+            declarations = try!(shorthand::parse_shorthand(property_name.as_slice(), groups, value_start));
         } else {
-            let value = self.parse_value();
-            self.consume_whitespace();
-            declarations.push(Declaration { name: property_name, value: value });
+            // Longhands take a single value, or a comma-separated list of single values.  An
+            // unquoted family name may be several words, as in `Times New Roman`.
+            let single: fn(Vec<Value>) -> Value = if property_name == "font-family" { join_words } else { first_value };
+            let value = if property_name == "font-feature-settings" {
+                // Each setting keeps its tag and value, as in `"liga" 0, "kern"`.
+                Value::List(groups.into_iter().map(|group| Value::List(group)).collect())
+            } else if groups.len() == 1 {
+                single(groups.swap_remove(0))
+            } else {
+                Value::List(groups.into_iter().map(single).collect())
+            };
+            declarations.push(Declaration { name: property_name, value: value, important: false });
+        }
+        let important = try!(self.parse_important());
+        if self.eof() || self.next_char() != '}' {
+            try!(self.expect_char(';'));
         }
-        assert!(self.consume_char() == ';');
         self.consume_comment();
 
         for decl in declarations.iter_mut() {
             set_default_unit(decl);
+            decl.important = important;
         }
 
-        declarations
+        Ok(declarations)
+    }
+
+    /// Parse a trailing `!important`, if there is one.
+    fn parse_important(&mut self) -> Result<bool, ParseError> {
+        if self.eof() || self.next_char() != '!' {
+            return Ok(false);
+        }
+        self.consume_char();
+        self.consume_whitespace();
+        if self.parse_identifier().into_ascii_lowercase() != "important" {
+            return self.error("Expected important after !".to_string());
+        }
+        self.consume_whitespace();
+        Ok(true)
     }
 
-    fn parse_values(&mut self) -> Vec<Value> {
+    /// Parse space-separated values up to the end of a declaration, the next comma, or
+    /// `!important`.
+    fn parse_values(&mut self) -> Result<Vec<Value>, ParseError> {
         let mut values = Vec::new();
-        while self.next_char() != ';' {
-            values.push(self.parse_value());
+        while !self.eof() && !";,!}".contains_char(self.next_char()) {
+            values.push(try!(self.parse_value()));
             self.consume_whitespace();
         }
-        values
+        Ok(values)
+    }
+
+    /// Parse a comma-separated list of groups of space-separated values, such as the layers in
+    /// `background: red, blue`.  Without a comma, this is a single group.
+    fn parse_comma_separated_values(&mut self) -> Result<Vec<Vec<Value>>, ParseError> {
+        let mut groups = vec![try!(self.parse_values())];
+        while !self.eof() && self.next_char() == ',' {
+            self.consume_char();
+            self.consume_whitespace();
+            groups.push(try!(self.parse_values()));
+        }
+        Ok(groups)
     }
 
     // Methods for parsing values:
 
-    fn parse_value(&mut self) -> Value {
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
         match self.next_char() {
             '0'...'9' | '.' => self.parse_length(),
-            '#' => self.parse_color(),
-            _ => {
+            '-' if self.starts_with_negative_number() => self.parse_length(),
+            '#' => Ok(self.parse_color()),
+            '"' | '\'' => Ok(self.parse_string()),
+            c => {
                 let value = self.parse_identifier();
-                match self.convert_keyword_to_color(value.as_slice()) {
+                if value.is_empty() {
+                    return self.error(format!("Unexpected character {} in value", c));
+                }
+                if !self.eof() && self.next_char() == '(' {
+                    let function = try!(self.parse_function(value.into_ascii_lowercase()));
+                    if let Value::Function(ref name, ref args) = function {
+                        if let Some(color) = rgb_function(name.as_slice(), args.as_slice()) {
+                            return Ok(Value::ColorValue(color));
+                        }
+                    }
+                    return Ok(function);
+                }
+                Ok(match self.convert_keyword_to_color(value.as_slice()) {
                     Some(color) => Value::ColorValue(color),
                     None => Value::Keyword(value),
-                }
+                })
             }
         }
     }
 
+    /// Parse the comma-separated arguments of a function, up to and including the closing
+    /// parenthesis.  Unitless lengths in the arguments are in px.
+    fn parse_function(&mut self, name: String) -> Result<Value, ParseError> {
+        try!(self.expect_char('('));
+        let mut args = Vec::new();
+        loop {
+            self.consume_whitespace();
+            if self.eof() {
+                return self.error(format!("Unexpected end of input in {}()", name));
+            }
+            match self.next_char() {
+                ')' => { self.consume_char(); break; }
+                ',' => { self.consume_char(); }
+                _ => args.push(match try!(self.parse_value()) {
+                    Value::Length(f, Unit::Default) => Value::Length(f, Unit::Px),
+                    value => value
+                })
+            }
+        }
+        Ok(Value::Function(name, args))
+    }
+
     fn parse_value_to_string(&mut self) -> String {
         self.consume_while(|c| c != ';')
     }
 
-    fn parse_length(&mut self) -> Value {
-        Value::Length(self.parse_float(), self.parse_unit())
+    fn parse_length(&mut self) -> Result<Value, ParseError> {
+        let f = try!(self.parse_float());
+        Ok(Value::Length(f, try!(self.parse_unit())))
     }
 
-    fn parse_float(&mut self) -> f32 {
-        let s = self.consume_while(|c| match c {
+    fn parse_float(&mut self) -> Result<f32, ParseError> {
+        let mut s = String::new();
+        if self.next_char() == '-' {
+            s.push(self.consume_char());
+        }
+        s.push_str(self.consume_while(|c| match c {
             '0'...'9' | '.' => true,
             _ => false
-        });
+        }).as_slice());
         let f: Option<f32> = FromStr::from_str(&*s);
-        f.unwrap()
+        match f {
+            Some(f) => Ok(f),
+            None => self.error(format!("Invalid number {}", s))
+        }
     }
 
-    fn parse_unit(&mut self) -> Unit {
-        match &*self.parse_identifier().into_ascii_lowercase() {
+    fn parse_unit(&mut self) -> Result<Unit, ParseError> {
+        let unit = self.parse_identifier().into_ascii_lowercase();
+        Ok(match unit.as_slice() {
             "px" => Unit::Px,
             "em" => Unit::Em,
+            "rem" => Unit::Rem,
+            "pt" => Unit::Pt,
+            "vw" => Unit::Vw,
+            "vh" => Unit::Vh,
             "%" => Unit::Percent,
+            "s" => Unit::S,
+            "ms" => Unit::Ms,
             "" => Unit::Default,
-            _ => panic!("unrecognized unit")
+            _ => return self.error(format!("Unrecognized unit {}", unit))
+        })
+    }
+
+    /// Parse a quoted string, such as a font family name with spaces in it, as a keyword.
+    fn parse_string(&mut self) -> Value {
+        let quote = self.consume_char();
+        let string = self.consume_while(|c| c != quote);
+        if !self.eof() {
+            self.consume_char();
         }
+        Value::Keyword(string)
     }
 
     fn parse_color(&mut self) -> Value {
         assert!(self.consume_char() == '#');
-        let mut hex = self.consume_while(|c| c != ';');
-        Value::ColorValue(convert_hex_to_color(&mut hex))
-    }
-
-    /// Parse two hexadecimal digits.
-    fn parse_hex_pair(&mut self) -> u8 {
-        let s = self.input.slice(self.pos, self.pos + 2);
-        self.pos = self.pos + 2;
-        FromStrRadix::from_str_radix(s, 0x10).unwrap()
+        let hex = self.parse_identifier();
+        match Color::from_hex(hex.as_slice()) {
+            Some(color) => Value::ColorValue(color),
+            None => {
+                // Keep the invalid color as a keyword, which no property accepts, and report it.
+                let error = ParseError { position: self.pos, message: format!("Invalid color #{}", hex) };
+                self.errors.push(error);
+                Value::Keyword(format!("#{}", hex))
+            }
+        }
     }
 
     /// Parse a property name or keyword.
@@ -352,6 +985,24 @@ impl Parser {
         return result;
     }
 
+    /// An error at the current position.
+    fn error<T>(&self, message: String) -> Result<T, ParseError> {
+        Err(ParseError { position: self.pos, message: message })
+    }
+
+    /// Consume the character `expected`, or return an error if the input has anything else.
+    fn expect_char(&mut self, expected: char) -> Result<(), ParseError> {
+        if self.eof() {
+            return self.error(format!("Expected {} at the end of input", expected));
+        }
+        let c = self.next_char();
+        if c != expected {
+            return self.error(format!("Expected {} but found {}", expected, c));
+        }
+        self.consume_char();
+        Ok(())
+    }
+
     /// Return the current character, and advance self.pos to the next character.
     fn consume_char(&mut self) -> char {
         let range = self.input.char_range_at(self.pos);
@@ -374,52 +1025,81 @@ impl Parser {
         self.input.slice_from(self.pos).starts_with(s)
     }
 
+    /// Does the current input start with a negative number, like `-1` or `-.5`?
+    fn starts_with_negative_number(&self) -> bool {
+        let mut chars = self.input.slice_from(self.pos).chars();
+        chars.next() == Some('-') && match chars.next() {
+            Some('0'...'9') | Some('.') => true,
+            _ => false
+        }
+    }
+
+    /// Consume whitespace and comments.  A comment left open runs to the end of the input.
     fn consume_comment(&mut self) {
         self.consume_whitespace();
         while self.starts_with("/*") {
-            assert!(self.consume_char() == '/');
-            assert!(self.consume_char() == '*');
-            self.consume_while(|c| c != '/');
-            assert!(self.consume_char() == '/');
+            self.pos = match self.input.slice_from(self.pos + 2).find_str("*/") {
+                Some(end) => self.pos + 2 + end + 2,
+                None => self.input.len()
+            };
             self.consume_whitespace();
         }
     }
 
     fn convert_keyword_to_color(&mut self, source: &str) -> Option<Color> {
-        if let Some(color) = self.color_map.get_color(source.as_slice()) {
-            return Some(*color);
-        }
-        if !source.starts_with("rgb") {
-            return None;
-        }
-
-        let num = source.len();
-        let input = self.consume_while(|c| c != ';');
-
-        let mut pos = input.find(|&:c: char| c == '(');
-        assert!(pos != None);
-
-        let mut data = input.slice(pos.unwrap() + 1, input.len() - 1).to_string();
-        let len = data.len();
-        data.insert(len, ',');
-
-        let mut values: [u8; 4] = [0, 0, 0, 255];
-        let mut component = data.as_slice();
-
-        for i in range(0, num) {
-            pos = component.find(|&:c: char| c == ',');
-            assert!(pos != None);
+        self.color_map.get_color(source.as_slice()).map(|color| *color)
+    }
+}
 
-            let val: Option<u8> = FromStr::from_str(component.slice(0, pos.unwrap()));
-            assert!(val != None);
-            values[i] = val.unwrap();
+/// The color given by the arguments of `rgb(r, g, b)` or `rgba(r, g, b, a)`.  Each channel is a
+/// number from 0 to 255 or a percentage, and alpha is a number from 0 to 1 or a percentage.
+/// Values out of range are clamped.
+fn rgb_function(name: &str, args: &[Value]) -> Option<Color> {
+    let channel = |&: value: &Value, max: f32| -> Option<u8> {
+        let f = match *value {
+            Value::Length(f, Unit::Percent) => f / 100.0 * 255.0,
+            Value::Length(f, Unit::Px) => f * 255.0 / max,
+            _ => return None
+        };
+        Some(f.max(0.0).min(255.0).round() as u8)
+    };
+    match (name, args) {
+        ("rgb", [ref r, ref g, ref b]) => match (channel(r, 255.0), channel(g, 255.0), channel(b, 255.0)) {
+            (Some(r), Some(g), Some(b)) => Some(Color::new(r, g, b, 255)),
+            _ => None
+        },
+        ("rgba", [ref r, ref g, ref b, ref a]) => match (channel(r, 255.0), channel(g, 255.0), channel(b, 255.0), channel(a, 1.0)) {
+            (Some(r), Some(g), Some(b), Some(a)) => Some(Color::new(r, g, b, a)),
+            _ => None
+        },
+        _ => None
+    }
+}
 
-            component = component.slice_from(pos.unwrap() + 1);
+/// Parse the argument of `:nth-child()`, like `2n+1`, `odd`, or `3`, into `a` and `b` of `an+b`.
+fn parse_nth(argument: &str) -> Option<(i32, i32)> {
+    let argument = argument.chars().filter(|c| !c.is_whitespace()).collect::<String>().into_ascii_lowercase();
+    match argument.as_slice() {
+        "odd" => Some((2, 1)),
+        "even" => Some((2, 0)),
+        _ => match argument.find('n') {
+            None => FromStr::from_str(argument.as_slice()).map(|b| (0, b)),
+            Some(i) => {
+                let a = match argument.slice_to(i) {
+                    "" | "+" => Some(1),
+                    "-" => Some(-1),
+                    a => FromStr::from_str(a),
+                };
+                let b = match argument.slice_from(i + 1) {
+                    "" => Some(0),
+                    b => FromStr::from_str(b.trim_left_matches('+')),
+                };
+                match (a, b) {
+                    (Some(a), Some(b)) => Some((a, b)),
+                    _ => None
+                }
+            }
         }
-
-        Some(Color {
-            r: values[0], g: values[1], b: values[2], a: values[3],
-        })
     }
 }
 
@@ -430,19 +1110,19 @@ fn valid_identifier_char(c: char) -> bool {
     }
 }
 
-fn convert_hex_to_color(input: &mut String) -> Color {
-    if (input.len() / 3) == 1 {
-        for i in range(0, 3).rev() {
-            let c = input.char_at(i);
-            input.insert(i + 1, c);
-        }
-    }
-    Color {
-        r: FromStrRadix::from_str_radix(input.slice(0, 2), 0x10).unwrap(),
-        g: FromStrRadix::from_str_radix(input.slice(2, 4), 0x10).unwrap(),
-        b: FromStrRadix::from_str_radix(input.slice(4, 6), 0x10).unwrap(),
-        a: 255,
-    }
+/// The first of a group of space-separated values, for a longhand that takes a single value.
+fn first_value(group: Vec<Value>) -> Value {
+    group.into_iter().next().unwrap_or(Value::Keyword(String::new()))
+}
+
+/// The words of a `font-family` name, joined with single spaces.  Values other than keywords are
+/// left out.
+fn join_words(group: Vec<Value>) -> Value {
+    let words: Vec<String> = group.into_iter().filter_map(|value| match value {
+        Value::Keyword(word) => Some(word),
+        _ => None
+    }).collect();
+    Value::Keyword(words.connect(" "))
 }
 
 static DEFAULT_EM: [&'static str; 1] = ["line-height"];
@@ -456,3 +1136,170 @@ fn set_default_unit(decl: &mut Declaration) {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{parse, Parser, Stylesheet, Rule, Selector, Origin, Value, Unit};
+    use color::Color;
+
+    /// The rules from the stylesheet itself, leaving out the user agent's.
+    fn author_rules(stylesheet: &Stylesheet) -> Vec<&Rule> {
+        stylesheet.rules.iter().filter(|rule| rule.origin == Origin::Author).collect()
+    }
+
+    /// The tag name of a rule's first selector.
+    fn tag_name(rule: &Rule) -> Option<String> {
+        match rule.selectors[0] {
+            Selector::Simple(ref simple) => simple.tag_name.clone(),
+            Selector::Descendant(_) => None,
+        }
+    }
+
+    fn red() -> Value {
+        Value::ColorValue(Color::new(255, 0, 0, 255))
+    }
+
+    #[test]
+    fn unknown_supports_rule_is_skipped_without_errors() {
+        let (stylesheet, errors) = parse("p { color: red } \
+                                          @supports (display: grid) { div { display: grid } span { color: blue } } \
+                                          h1 { color: red }".to_string());
+        assert!(errors.is_empty());
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(tag_name(rules[0]), Some("p".to_string()));
+        assert_eq!(tag_name(rules[1]), Some("h1".to_string()));
+        assert_eq!(rules[1].declarations[0].value, red());
+    }
+
+    #[test]
+    fn invalid_declaration_keeps_the_rest_of_its_rule() {
+        let (stylesheet, errors) = parse("p { color: red; width: 10qq; height: 5px }".to_string());
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules.len(), 1);
+        let names: Vec<&str> = rules[0].declarations.iter().map(|decl| decl.name.as_slice()).collect();
+        assert_eq!(names, vec!["color", "height"]);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].position, 27);
+    }
+
+    #[test]
+    fn invalid_selector_skips_only_its_rule() {
+        let (stylesheet, errors) = parse("p!x { color: blue } h1 { color: red }".to_string());
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules.len(), 1);
+        assert_eq!(tag_name(rules[0]), Some("h1".to_string()));
+        assert_eq!(rules[0].declarations[0].value, red());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].position, 1);
+    }
+
+    #[test]
+    fn nested_block_in_declarations_is_skipped_whole() {
+        let (stylesheet, errors) = parse("a { b { c: d } } p { color: red }".to_string());
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules.len(), 2);
+        assert!(rules[0].declarations.is_empty());
+        assert_eq!(tag_name(rules[1]), Some("p".to_string()));
+        assert_eq!(rules[1].declarations[0].value, red());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].position, 6);
+    }
+
+    #[test]
+    fn empty_shorthand_is_an_error() {
+        let (stylesheet, errors) = parse("p { margin: ; padding: !important; color: red }".to_string());
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules[0].declarations.len(), 1);
+        assert_eq!(rules[0].declarations[0].value, red());
+        let positions: Vec<usize> = errors.iter().map(|error| error.position).collect();
+        assert_eq!(positions, vec![12, 23]);
+    }
+
+    #[test]
+    fn unclosed_block_at_end_of_input() {
+        let (stylesheet, errors) = parse("p { color: red;".to_string());
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules[0].declarations[0].value, red());
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn unquoted_font_family_keeps_every_word() {
+        let (stylesheet, _) = parse("p { font-family: Times New Roman, \"Comic Sans\", serif; }".to_string());
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules[0].declarations[0].value, Value::List(vec![
+            Value::Keyword("Times New Roman".to_string()),
+            Value::Keyword("Comic Sans".to_string()),
+            Value::Keyword("serif".to_string()),
+        ]));
+    }
+
+    #[test]
+    fn keyframes_are_sorted_and_out_of_range_offsets_rejected() {
+        let (stylesheet, errors) = parse("@keyframes fade { to { width: 2px } 150% { width: 3px } 25%, from { width: 1px } }".to_string());
+        let offsets: Vec<f32> = stylesheet.keyframes[0].frames.iter().map(|&(offset, _)| offset).collect();
+        assert_eq!(offsets, vec![0.0, 0.25, 1.0]);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn font_feature_settings_keeps_each_setting() {
+        let (stylesheet, _) = parse("p { font-feature-settings: \"liga\" 0, \"kern\"; }".to_string());
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules[0].declarations[0].value, Value::List(vec![
+            Value::List(vec![Value::Keyword("liga".to_string()), Value::Length(0.0, Unit::Default)]),
+            Value::List(vec![Value::Keyword("kern".to_string())]),
+        ]));
+    }
+
+    #[test]
+    fn length_to_px_resolves_percentages_against_the_base() {
+        assert_eq!(Value::Length(50.0, Unit::Percent).length_to_px(600.0, 16.0), 300.0);
+        assert_eq!(Value::Length(10.0, Unit::Percent).length_to_px(600.0, 16.0), 60.0);
+        assert_eq!(Value::Length(2.0, Unit::Em).length_to_px(600.0, 16.0), 32.0);
+        assert_eq!(Value::Keyword("auto".to_string()).length_to_px(600.0, 16.0), 0.0);
+    }
+
+    #[test]
+    fn to_px_has_no_value_for_percentages() {
+        assert_eq!(Value::Length(50.0, Unit::Percent).to_px(16.0), None);
+        assert_eq!(Value::Keyword("auto".to_string()).to_px(16.0), None);
+        assert_eq!(Value::Length(12.0, Unit::Px).to_px(16.0), Some(12.0));
+        assert_eq!(Value::Length(0.5, Unit::Em).to_px(16.0), Some(8.0));
+    }
+
+    #[test]
+    fn invalid_hex_color_is_a_recoverable_error() {
+        let (stylesheet, errors) = parse("p { color: #12345; width: 5px } div { color: red }".to_string());
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].position, 17);
+        assert_eq!(errors[0].message.as_slice(), "Invalid color #12345");
+
+        let rules = author_rules(&stylesheet);
+        assert_eq!(rules.len(), 2);
+        assert_eq!(rules[0].declarations[1].value, Value::Length(5.0, Unit::Px));
+        assert_eq!(rules[1].declarations[0].value, red());
+    }
+
+    #[test]
+    fn parser_returns_one_rule_at_a_time_with_its_end_offset() {
+        let mut parser = Parser::new("a { color: red } b { width: 1px }  @keyframes k { from { width: 0px } } c {}".to_string());
+        assert_eq!(parser.position(), 0);
+
+        let rule = parser.parse_one_rule().unwrap();
+        assert_eq!(tag_name(&rule), Some("a".to_string()));
+        assert_eq!(parser.position(), 16);
+
+        let rule = parser.parse_one_rule().unwrap();
+        assert_eq!(tag_name(&rule), Some("b".to_string()));
+        assert_eq!(parser.position(), 33);
+
+        // The at-rule is passed over on the way to the next rule.
+        let rule = parser.next().unwrap();
+        assert_eq!(tag_name(&rule), Some("c".to_string()));
+        assert_eq!(parser.position(), 76);
+
+        assert!(parser.next().is_none());
+    }
+}
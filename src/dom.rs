@@ -4,13 +4,16 @@ use std::collections::{HashMap,HashSet};
 
 use std::rc::Rc;
 use std::rc::Weak;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 
 pub type AttrMap = HashMap<String, String>;
 
 #[derive(Show)]
 pub struct Node {
-    pub parent: RefCell<Vec<Weak<Node>>>,
+    pub parent: RefCell<Option<Weak<Node>>>,
+    /// For an element, its position among the elements under its parent and their number, as
+    /// returned by `element_position`.  Set by `set_parents`.
+    pub position: Cell<(usize, usize)>,
 
     // data common to all nodes:
     pub children: Vec<Rc<Node>>,
@@ -33,12 +36,18 @@ pub struct ElementData {
 
 // Constructor functions for convenience:
 pub fn text(data: String) -> Rc<Node> {
-    Rc::new(Node { parent: RefCell::new(Vec::new()), children: vec![], node_type: NodeType::Text(data.to_string()) })
+    Rc::new(Node {
+        parent: RefCell::new(None),
+        position: Cell::new((1, 1)),
+        children: vec![],
+        node_type: NodeType::Text(data.to_string()),
+    })
 }
 
 pub fn elem(name: String, attrs: AttrMap, children: Vec<Rc<Node>>) -> Node {
     Node {
-        parent: RefCell::new(Vec::new()),
+        parent: RefCell::new(None),
+        position: Cell::new((1, 1)),
         children: children,
         node_type: NodeType::Element(ElementData {
             tag_name: name,
@@ -47,9 +56,103 @@ pub fn elem(name: String, attrs: AttrMap, children: Vec<Rc<Node>>) -> Node {
     }
 }
 
+// Node methods
+
+impl Node {
+    /// The node's parent, if it has one.
+    pub fn parent(&self) -> Option<Rc<Node>> {
+        self.parent.borrow().as_ref().and_then(|parent| parent.upgrade())
+    }
+
+    /// This element's position among the elements under its parent, counting from 1, and the
+    /// number of those elements.  An element without a parent is the only one.
+    ///
+    /// Positions are counted once for each list of children by `set_parents`, so this doesn't
+    /// scan the siblings.
+    pub fn element_position(&self) -> (usize, usize) {
+        self.position.get()
+    }
+
+    /// Is this node an element, rather than text?
+    pub fn is_element(&self) -> bool {
+        match self.node_type {
+            NodeType::Element(_) => true,
+            NodeType::Text(_) => false,
+        }
+    }
+
+    /// The elements that come before this node under the same parent, nearest first.  Text nodes
+    /// are skipped.
+    pub fn previous_element_siblings(&self) -> Vec<Rc<Node>> {
+        let parent = match self.parent() {
+            Some(parent) => parent,
+            None => return vec![]
+        };
+        let index = parent.children.iter()
+            .position(|child| &**child as *const Node == self as *const Node)
+            .expect("node is missing from its parent's children");
+        parent.children.slice_to(index).iter().rev()
+            .filter(|sibling| sibling.is_element())
+            .map(|sibling| sibling.clone())
+            .collect()
+    }
+}
+
+/// Make `parent` the parent of `child`, replacing any previous parent.  Element positions are
+/// left alone; `set_parents` counts them for a whole tree.
+pub fn set_parent(child: &Rc<Node>, parent: &Rc<Node>) {
+    // A node can't be its own ancestor.
+    debug_assert!({
+        let mut ancestor = Some(parent.clone());
+        let mut cycle = false;
+        while let Some(node) = ancestor {
+            if same_node(&node, child) {
+                cycle = true;
+                break;
+            }
+            ancestor = node.parent();
+        }
+        !cycle
+    });
+    *child.parent.borrow_mut() = Some(parent.downgrade());
+}
+
+/// Link every node in the tree under `root` to its parent, and count each element's position
+/// among its siblings.
+pub fn set_parents(root: &Rc<Node>) {
+    let count = root.children.iter().filter(|child| child.is_element()).count();
+    let mut index = 0;
+    for child in root.children.iter() {
+        if child.is_element() {
+            index += 1;
+            child.position.set((index, count));
+        }
+        set_parent(child, root);
+        set_parents(child);
+    }
+}
+
+/// Are `a` and `b` the same node, rather than just equal?
+pub fn same_node(a: &Rc<Node>, b: &Rc<Node>) -> bool {
+    &**a as *const Node == &**b as *const Node
+}
+
 // Element methods
 
 impl ElementData {
+    /// The value of the attribute `name`, if the element has it.
+    pub fn get_attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.get(name).map(|value| value.as_slice())
+    }
+
+    /// Set the attribute `name` to `value`, replacing any previous value.
+    ///
+    /// Nodes can't be changed once they are shared in a tree, so this is for building elements
+    /// (or for nodes that are still uniquely owned, through `Rc::get_mut`).
+    pub fn set_attribute(&mut self, name: &str, value: &str) {
+        self.attributes.insert(name.to_string(), value.to_string());
+    }
+
     pub fn id(&self) -> Option<&String> {
         self.attributes.get("id")
     }
@@ -85,30 +188,116 @@ pub fn find_style(node: &Rc<Node>) -> String {
     style_string
 }
 
-pub fn show_all(node: &Rc<Node>, depth: usize) {
-    for i in range(0us, depth) {
-        print!("--");
+/// Describe a node and all its descendants, one line per node.
+pub fn dump_all(node: &Rc<Node>, depth: usize) -> String {
+    let mut result = String::new();
+    for _ in range(0us, depth) {
+        result.push_str("--");
     }
 
-    show(node);
+    result.push_str(dump(node).as_slice());
 
     for i in node.children.iter() {
-        show_all(i, depth + 1);
+        result.push_str(dump_all(i, depth + 1).as_slice());
     }
+    result
 }
 
-pub fn show(node: &Rc<Node>) {
-    match node.node_type {
-        NodeType::Element(ref data) => print!(" Element: {}", data.tag_name),
-        NodeType::Text(ref string) => print!(" Text: {}", string),
+/// Describe a single node and its parent, as one line.
+pub fn dump(node: &Rc<Node>) -> String {
+    let mut result = match node.node_type {
+        NodeType::Element(ref data) => format!(" Element: {}", data.tag_name),
+        NodeType::Text(ref string) => format!(" Text: {}", string),
+    };
+
+    match node.parent() {
+        None => result.push_str(" -> No parent\n"),
+        Some(parent) => {
+            let parent = match parent.node_type {
+                NodeType::Element(ref data) => format!(" -> parent: {}\n", data.tag_name),
+                NodeType::Text(ref string) => format!(" -> parent: {}\n", string),
+            };
+            result.push_str(parent.as_slice());
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use super::{elem, set_parents, same_node, dump_all, Node, NodeType};
+    use html;
+
+    #[test]
+    fn get_attribute_reads_a_data_attribute() {
+        let root = html::parse("<div data-testid=\"card\" id=\"a\"></div>".to_string());
+        match root.node_type {
+            NodeType::Element(ref data) => {
+                assert_eq!(data.get_attribute("data-testid"), Some("card"));
+                assert_eq!(data.get_attribute("data-missing"), None);
+            }
+            NodeType::Text(_) => panic!("expected an element"),
+        }
+    }
+
+    #[test]
+    fn set_attribute_adds_and_replaces_values() {
+        let mut node = elem("div".to_string(), HashMap::new(), vec![]);
+        match node.node_type {
+            NodeType::Element(ref mut data) => {
+                data.set_attribute("data-state", "open");
+                assert_eq!(data.get_attribute("data-state"), Some("open"));
+                data.set_attribute("data-state", "closed");
+                assert_eq!(data.get_attribute("data-state"), Some("closed"));
+            }
+            NodeType::Text(_) => panic!("expected an element"),
+        }
+    }
+
+    #[test]
+    fn element_positions_skip_text_siblings() {
+        let root = html::parse("<ul>one<li></li> two <li></li><li></li>three</ul>".to_string());
+        set_parents(&root);
+        let positions: Vec<(usize, usize)> = root.children.iter()
+            .filter(|child| child.is_element())
+            .map(|child| child.element_position())
+            .collect();
+        assert_eq!(positions, vec![(1, 3), (2, 3), (3, 3)]);
+        assert_eq!(root.element_position(), (1, 1));
     }
 
-    if node.parent.borrow().is_empty() {
-        println!(" -> No parent");
-    } else {
-        match node.parent.borrow().last().unwrap().upgrade().unwrap().node_type {
-            NodeType::Element(ref data) => println!(" -> parent: {}", data.tag_name),
-            NodeType::Text(ref string) => println!(" -> parent: {}", string),
+    #[test]
+    fn dump_all_indents_each_level_and_names_the_parent() {
+        let root = html::parse("<div><p>hi</p></div>".to_string());
+        set_parents(&root);
+        assert_eq!(dump_all(&root, 0).as_slice(),
+                   " Element: div -> No parent\n\
+                    -- Element: p -> parent: div\n\
+                    ---- Text: hi -> parent: p\n");
+    }
+
+    #[test]
+    fn set_parents_links_every_node_to_its_own_parent() {
+        /// Check that each child below `node` has `node` as its parent.
+        fn check_children(node: &Rc<Node>) -> usize {
+            let mut count = 0;
+            for child in node.children.iter() {
+                assert!(same_node(&child.parent().unwrap(), node));
+                count += 1 + check_children(child);
+            }
+            count
         }
+
+        let root = html::parse("<div><p>one <em>two</em></p><ul><li>three</li><li></li></ul></div>".to_string());
+        set_parents(&root);
+        assert!(root.parent().is_none());
+        assert_eq!(check_children(&root), 8);
+
+        // Linking the same tree again doesn't give any node a second parent.
+        set_parents(&root);
+        assert!(root.parent().is_none());
+        assert_eq!(check_children(&root), 8);
     }
 }
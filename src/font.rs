@@ -1,5 +1,11 @@
 
 use std::default::Default;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ascii::{AsciiExt, OwnedAsciiExt};
+use std::ffi::CString;
+use std::rc::Rc;
+use std::io::stderr;
 
 use font_context::FontContextHandle;
 use freetype::freetype::{FT_Face, FT_New_Face, FT_Done_Face, FT_Error};
@@ -7,7 +13,7 @@ use freetype::freetype::{FT_Get_Char_Index, FT_Set_Char_Size, FT_Load_Glyph, FT_
 use freetype::freetype::{FT_UInt, FT_ULong, FT_Vector, struct_FT_Vector_};
 use freetype::freetype::{FT_Load_Char, FT_LOAD_RENDER};
 use freetype::freetype::{FT_Get_Kerning, FT_KERNING_DEFAULT};
-use freetype::freetype::{FT_Bitmap};
+use freetype::freetype::{FT_Bitmap, FT_Set_Pixel_Sizes};
 
 use painting::{Canvas};
 use color::{Color};
@@ -32,17 +38,260 @@ impl Default for TextDecoration {
 
 impl Copy for TextDecoration {}
 
-#[derive(Show, Clone, Default)]
+impl TextDecoration {
+    /// The decoration for a `text-decoration` keyword, or `None` if it isn't supported.
+    pub fn from_keyword(keyword: &str) -> Option<TextDecoration> {
+        match keyword {
+            "none" => Some(TextDecoration::Normal),
+            "underline" => Some(TextDecoration::Underline),
+            "overline" => Some(TextDecoration::Overline),
+            "line-through" => Some(TextDecoration::LineThrough),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Show, Clone, PartialEq)]
+pub enum FontWeight {
+    Normal,
+    Bold,
+}
+
+impl Default for FontWeight {
+    fn default() -> FontWeight {
+        FontWeight::Normal
+    }
+}
+
+impl Copy for FontWeight {}
+
+#[derive(Show, Clone, PartialEq)]
+pub enum FontStyle {
+    Normal,
+    Italic,
+}
+
+impl Default for FontStyle {
+    fn default() -> FontStyle {
+        FontStyle::Normal
+    }
+}
+
+impl Copy for FontStyle {}
+
+/// Whether the standard ligatures, like `fi`, are used (`font-variant-ligatures`, or the `liga`
+/// feature in `font-feature-settings`).
+#[derive(Show, Clone, PartialEq)]
+pub enum Ligatures {
+    Common,
+    None,
+}
+
+impl Default for Ligatures {
+    fn default() -> Ligatures {
+        Ligatures::Common
+    }
+}
+
+impl Copy for Ligatures {}
+
+#[derive(Show, Clone)]
+pub struct TextShadow {
+    pub offset_x: i32,
+    pub offset_y: i32,
+    pub color: Color,
+}
+
+impl Copy for TextShadow {}
+
+/// The font file used when no other is given.
+pub static DEFAULT_FONT_PATH: &'static str = "./examples/verdana.ttf";
+
+/// The font files available for text: the default font, and the font map of files for
+/// `font-family` names and their bold and italic variants.
+#[derive(Show)]
+pub struct FontConfig {
+    pub default_path: String,
+    /// Font files keyed by lowercase family or variant name, such as `verdana bold`.
+    pub families: HashMap<String, String>,
+}
+
+impl Default for FontConfig {
+    fn default() -> FontConfig {
+        FontConfig::new(DEFAULT_FONT_PATH.to_string(), HashMap::new())
+    }
+}
+
+impl FontConfig {
+    /// Use the TrueType font at `default_path` for text, except where `font-family` names one of
+    /// the `families`.  Family names are case-insensitive.
+    pub fn new(default_path: String, families: HashMap<String, String>) -> FontConfig {
+        FontConfig {
+            default_path: default_path,
+            families: families.into_iter().map(|(name, path)| (name.into_ascii_lowercase(), path)).collect(),
+        }
+    }
+
+    /// The first of `names` that has a font file, in lowercase.
+    pub fn available_family(&self, names: &[String]) -> Option<String> {
+        names.iter().map(|name| name.to_ascii_lowercase()).find(|name| self.families.contains_key(name))
+    }
+
+    /// The path of the font file for text in `family` with `weight` and `style`.
+    ///
+    /// Bold and italic text uses the matching variant from the font map.  Without one, the
+    /// regular face is used instead.
+    pub fn face_path(&self, family: Option<&str>, weight: FontWeight, style: FontStyle) -> String {
+        if let Some(variant) = variant_name(family, weight, style) {
+            match self.families.get(&variant) {
+                Some(path) => return path.clone(),
+                None => warn_missing_variant(variant)
+            }
+        }
+        self.family_path(family)
+    }
+
+    /// The path of the TrueType font for `family`, or the default font without one.
+    fn family_path(&self, family: Option<&str>) -> String {
+        family.and_then(|family| self.families.get(family).cloned()).unwrap_or_else(|| self.default_path.clone())
+    }
+}
+
+/// The name of the bold, italic, or bold italic variant of `family` in the font map, such as
+/// `verdana bold`.  The variants of the default font are just `bold`, `italic`, and
+/// `bold italic`.  Returns `None` for the regular variant.
+fn variant_name(family: Option<&str>, weight: FontWeight, style: FontStyle) -> Option<String> {
+    let variant = match (weight, style) {
+        (FontWeight::Normal, FontStyle::Normal) => return None,
+        (FontWeight::Bold, FontStyle::Normal) => "bold",
+        (FontWeight::Normal, FontStyle::Italic) => "italic",
+        (FontWeight::Bold, FontStyle::Italic) => "bold italic",
+    };
+    Some(match family {
+        Some(family) => format!("{} {}", family, variant),
+        None => variant.to_string(),
+    })
+}
+
+/// Faces opened so far, keyed by font path and pixel size.  Each face stays open until the cache
+/// is dropped, so the library handle that owns them is kept here too.
+///
+/// Glyphs are kept as well, keyed by the face they were rendered from and the character.
+pub struct FontCache {
+    handle: FontContextHandle,
+    faces: HashMap<(String, i32), FT_Face>,
+    glyphs: HashMap<(usize, char), Glyph>,
+    /// Font variants that were asked for but have no font file, so they are only warned about
+    /// once.
+    missing_variants: HashSet<String>,
+}
+
+impl FontCache {
+    pub fn new() -> FontCache {
+        FontCache {
+            handle: FontContextHandle::new(),
+            faces: HashMap::new(),
+            glyphs: HashMap::new(),
+            missing_variants: HashSet::new(),
+        }
+    }
+
+    /// The face for the font at `path` set to `size` pixels, opening it on first use.
+    pub fn face(&mut self, path: &str, size: i32) -> Option<FT_Face> {
+        let key = (path.to_string(), size);
+        if let Some(face) = self.faces.get(&key) {
+            return Some(*face);
+        }
+
+        unsafe {
+            let mut face: FT_Face = ptr::null_mut();
+            let filename = CString::from_slice(path.as_bytes());
+            let error = FT_New_Face(self.handle.ctx.ctx, filename.as_ptr() as *mut i8, 0, &mut face);
+            if error != 0 || face.is_null() {
+                let _ = stderr().write_line(format!("failed to load font {}", path).as_slice());
+                return None;
+            }
+            if FT_Set_Pixel_Sizes(face, 0, size as u32) != 0 {
+                let _ = stderr().write_line(format!("failed to set pixel size: {}", size).as_slice());
+                FT_Done_Face(face);
+                return None;
+            }
+            self.faces.insert(key, face);
+            Some(face)
+        }
+    }
+}
+
+impl Drop for FontCache {
+    fn drop(&mut self) {
+        for face in self.faces.values() {
+            unsafe { FT_Done_Face(*face) };
+        }
+    }
+}
+
+thread_local!(static FONT_CACHE: RefCell<FontCache> = RefCell::new(FontCache::new()));
+
+/// The face for text with `font_info`, shared by everything that lays out or paints text in the
+/// same font and size.
+pub fn cached_face(font_info: &FontInfo) -> Option<FT_Face> {
+    FONT_CACHE.with(|cache| cache.borrow_mut().face(font_info.path.as_slice(), font_info.size))
+}
+
+/// Warn that there is no font file for `variant`, the first time it is asked for.
+fn warn_missing_variant(variant: String) {
+    FONT_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if cache.missing_variants.insert(variant.clone()) {
+            let _ = stderr().write_line(format!("no font file for '{}', using the regular face", variant).as_slice());
+        }
+    })
+}
+
+#[derive(Show, Clone)]
 pub struct FontInfo {
     pub size: i32,
     pub line_height: i32,
     pub color: Color,
     pub deco: TextDecoration,
+    pub shadow: Option<TextShadow>,
+    /// The family chosen from `font-family`, or `None` for the default font.
+    pub family: Option<String>,
+    pub weight: FontWeight,
+    pub style: FontStyle,
+    pub ligatures: Ligatures,
+    /// The font file for the family, weight, and style.
+    pub path: String,
+    /// The font files to choose `path` from.
+    pub fonts: Rc<FontConfig>,
 }
 
-impl Copy for FontInfo {}
+impl Default for FontInfo {
+    fn default() -> FontInfo {
+        FontInfo::new(Rc::new(Default::default()))
+    }
+}
 
-#[derive(Default, Show)]
+impl FontInfo {
+    /// The font info for the root of a document, with the default font from `fonts`.
+    pub fn new(fonts: Rc<FontConfig>) -> FontInfo {
+        FontInfo {
+            size: 0,
+            line_height: 0,
+            color: Default::default(),
+            deco: Default::default(),
+            shadow: None,
+            family: None,
+            weight: Default::default(),
+            style: Default::default(),
+            ligatures: Default::default(),
+            path: fonts.default_path.clone(),
+            fonts: fonts,
+        }
+    }
+}
+
+#[derive(Default, Show, Clone)]
 pub struct Glyph {
     pub top: i32,
     pub height: i32,
@@ -62,7 +311,29 @@ pub struct Text_Dimension {
     // unsigned char *outpuffer;
 }
 
+/// The glyph for `character` in `face`, rendered once per face and character and then reused.
+/// Without `bBitmap` the glyph's pixelmap is left empty.
 pub fn get_glyph(character: char, face: &FT_Face, bBitmap: bool) -> Glyph {
+    // Faces stay open as long as the cache, so their addresses identify them.
+    let key = (*face as usize, character);
+
+    FONT_CACHE.with(|cache| {
+        let mut cache = cache.borrow_mut();
+        if !cache.glyphs.contains_key(&key) {
+            let glyph = load_glyph(character, face);
+            cache.glyphs.insert(key, glyph);
+        }
+
+        let glyph = cache.glyphs.get(&key).unwrap();
+        if bBitmap {
+            glyph.clone()
+        } else {
+            Glyph { pixelmap: Default::default(), ..*glyph }
+        }
+    })
+}
+
+fn load_glyph(character: char, face: &FT_Face) -> Glyph {
     unsafe {
         let error = FT_Load_Char(*face, character as u64, FT_LOAD_RENDER);
         if error != 0 {
@@ -70,8 +341,48 @@ pub fn get_glyph(character: char, face: &FT_Face, bBitmap: bool) -> Glyph {
         }
 
         let slot: FT_GlyphSlot = mem::transmute((**face).glyph);
-        return convert_glyph(&slot, bBitmap);
+        return convert_glyph(&slot, true);
+    }
+}
+
+/// The standard ligatures, and the Unicode characters for them.  Longer sequences come first, so
+/// that `ffi` doesn't become `ff` followed by `i`.
+static LIGATURES: [(&'static str, char); 5] = [
+    ("ffi", '\u{FB03}'),
+    ("ffl", '\u{FB04}'),
+    ("ff", '\u{FB00}'),
+    ("fi", '\u{FB01}'),
+    ("fl", '\u{FB02}'),
+];
+
+/// Replace the standard ligatures in `text` with single characters, where `face` has glyphs for
+/// them.  There is no OpenType shaping, so only ligatures with their own Unicode characters can be
+/// used.
+///
+/// Text should be passed through this once, before it is both measured and painted.
+pub fn apply_ligatures(text: &str, face: &FT_Face) -> String {
+    let available: Vec<(&'static str, char)> = LIGATURES.iter().map(|&ligature| ligature)
+        .filter(|&(_, c)| unsafe { FT_Get_Char_Index(*face, c as u64) } != 0)
+        .collect();
+    if available.is_empty() {
+        return text.to_string();
     }
+
+    let mut result = String::new();
+    let mut rest = text;
+    'chars: while !rest.is_empty() {
+        for &(sequence, ligature) in available.iter() {
+            if rest.starts_with(sequence) {
+                result.push(ligature);
+                rest = rest.slice_from(sequence.len());
+                continue 'chars;
+            }
+        }
+        let c = rest.char_at(0);
+        result.push(c);
+        rest = rest.slice_from(c.len_utf8());
+    }
+    result
 }
 
 pub fn calculate_text_dimension(text: &str, face: &FT_Face) -> Text_Dimension {
@@ -117,6 +428,14 @@ pub fn calculate_text_dimension(text: &str, face: &FT_Face) -> Text_Dimension {
     return result;
 }
 
+/// The font's own line spacing at its current size, used for `line-height: normal`.
+pub fn normal_line_height(face: &FT_Face) -> i32 {
+    unsafe {
+        // The metrics are in 26.6 fixed point.
+        ((*(**face).size).metrics.height / 64) as i32
+    }
+}
+
 pub fn kerning_offset(c: char, pc: char, face: &FT_Face) -> i32 {
     let mut kerning = struct_FT_Vector_ { x: 0, y: 0 };
 
@@ -124,7 +443,7 @@ pub fn kerning_offset(c: char, pc: char, face: &FT_Face) -> i32 {
         let error = FT_Get_Kerning(*face, c as u32, pc as u32, FT_KERNING_DEFAULT, &mut kerning);
 
         if error != 0 {
-            println!("failed to get kerning");
+            let _ = stderr().write_line("failed to get kerning");
         }
     }
 
@@ -184,3 +503,48 @@ fn draw_char(bitmap: &FT_Bitmap) -> Canvas {
 
     return canvas;
 }
+
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use freetype::freetype::FT_Face;
+    use super::{FONT_CACHE, FontInfo, cached_face, get_glyph, calculate_text_dimension};
+
+    /// The number of glyphs cached for `face`.
+    fn cached_glyphs(face: FT_Face) -> usize {
+        FONT_CACHE.with(|cache| cache.borrow().glyphs.keys().filter(|&&(f, _)| f == face as usize).count())
+    }
+
+    fn face(size: i32) -> FT_Face {
+        let font_info = FontInfo { size: size, ..Default::default() };
+        cached_face(&font_info).unwrap()
+    }
+
+    #[test]
+    fn each_glyph_is_rendered_once_per_size() {
+        let small = face(13);
+        let before = cached_glyphs(small);
+        let first = get_glyph('Q', &small, true);
+        let second = get_glyph('Q', &small, true);
+        assert_eq!(cached_glyphs(small), before + 1);
+        assert_eq!(first.advance_width, second.advance_width);
+        assert_eq!(first.pixelmap.pixels, second.pixelmap.pixels);
+
+        // Another size is another face, with glyphs of its own.
+        let large = face(39);
+        let large_glyph = get_glyph('Q', &large, true);
+        assert!(large_glyph.advance_width > first.advance_width);
+        assert_eq!(cached_glyphs(small), before + 1);
+    }
+
+    #[test]
+    fn measuring_a_long_text_caches_only_its_distinct_characters() {
+        let face = face(17);
+        let text: String = "the quick brown fox ".chars().cycle().take(2000).collect();
+        calculate_text_dimension(text.as_slice(), &face);
+        let distinct = cached_glyphs(face);
+        assert!(distinct <= 16);
+        calculate_text_dimension(text.as_slice(), &face);
+        assert_eq!(cached_glyphs(face), distinct);
+    }
+}
@@ -15,6 +15,8 @@ use std::collections::HashMap;
 use std::rc::Rc;
 
 /// Parse an HTML document and return the root element.
+///
+/// Parent links are not set; call `dom::set_parents` on the result.
 pub fn parse(source: String) -> Rc<dom::Node> {
     let mut nodes = Parser { pos: 0, input: source }.parse_nodes();
 
@@ -47,16 +49,10 @@ impl Parser {
 
     /// Parse a single node.
     fn parse_node(&mut self) -> Rc<dom::Node> {
-        let node = match self.next_char() {
+        match self.next_char() {
             '<' => self.parse_element(),
             _   => self.parse_text()
-        };
-
-        // set parent
-        for child in node.children.iter() {
-            child.parent.borrow_mut().push(node.clone().downgrade());
         }
-        return node;
     }
 
     /// Parse a single element, including its open tag, contents, and closing tag.
@@ -101,7 +97,10 @@ impl Parser {
                 break;
             }
             let (name, value) = self.parse_attr();
-            attributes.insert(name, value);
+            // When an attribute is repeated, the first one wins.
+            if !attributes.contains_key(&name) {
+                attributes.insert(name, value);
+            }
         }
         return attributes;
     }
@@ -179,3 +178,22 @@ impl Parser {
         return self_closing_tags.contains(&tag_name);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::parse;
+    use dom::NodeType;
+
+    #[test]
+    fn first_of_a_repeated_attribute_wins() {
+        let root = parse("<div id=\"a\" class=\"x\" id=\"b\"></div>".to_string());
+        match root.node_type {
+            NodeType::Element(ref data) => {
+                assert_eq!(data.get_attribute("id"), Some("a"));
+                assert_eq!(data.get_attribute("class"), Some("x"));
+                assert_eq!(data.attributes.len(), 2);
+            }
+            NodeType::Text(_) => panic!("expected an element"),
+        }
+    }
+}
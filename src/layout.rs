@@ -1,15 +1,22 @@
 ///! Basic CSS block layout.
 
-use style::{StyledNode, Display, Float, Clear};
-use css::{Value};
+use style::{StyledNode, PropertyMap, Display, Float, Clear, Position, Overflow, OverflowWrap, WhiteSpace, BoxSizing};
+use css::{Value, Selector};
 use css::Value::{Keyword, Length};
-use css::Unit::Px;
+use css::Unit;
+use css::Unit::{Px, Em};
 use std::default::Default;
+use std::io::stderr;
+use std::rc::Rc;
 use std::iter::AdditiveIterator; // for `sum`
+use std::num::Float as FloatMath; // for `min` and `max`
 
 use dom::{NodeType};
+use style;
+use bidi;
+use bidi::Direction;
 
-pub use self::BoxType::{AnonymousBlock, InlineNode, BlockNode, FloatNode, TextNode};
+pub use self::BoxType::{AnonymousBlock, InlineNode, InlineBlockNode, BlockNode, FloatNode, AbsoluteNode, TextNode};
 
 use font_context::FontContextHandle;
 use freetype::freetype::{FT_Face, FT_New_Face, FT_Done_Face, FT_Error};
@@ -18,7 +25,7 @@ use freetype::freetype::{FT_UInt, FT_ULong, FT_Vector, struct_FT_Vector_};
 use freetype::freetype::{FT_Load_Char, FT_LOAD_RENDER};
 use freetype::freetype::{FT_Get_Kerning, FT_KERNING_DEFAULT};
 
-use font::{TextDecoration, FontInfo, Glyph, Text_Dimension, get_glyph, calculate_text_dimension};
+use font::{TextDecoration, TextShadow, FontInfo, FontWeight, FontStyle, Ligatures, apply_ligatures, Glyph, Text_Dimension, get_glyph, calculate_text_dimension, normal_line_height, cached_face, FontConfig};
 
 use std::ptr;
 use std::mem;
@@ -62,12 +69,22 @@ pub struct LayoutBox<'a> {
     pub children: Vec<LayoutBox<'a>>,
     pub float_info: FloatInfo,
     pub font_info: FontInfo,
+    /// For an inline box, the content rectangle of each line it spans, in order.
+    pub fragments: Vec<Rect>,
+    /// The content height of the containing block, if it is known before this box is laid out.
+    /// Percentage heights are resolved against it, and treated as `auto` without it.
+    pub containing_height: Option<f32>,
+    /// For a text box, whether it starts a new line after a preserved newline rather than
+    /// following the inline content before it.
+    pub starts_line: bool,
 }
 
 pub enum BoxType<'a> {
     BlockNode(&'a StyledNode<'a>),
     InlineNode(&'a StyledNode<'a>),
+    InlineBlockNode(&'a StyledNode<'a>),
     FloatNode(&'a StyledNode<'a>),
+    AbsoluteNode(&'a StyledNode<'a>),
     TextNode(String),
     AnonymousBlock,
 }
@@ -86,6 +103,9 @@ impl<'a> LayoutBox<'a> {
             children: Vec::new(),
             float_info: Default::default(),
             font_info: Default::default(),
+            fragments: Vec::new(),
+            containing_height: None,
+            starts_line: false,
         }
     }
 
@@ -93,24 +113,182 @@ impl<'a> LayoutBox<'a> {
         match self.box_type {
             BlockNode(node) => node,
             InlineNode(node) => node,
+            InlineBlockNode(node) => node,
             FloatNode(node) => node,
+            AbsoluteNode(node) => node,
             TextNode(_) => panic!("text node box has no style node"),
             AnonymousBlock => panic!("Anonymous block box has no style node")
         }
     }
 }
 
+/// The min-content and max-content widths of a run of text: the width of its widest word, and
+/// the width of the whole text on one line.
+///
+/// With `overflow-wrap: anywhere`, the text can break between any two characters, so its
+/// min-content width is that of its widest character instead.  With `white-space: nowrap` or
+/// `pre` it can't break at spaces, so both widths are that of its widest line.  With `pre-line`
+/// the max-content width is that of its widest line.
+fn text_intrinsic_widths(text: &str, font_info: &FontInfo, overflow_wrap: OverflowWrap, white_space: WhiteSpace) -> (f32, f32) {
+    let face = match cached_face(font_info) {
+        Some(face) => face,
+        None => return (0.0, 0.0)
+    };
+
+    let widest_line = |&: text: &str| {
+        text.split('\n').map(|line| calculate_text_dimension(line, &face).width).max().unwrap_or(0)
+    };
+    let text = match white_space {
+        WhiteSpace::Normal => collapse_whitespace(text),
+        WhiteSpace::NoWrap | WhiteSpace::Pre => {
+            let text = match white_space {
+                WhiteSpace::Pre => text.to_string(),
+                _ => collapse_whitespace(text).trim().to_string(),
+            };
+            let text = match font_info.ligatures {
+                Ligatures::Common => apply_ligatures(text.as_slice(), &face),
+                Ligatures::None => text,
+            };
+            let width = widest_line(text.as_slice()) as f32;
+            return (width, width);
+        }
+        WhiteSpace::PreLine => {
+            let lines: Vec<String> = text.split('\n').map(|line| collapse_whitespace(line).trim().to_string()).collect();
+            lines.connect("\n")
+        }
+    };
+    let text = match font_info.ligatures {
+        Ligatures::Common => apply_ligatures(text.as_slice(), &face),
+        Ligatures::None => text,
+    };
+
+    let text = text.trim();
+    let min_content = match overflow_wrap {
+        OverflowWrap::Anywhere => text.chars().map(|c| get_glyph(c, &face, false).advance_width).max().unwrap_or(0),
+        _ => text.split(|&: c: char| c == ' ' || c == '\n').map(|word| calculate_text_dimension(word, &face).width).max().unwrap_or(0),
+    };
+    let max_content = widest_line(text);
+    (min_content as f32, max_content as f32)
+}
+
+/// Collapse each run of spaces, tabs, and line breaks in `text` into a single space, as
+/// `white-space: normal` does.  A run at the start or end of the text leaves a single space too.
+fn collapse_whitespace(text: &str) -> String {
+    let mut collapsed = String::new();
+    let mut in_whitespace = false;
+    for c in text.chars() {
+        match c {
+            ' ' | '\t' | '\n' | '\r' | '\x0c' => {
+                if !in_whitespace {
+                    collapsed.push(' ');
+                }
+                in_whitespace = true;
+            }
+            _ => {
+                collapsed.push(c);
+                in_whitespace = false;
+            }
+        }
+    }
+    collapsed
+}
+
+/// Cut `text` short with an ellipsis so that it fits in `width`, if it doesn't already.
+fn truncate_with_ellipsis(text: &str, face: &FT_Face, width: f32) -> String {
+    if calculate_text_dimension(text, face).width as f32 <= width {
+        return text.to_string();
+    }
+    let mut truncated = text.to_string();
+    while truncated.pop().is_some() {
+        let candidate = format!("{}\u{2026}", truncated.trim_right());
+        if calculate_text_dimension(candidate.as_slice(), face).width as f32 <= width {
+            return candidate;
+        }
+    }
+    "\u{2026}".to_string()
+}
+
+/// Whether `font-feature-settings` turns the OpenType feature `tag` on or off, or `None` if it
+/// doesn't mention it.  A setting is a quoted tag followed by `0` or `off` to turn it off, and by
+/// nothing, `on`, or any other number to turn it on.  The last setting for a tag wins.
+fn feature_setting(settings: &Value, tag: &str) -> Option<bool> {
+    let settings = match *settings {
+        Value::List(ref settings) => settings,
+        _ => return None
+    };
+    settings.iter().rev().filter_map(|setting| match *setting {
+        Value::List(ref values) => match values.as_slice() {
+            [Keyword(ref name)] if name.as_slice() == tag => Some(true),
+            [Keyword(ref name), Length(value, _)] if name.as_slice() == tag => Some(value != 0.0),
+            [Keyword(ref name), Keyword(ref value)] if name.as_slice() == tag => Some(value.as_slice() != "off"),
+            _ => None
+        },
+        _ => None
+    }).next()
+}
+
+/// The width left for a line of text starting at `x`, between `y` and `y + height`, once the
+/// floats beside it are taken out of `containing_block`.
+fn available_width(containing_block: Rect, x: f32, y: f32, height: f32, float_list: &[(Float, Dimensions)]) -> f32 {
+    let (left, right) = line_box_edges(containing_block, y, height, float_list);
+    (right - left.max(x)).max(0.0)
+}
+
+/// The left and right edges of a line box between `y` and `y + height`: the edges of
+/// `containing_block`, moved in past the floats beside the line.
+fn line_box_edges(containing_block: Rect, y: f32, height: f32, float_list: &[(Float, Dimensions)]) -> (f32, f32) {
+    let mut left = containing_block.x;
+    let mut right = containing_block.max_x();
+    for &(ref direction, ref float) in float_list.iter() {
+        let margin_box = float.margin_box();
+        if margin_box.y >= y + height || margin_box.max_y() <= y {
+            continue;
+        }
+        match *direction {
+            Float::FloatLeft => left = left.max(margin_box.max_x()),
+            Float::FloatRight => right = right.min(margin_box.x),
+        }
+    }
+    (left, right)
+}
+
+/// The content width or height left by a specified `width` or `height` (`name`) of `size` px.
+/// With `box-sizing: border-box` the specified size includes the padding and border, `edges`.
+fn content_size(style: &StyledNode, name: &str, size: f32, edges: f32) -> f32 {
+    match (style.box_sizing(), style.value(name)) {
+        (BoxSizing::BorderBox, Some(Length(_, _))) |
+        (BoxSizing::BorderBox, Some(Value::Function(_, _))) => (size - edges).max(0.0),
+        _ => size
+    }
+}
+
+/// The combined size of two adjoining margins: the largest positive margin plus the most negative
+/// one.
+fn collapse_margins(a: f32, b: f32) -> f32 {
+    a.max(b).max(0.0) + a.min(b).min(0.0)
+}
+
 /// Transform a style tree into a layout tree.
-pub fn layout_tree<'a>(node: &'a StyledNode<'a>, mut containing_block: Dimensions) -> LayoutBox<'a> {
-    // The layout algorithm expects the container height to start at 0.
-    // TODO: Save the initial containing block height, for calculating percent heights.
+///
+/// Text is set in the font files from `fonts`.
+pub fn layout_tree<'a>(node: &'a StyledNode<'a>, mut containing_block: Dimensions, fonts: Rc<FontConfig>) -> LayoutBox<'a> {
+    // Absolutely positioned boxes with no positioned ancestor are placed in the initial
+    // containing block.
+    let initial_containing_block = containing_block.content;
+
+    // The layout algorithm expects the container height to start at 0.  Percent heights of the
+    // root are resolved against the initial containing block instead.
     containing_block.content.height = 0.0;
 
     let mut root_box = build_layout_tree(node);
+    root_box.containing_height = Some(initial_containing_block.height);
 
     let mut float_list: Vec<(Float, Dimensions)> = Vec::new();
     let mut previous_inline: Option<(i32, i32)> = None;
-    root_box.layout(containing_block, &mut float_list, &mut previous_inline);
+    root_box.layout(containing_block, FontInfo::new(fonts), &mut float_list, &mut previous_inline);
+    if !root_box.is_positioned() {
+        root_box.layout_absolute_descendants(initial_containing_block);
+    }
     return root_box;
 }
 
@@ -120,57 +298,129 @@ fn build_layout_tree<'a>(style_node: &'a StyledNode<'a>) -> LayoutBox<'a> {
     let mut root = create_layout_box(style_node);
 
     // Create the descendant boxes.
+    build_children(&mut root, style_node);
+    if style_node.tag_name() == "table" {
+        place_captions(&mut root);
+    }
+    return root;
+}
+
+/// Move a table's `<caption>` boxes above the rest of it, or below with `caption-side: bottom`.
+///
+/// There is no table layout yet, so the rows are laid out as ordinary blocks and a caption spans
+/// the table's full width.
+fn place_captions(table: &mut LayoutBox) {
+    let mut top = Vec::new();
+    let mut rows = Vec::new();
+    let mut bottom = Vec::new();
+    for child in mem::replace(&mut table.children, Vec::new()).into_iter() {
+        let caption_side = match child.box_type {
+            BlockNode(style) if style.tag_name() == "caption" => style.value("caption-side"),
+            _ => {
+                rows.push(child);
+                continue;
+            }
+        };
+        match caption_side {
+            Some(Keyword(ref side)) if side.as_slice() == "bottom" => bottom.push(child),
+            _ => top.push(child),
+        }
+    }
+    table.children = top;
+    table.children.extend(rows.into_iter());
+    table.children.extend(bottom.into_iter());
+}
+
+/// Build boxes for the children of `style_node`, and add them to `root`.
+fn build_children<'a>(root: &mut LayoutBox<'a>, style_node: &'a StyledNode<'a>) {
     for child in style_node.children.iter() {
         match child.display() {
             Display::Block => root.children.push(build_layout_tree(child)),
-            Display::Inline => root.get_inline_container().children.push(build_layout_tree(child)),
+            // Absolutely positioned boxes are taken out of the inline flow.
+            Display::Inline | Display::InlineBlock if child.position() == Position::Absolute =>
+                root.children.push(build_layout_tree(child)),
+            Display::Inline | Display::InlineBlock => root.get_inline_container().children.push(build_layout_tree(child)),
+            // A node with `display: contents;` has no box of its own; its children are laid out
+            // as if they were children of its parent.
+            Display::Contents => build_children(root, child),
             Display::None => {} // Don't lay out nodes with `display: none;`
         }
     }
-    return root;
 }
 
 fn create_layout_box<'a>(style_node: &'a StyledNode<'a>) -> LayoutBox<'a> {
+    if style_node.position() == Position::Absolute {
+        return LayoutBox::new(AbsoluteNode(style_node));
+    }
+
     if let Some(_) = style_node.value("float") {
         return LayoutBox::new(FloatNode(style_node));
     }
 
     LayoutBox::new(match style_node.display() {
-        Display::Block => BlockNode(style_node),
+        // The root element always generates a box.
+        Display::Block | Display::Contents => BlockNode(style_node),
         Display::Inline => InlineNode(style_node),
+        Display::InlineBlock => InlineBlockNode(style_node),
         Display::None => panic!("Root node has display: none.")
     })
 }
 
 impl<'a> LayoutBox<'a> {
-    /// Lay out a box and its descendants.
-    fn layout(&mut self, containing_block: Dimensions, float_list: &mut Vec<(Float, Dimensions)>, previous_inline: &mut Option<(i32, i32)>) {
+    /// Lay out a box and its descendants, inheriting `font_info`.
+    fn layout(&mut self, containing_block: Dimensions, font_info: FontInfo, float_list: &mut Vec<(Float, Dimensions)>, previous_inline: &mut Option<(i32, i32)>) {
         match self.box_type {
-            BlockNode(_) => self.layout_block(containing_block, float_list, previous_inline),
-            InlineNode(_) => self.layout_inline(containing_block, float_list, previous_inline),
-            FloatNode(_) => self.layout_float(containing_block, &mut Default::default(), None, float_list, previous_inline),
-            TextNode(_) => self.layout_text(containing_block, Default::default(), previous_inline),
-            AnonymousBlock => self.layout_anonymous(containing_block, Default::default(), float_list, previous_inline),
+            BlockNode(_) => self.layout_block(containing_block, font_info, float_list, previous_inline),
+            InlineNode(_) => self.layout_inline(containing_block, font_info, float_list, previous_inline),
+            InlineBlockNode(_) => self.layout_inline_block(containing_block, font_info, previous_inline),
+            FloatNode(_) => self.layout_float(containing_block, font_info, &mut Default::default(), None, float_list, previous_inline),
+            AbsoluteNode(_) => self.layout_absolute(containing_block.content, font_info),
+            TextNode(_) => self.layout_text(containing_block, font_info, previous_inline),
+            AnonymousBlock => self.layout_anonymous(containing_block, font_info, float_list, previous_inline),
         }
     }
 
-    fn fill_font_info(&mut self) {
+    /// Set this box's font info from its style, starting from the font info of its parent so that
+    /// unspecified properties are inherited.
+    fn fill_font_info(&mut self, parent_font_info: FontInfo) {
+        self.font_info = self.specified_font(&parent_font_info);
         match self.box_type {
-            BlockNode(style) | InlineNode(style) | FloatNode(style) => {
+            BlockNode(style) | InlineNode(style) | InlineBlockNode(style) | FloatNode(style) | AbsoluteNode(style) => {
                 if let Some(Value::ColorValue(color)) = style.value("color") {
                     self.font_info.color = color;
                 }
-                if let Some(val) = style.value("font-size") {
-                    self.font_info.size = val.to_px().unwrap() as i32;
-                }
-                if let Some(val) = style.value("line-height") {
-                    self.font_info.line_height = val.to_px().unwrap() as i32;
+                match style.value("line-height") {
+                    // Plain numbers are stored as ems.  Like percentages, they are relative to
+                    // the font size of this box rather than the default.
+                    Some(Length(em, Em)) => self.font_info.line_height = (em * self.font_info.size as f32) as i32,
+                    // `normal` is left as zero, to be filled in from the font's metrics.
+                    Some(Keyword(_)) => self.font_info.line_height = 0,
+                    Some(val) => {
+                        let size = self.font_info.size as f32;
+                        self.font_info.line_height = val.length_to_px(size, size) as i32;
+                    }
+                    None => {}
                 }
                 if let Some(Value::Keyword(string)) = style.value("text-decoration") {
-                    if string == "underline" {
-                        self.font_info.deco = TextDecoration::Underline;
-                    } else {
-                        println!("text-decoration '{}' is not supported yet.", string);
+                    match TextDecoration::from_keyword(string.as_slice()) {
+                        Some(deco) => self.font_info.deco = deco,
+                        None => { let _ = stderr().write_line(format!("text-decoration '{}' is not supported yet.", string).as_slice()); }
+                    }
+                }
+                match style.value("text-shadow-color") {
+                    // `text-shadow: none`
+                    Some(Value::Keyword(_)) => self.font_info.shadow = None,
+                    color => if let Some(Length(x, Px)) = style.value("text-shadow-offset-x") {
+                        let y = style.value("text-shadow-offset-y").and_then(|v| v.to_px(self.font_info.size as f32)).unwrap_or(0.0);
+                        self.font_info.shadow = Some(TextShadow {
+                            offset_x: x as i32,
+                            offset_y: y as i32,
+                            // The shadow defaults to the text color.
+                            color: match color {
+                                Some(Value::ColorValue(c)) => c,
+                                _ => self.font_info.color,
+                            },
+                        });
                     }
                 }
             },
@@ -180,20 +430,85 @@ impl<'a> LayoutBox<'a> {
         }
     }
 
+    /// The font size this box will have once its font info is filled in, given its parent's.  Ems
+    /// in `font-size` are relative to the parent's size.
+    fn specified_font_size(&self, parent_font_size: f32) -> f32 {
+        self.get_style_node().value("font-size").and_then(|val| val.to_px(parent_font_size)).unwrap_or(parent_font_size)
+    }
+
+    /// The parent's font info with this box's own font size, family, weight, style, and
+    /// ligatures, which are all that's needed to measure its text.
+    ///
+    /// The family is the first one in `font-family` that has a font file, or the default font
+    /// if none of them do.  The font file is chosen for the family, weight, and style.
+    fn specified_font(&self, parent_font_info: &FontInfo) -> FontInfo {
+        let style = self.get_style_node();
+        let mut font_info = parent_font_info.clone();
+        font_info.size = self.specified_font_size(parent_font_info.size as f32) as i32;
+        match style.value("font-weight") {
+            Some(Keyword(ref weight)) => match weight.as_slice() {
+                "bold" | "bolder" => font_info.weight = FontWeight::Bold,
+                "normal" | "lighter" => font_info.weight = FontWeight::Normal,
+                _ => {}
+            },
+            // Only one bold face is used, for weights from 600 up.
+            Some(Length(weight, _)) => font_info.weight = if weight >= 600.0 { FontWeight::Bold } else { FontWeight::Normal },
+            _ => {}
+        }
+        match style.value("font-variant-ligatures") {
+            Some(Keyword(ref ligatures)) => match ligatures.as_slice() {
+                "none" | "no-common-ligatures" => font_info.ligatures = Ligatures::None,
+                "normal" | "common-ligatures" => font_info.ligatures = Ligatures::Common,
+                _ => {}
+            },
+            _ => {}
+        }
+        // `font-feature-settings` is applied after `font-variant-ligatures`, so it wins.
+        if let Some(liga) = style.value("font-feature-settings").and_then(|settings| feature_setting(&settings, "liga")) {
+            font_info.ligatures = if liga { Ligatures::Common } else { Ligatures::None };
+        }
+        match style.value("font-style") {
+            // There are no separate oblique faces.
+            Some(Keyword(ref font_style)) => match font_style.as_slice() {
+                "italic" | "oblique" => font_info.style = FontStyle::Italic,
+                "normal" => font_info.style = FontStyle::Normal,
+                _ => {}
+            },
+            _ => {}
+        }
+        let families = match style.value("font-family") {
+            Some(Value::List(values)) => Some(values),
+            Some(value) => Some(vec![value]),
+            None => None
+        };
+        if let Some(families) = families {
+            let names: Vec<String> = families.into_iter().filter_map(|value| match value {
+                Keyword(name) => Some(name),
+                _ => None
+            }).collect();
+            font_info.family = font_info.fonts.available_family(names.as_slice());
+        }
+        font_info.path = {
+            let family = font_info.family.as_ref().map(|family| family.as_slice());
+            font_info.fonts.face_path(family, font_info.weight, font_info.style)
+        };
+        font_info
+    }
+
     fn copy_font_info(&mut self, font_info: &FontInfo) {
         match self.box_type {
-            BlockNode(_) | InlineNode(_) | FloatNode(_) => {
+            BlockNode(_) | InlineNode(_) | InlineBlockNode(_) | FloatNode(_) | AbsoluteNode(_) => {
                 panic!("wrong function call!");
             },
             TextNode(_) | AnonymousBlock => {
-                self.font_info = *font_info;
+                self.font_info = font_info.clone();
             }
         }
     }
 
     /// Lay out a block-level element and its descendants.
-    fn layout_block(&mut self, containing_block: Dimensions, float_list: &mut Vec<(Float, Dimensions)>, previous_inline: &mut Option<(i32, i32)>) {
-        self.fill_font_info();
+    fn layout_block(&mut self, containing_block: Dimensions, font_info: FontInfo, float_list: &mut Vec<(Float, Dimensions)>, previous_inline: &mut Option<(i32, i32)>) {
+        self.fill_font_info(font_info);
         // Child width can depend on parent width, so we need to calculate this box's width before
         // laying out its children.
         self.calculate_block_width(containing_block);
@@ -201,20 +516,112 @@ impl<'a> LayoutBox<'a> {
         // Determine where the box is located within its container.
         self.calculate_block_position(containing_block);
 
-        // Recursively lay out the children of this box.
-        self.layout_block_children(float_list, previous_inline);
+        // Recursively lay out the children of this box.  A new block formatting context keeps
+        // its floats to itself.
+        let new_formatting_context = self.establishes_formatting_context();
+        let mut own_floats = Vec::new();
+        let floats = if new_formatting_context { &mut own_floats } else { float_list };
+        self.layout_block_children(floats, previous_inline);
+        self.align_lines(floats.as_slice());
 
         // Parent height can depend on child height, so `calculate_height` must be called after the
         // children are laid out.
         self.calculate_block_height();
+        if new_formatting_context {
+            self.contain_floats();
+        }
+
+        self.apply_first_line_style();
+
+        // A relatively positioned box is moved after it has been laid out, so that its siblings
+        // stay where they would have been.
+        self.apply_relative_offsets(containing_block);
+
+        self.layout_positioned_descendants();
+    }
+
+    /// Does this block establish a new block formatting context for its contents?
+    ///
+    /// `overflow: hidden`, `scroll`, and `auto` do.  `overflow: clip` doesn't make a scroll
+    /// container, so its contents, including floats, still share the outer context.  Nothing is
+    /// actually clipped yet, whatever the value.
+    fn establishes_formatting_context(&self) -> bool {
+        match self.box_type {
+            BlockNode(style) => match style.overflow() {
+                Overflow::Visible | Overflow::Clip => false,
+                Overflow::Hidden | Overflow::Scroll | Overflow::Auto => true,
+            },
+            _ => false
+        }
+    }
+
+    /// Grow an auto-height block formatting context to enclose the floats inside it, which then
+    /// no longer affect any boxes outside it.
+    fn contain_floats(&mut self) {
+        let float_max_y = self.float_info.left_float_max_y.max(self.float_info.right_float_max_y);
+        let auto_height = self.definite_height().is_none();
+        if auto_height && float_max_y > self.dimensions.content.max_y() {
+            self.dimensions.content.height = float_max_y - self.dimensions.content.y;
+        }
+        self.float_info = Default::default();
+    }
+
+    /// Restyle the text on the first line of this block with the block's `::first-line` styles.
+    ///
+    /// The line is only known once the text has been laid out, so only properties that can't
+    /// change its layout (`color` and `text-decoration`) are applied.
+    fn apply_first_line_style(&mut self) {
+        let values = match self.box_type {
+            BlockNode(style) => match style.first_line {
+                Some(ref values) => values,
+                None => return
+            },
+            _ => return
+        };
+        if let Some(line_y) = self.first_line_y() {
+            self.restyle_line(values, line_y);
+        }
+    }
+
+    /// The y position of the first line of text in this box, if it contains any.
+    fn first_line_y(&self) -> Option<f32> {
+        for child in self.children.iter() {
+            match child.box_type {
+                TextNode(_) => return Some(child.dimensions.content.y),
+                InlineBlockNode(_) | FloatNode(_) | AbsoluteNode(_) => {},
+                _ => if let Some(y) = child.first_line_y() { return Some(y); }
+            }
+        }
+        None
+    }
+
+    /// Apply `values` to the font info of the in-flow text boxes at `line_y`.
+    fn restyle_line(&mut self, values: &PropertyMap, line_y: f32) {
+        for child in self.children.iter_mut() {
+            match child.box_type {
+                TextNode(_) => if child.dimensions.content.y == line_y {
+                    if let Some(&Value::ColorValue(color)) = values.get("color") {
+                        child.font_info.color = color;
+                    }
+                    if let Some(&Keyword(ref deco)) = values.get("text-decoration") {
+                        if let Some(deco) = TextDecoration::from_keyword(deco.as_slice()) {
+                            child.font_info.deco = deco;
+                        }
+                    }
+                },
+                InlineBlockNode(_) | FloatNode(_) | AbsoluteNode(_) => {},
+                _ => child.restyle_line(values, line_y)
+            }
+        }
     }
 
     fn layout_float(&mut self, containing_block: Dimensions,
+                    font_info: FontInfo,
                     float_rect: &mut Rect,
                     previous_float: Option<Dimensions>,
                     float_list: &mut Vec<(Float, Dimensions)>,
                     previous_inline: &mut Option<(i32, i32)>) {
-        self.fill_font_info();
+        self.fill_font_info(font_info);
 
         self.calculate_float_width(containing_block);
 
@@ -235,10 +642,16 @@ impl<'a> LayoutBox<'a> {
         self.calculate_float_height();
 
         float_list.push((self.get_style_node().float_value().unwrap(), self.dimensions));
+
+        // Content beside the float flows around where it was placed, so the offset comes after
+        // it is added to `float_list`.
+        self.apply_relative_offsets(containing_block);
+
+        self.layout_positioned_descendants();
     }
 
-    fn layout_inline(&mut self, containing_block: Dimensions, float_list: &mut Vec<(Float, Dimensions)>, previous_inline: &mut Option<(i32, i32)>) {
-        self.fill_font_info();
+    fn layout_inline(&mut self, containing_block: Dimensions, font_info: FontInfo, float_list: &mut Vec<(Float, Dimensions)>, previous_inline: &mut Option<(i32, i32)>) {
+        self.fill_font_info(font_info);
         // Child width can depend on parent width, so we need to calculate this box's width before
         // laying out its children.
         self.calculate_inline_width(containing_block, previous_inline);
@@ -249,9 +662,291 @@ impl<'a> LayoutBox<'a> {
         // Recursively lay out the children of this box.
         self.layout_block_children(float_list, previous_inline);
 
+        // The box's content may have wrapped onto several lines.
+        let mut fragments = Vec::new();
+        self.collect_line_rects(&mut fragments);
+        self.fragments = fragments;
+
         // Parent height can depend on child height, so `calculate_height` must be called after the
         // children are laid out.
         self.calculate_block_height();
+
+        self.layout_positioned_descendants();
+    }
+
+    /// Add the area covered by this box's in-flow text to `lines`, as one rectangle per line.
+    /// Text inside child blocks is on the blocks' own lines, so it is left out.
+    fn collect_line_rects(&self, lines: &mut Vec<Rect>) {
+        for child in self.children.iter() {
+            match child.box_type {
+                // An inline-block sits on the line as a whole.
+                TextNode(_) | InlineBlockNode(_) => {
+                    let rect = match child.box_type {
+                        TextNode(_) => child.dimensions.content,
+                        _ => child.dimensions.margin_box()
+                    };
+                    let same_line = match lines.last() {
+                        Some(last) => last.y == rect.y,
+                        None => false
+                    };
+                    if same_line {
+                        let last = lines.pop().unwrap();
+                        lines.push(last.union(&rect));
+                    } else {
+                        lines.push(rect);
+                    }
+                },
+                BlockNode(_) | FloatNode(_) | AbsoluteNode(_) => {},
+                _ => child.collect_line_rects(lines)
+            }
+        }
+    }
+
+    /// Move each line of this block's text sideways according to its `text-align` property.
+    /// Lines are left-aligned as they are laid out, so only `right` and `center` move them.
+    ///
+    /// Like the width available to each line as it is laid out, the line box is narrowed by the
+    /// floats in `float_list` beside it.
+    fn align_lines(&mut self, float_list: &[(Float, Dimensions)]) {
+        let align = match self.get_style_node().value("text-align") {
+            Some(Keyword(ref align)) if align.as_slice() == "right" => 1.0,
+            Some(Keyword(ref align)) if align.as_slice() == "center" => 0.5,
+            _ => return
+        };
+
+        let mut lines = Vec::new();
+        self.collect_line_rects(&mut lines);
+        for line in lines.iter() {
+            let (_, right_edge) = line_box_edges(self.dimensions.content, line.y, line.height, float_list);
+            let slack = right_edge - line.max_x();
+            if slack > 0.0 {
+                self.shift_line(line.y, slack * align);
+            }
+        }
+    }
+
+    /// Move the in-flow text boxes at `line_y`, and the fragments of inline boxes around them,
+    /// right by `dx`.
+    fn shift_line(&mut self, line_y: f32, dx: f32) {
+        for fragment in self.fragments.iter_mut() {
+            if fragment.y == line_y {
+                fragment.x += dx;
+            }
+        }
+        for child in self.children.iter_mut() {
+            match child.box_type {
+                TextNode(_) => if child.dimensions.content.y == line_y {
+                    child.dimensions.content.x += dx;
+                },
+                InlineBlockNode(_) => if child.dimensions.margin_box().y == line_y {
+                    child.translate(dx, 0.0);
+                },
+                BlockNode(_) | FloatNode(_) | AbsoluteNode(_) => {},
+                _ => child.shift_line(line_y, dx)
+            }
+        }
+    }
+
+    /// Lay out an inline-block element.  It is sized like a block, but placed after the inline
+    /// content before it on the same line, or on a new line below if it doesn't fit there.
+    ///
+    /// Its contents are laid out as a block of their own, with their own floats and lines.  The
+    /// position after it is left in `previous_inline`.
+    fn layout_inline_block(&mut self, containing_block: Dimensions, font_info: FontInfo, previous_inline: &mut Option<(i32, i32)>) {
+        self.fill_font_info(font_info);
+
+        // With `width: auto`, an inline-block shrinks to fit its contents.
+        self.calculate_float_width(containing_block);
+        let auto_width = match self.get_style_node().value("width") {
+            None => true,
+            Some(Keyword(ref keyword)) => keyword.as_slice() == "auto",
+            _ => false
+        };
+        if auto_width {
+            if let Length(width, _) = self.resolve_intrinsic_width(Keyword("fit-content".to_string()), containing_block) {
+                self.dimensions.content.width = width;
+            }
+        }
+
+        // This puts the box at the start of a new line.  Move it up next to the previous inline
+        // content if there is room.
+        self.calculate_block_position(containing_block);
+        if let Some((inline_x, inline_y)) = *previous_inline {
+            let d = &mut self.dimensions;
+            let margin_box = d.margin_box();
+            if inline_x as f32 + margin_box.width <= containing_block.content.max_x() {
+                d.content.x += inline_x as f32 - margin_box.x;
+                d.content.y += inline_y as f32 - margin_box.y;
+            }
+        }
+
+        let mut inner_float_list: Vec<(Float, Dimensions)> = Vec::new();
+        let mut inner_inline: Option<(i32, i32)> = None;
+        self.layout_block_children(&mut inner_float_list, &mut inner_inline);
+        self.align_lines(inner_float_list.as_slice());
+
+        self.calculate_block_height();
+
+        // The inline content after this box follows where it was laid out, not where a relative
+        // offset moves it.
+        let margin_box = self.dimensions.margin_box();
+        *previous_inline = Some((margin_box.max_x() as i32, margin_box.y as i32));
+        self.apply_relative_offsets(containing_block);
+
+        self.layout_positioned_descendants();
+    }
+
+    /// Lay out an absolutely positioned element within the padding box of its containing block.
+    ///
+    /// The box is out of flow, so it starts its own list of floats and inline positions.
+    fn layout_absolute(&mut self, containing_block: Rect, font_info: FontInfo) {
+        self.fill_font_info(font_info);
+        // The containing block has already been laid out, so its height is always known.
+        self.containing_height = Some(containing_block.height);
+
+        let mut block: Dimensions = Default::default();
+        block.content = containing_block;
+        self.calculate_float_width(block);
+
+        block.content.height = 0.0;
+        self.calculate_block_position(block);
+        self.apply_absolute_offsets(containing_block);
+
+        let mut float_list: Vec<(Float, Dimensions)> = Vec::new();
+        let mut previous_inline: Option<(i32, i32)> = None;
+        self.layout_block_children(&mut float_list, &mut previous_inline);
+
+        self.calculate_block_height();
+        self.apply_absolute_bottom(containing_block);
+
+        self.layout_positioned_descendants();
+    }
+
+    /// The used value of an absolutely positioned box's `top`, `right`, `bottom`, or `left`
+    /// property, or `None` if it is `auto`.  Percentages are of the containing block's width for
+    /// `left` and `right`, and of its height for `top` and `bottom`.
+    fn absolute_offset(&self, name: &str, containing_block: Rect) -> Option<f32> {
+        let base = match name {
+            "top" | "bottom" => containing_block.height,
+            _ => containing_block.width
+        };
+        match self.get_style_node().value(name) {
+            Some(value @ Length(_, _)) | Some(value @ Value::Function(_, _)) => {
+                Some(value.length_to_px(base, self.font_info.size as f32))
+            }
+            _ => None
+        }
+    }
+
+    /// Place an absolutely positioned box horizontally by its `left` or `right` offset, and
+    /// vertically by its `top` offset.  `left` wins over `right`.
+    ///
+    /// When both `left` and `right` are given and `width` is `auto`, the width is whatever is
+    /// left of the containing block between the two offsets.
+    fn apply_absolute_offsets(&mut self, containing_block: Rect) {
+        let left = self.absolute_offset("left", containing_block);
+        let right = self.absolute_offset("right", containing_block);
+        let top = self.absolute_offset("top", containing_block);
+        let auto_width = match self.get_style_node().value("width") {
+            None => true,
+            Some(Keyword(ref keyword)) => keyword.as_slice() == "auto",
+            _ => false
+        };
+        let d = &mut self.dimensions;
+
+        if let (Some(left), Some(right), true) = (left, right, auto_width) {
+            let edges = d.margin_box().width - d.content.width;
+            d.content.width = (containing_block.width - left - right - edges).max(0.0);
+        }
+        match (left, right) {
+            (Some(left), _) => d.content.x += left,
+            (None, Some(right)) => d.content.x += containing_block.width - right - d.margin_box().width,
+            (None, None) => {}
+        }
+        if let Some(top) = top {
+            d.content.y += top;
+        }
+    }
+
+    /// Without a `top` offset, move an absolutely positioned box so that its bottom edge is at its
+    /// `bottom` offset.  This needs the box's height, so it happens after its children are laid
+    /// out.
+    fn apply_absolute_bottom(&mut self, containing_block: Rect) {
+        if self.absolute_offset("top", containing_block).is_some() {
+            return;
+        }
+        if let Some(bottom) = self.absolute_offset("bottom", containing_block) {
+            let dy = containing_block.height - bottom - self.dimensions.margin_box().height;
+            self.translate(0.0, dy);
+        }
+    }
+
+    /// Move a relatively positioned box and its contents by its `top`/`bottom` and `left`/`right`
+    /// offsets.  `top` wins over `bottom` and `left` over `right`.
+    ///
+    /// Horizontal percentages are of the containing block's width, and vertical ones of its
+    /// height.  Vertical percentages are treated as `auto` unless that height is definite.
+    fn apply_relative_offsets(&mut self, containing_block: Dimensions) {
+        if self.get_style_node().position() != Position::Relative {
+            return;
+        }
+        let width = Some(containing_block.content.width);
+        let height = self.containing_height;
+
+        let dx = match (self.size_limit("left", width), self.size_limit("right", width)) {
+            (Some(left), _) => left,
+            (None, Some(right)) => -right,
+            (None, None) => 0.0
+        };
+        let dy = match (self.size_limit("top", height), self.size_limit("bottom", height)) {
+            (Some(top), _) => top,
+            (None, Some(bottom)) => -bottom,
+            (None, None) => 0.0
+        };
+        self.translate(dx, dy);
+    }
+
+    /// Move this box and all its descendants by `dx` and `dy`.
+    fn translate(&mut self, dx: f32, dy: f32) {
+        self.dimensions.content.x += dx;
+        self.dimensions.content.y += dy;
+        for fragment in self.fragments.iter_mut() {
+            fragment.x += dx;
+            fragment.y += dy;
+        }
+        for child in self.children.iter_mut() {
+            child.translate(dx, dy);
+        }
+    }
+
+    /// A positioned box is the containing block for its absolutely positioned descendants, which
+    /// can be laid out once its own size is known.
+    fn layout_positioned_descendants(&mut self) {
+        if self.is_positioned() {
+            let padding_box = self.dimensions.padding_box();
+            self.layout_absolute_descendants(padding_box);
+        }
+    }
+
+    /// Lay out the absolutely positioned descendants whose containing block is `containing_block`.
+    ///
+    /// Descendants of another positioned box are skipped, since that box is their containing block.
+    fn layout_absolute_descendants(&mut self, containing_block: Rect) {
+        for child in self.children.iter_mut() {
+            match child.box_type {
+                AbsoluteNode(_) => child.layout_absolute(containing_block, self.font_info.clone()),
+                _ if child.is_positioned() => {}
+                _ => child.layout_absolute_descendants(containing_block),
+            }
+        }
+    }
+
+    fn is_positioned(&self) -> bool {
+        match self.box_type {
+            AbsoluteNode(_) => true,
+            BlockNode(style) | InlineNode(style) | InlineBlockNode(style) | FloatNode(style) => style.position() != Position::Static,
+            TextNode(_) | AnonymousBlock => false,
+        }
     }
 
     fn layout_text(&mut self, containing_block: Dimensions, font_info: FontInfo, previous_inline: &mut Option<(i32, i32)>) {
@@ -266,39 +961,29 @@ impl<'a> LayoutBox<'a> {
 
         let d = &mut self.dimensions;
 
-        unsafe {
-            let handle = FontContextHandle::new();
-            let mut face: FT_Face = ptr::null_mut();
-            let mut error: FT_Error;
-            let filename = "./examples/verdana.ttf".as_ptr() as *mut i8;
-            error = FT_New_Face(handle.ctx.ctx, filename, 0, &mut face);
-
-            if error != 0 || face.is_null() {
-                println!("failed to new face");
-                return;
-            }
+        let face = match cached_face(&font_info) {
+            Some(face) => face,
+            None => return
+        };
 
-            error = FT_Set_Pixel_Sizes(face, 0, font_info.size as u32);
-            if error != 0 {
-                println!("failed to set pixel size: {}", font_info.size);
-                return;
-            }
+        // Without a line height, use the font's natural one, and keep it for painting too.
+        if self.font_info.line_height <= 0 {
+            self.font_info.line_height = normal_line_height(&face);
+        }
 
-            d.content.width = calculate_text_dimension(text.as_slice(), &face).width as f32;
-            d.content.height = font_info.line_height as f32;
+        d.content.width = calculate_text_dimension(text.as_slice(), &face).width as f32;
+        d.content.height = self.font_info.line_height as f32;
 
-            if let Some((inline_x, inline_y)) = *previous_inline {
-                d.content.x = inline_x as f32;
-                d.content.y = inline_y as f32;
-                if d.content.max_x() > containing_block.content.max_x() {
-                    d.content.x = containing_block.content.x;
-                    d.content.y += d.content.height;
-                }
-            } else {
+        if let Some((inline_x, inline_y)) = *previous_inline {
+            d.content.x = inline_x as f32;
+            d.content.y = inline_y as f32;
+            if self.starts_line || d.content.max_x() > containing_block.content.max_x() {
                 d.content.x = containing_block.content.x;
-                d.content.y = containing_block.content.y;
+                d.content.y += d.content.height;
             }
-
+        } else {
+            d.content.x = containing_block.content.x;
+            d.content.y = containing_block.content.y;
         }
     }
 
@@ -324,7 +1009,36 @@ impl<'a> LayoutBox<'a> {
 
         // `width` has initial value `auto`.
         let auto = Keyword("auto".to_string());
-        let mut width = style.value("width").unwrap_or(auto.clone());
+        let width = self.resolve_intrinsic_width(style.value("width").unwrap_or(auto), containing_block);
+        self.calculate_used_block_width(containing_block, width, "width");
+
+        // If the width is out of range, the rules above are applied again with `max-width` or
+        // `min-width` as the width.
+        // http://www.w3.org/TR/CSS2/visudet.html#min-max-widths
+        let containing_width = containing_block.content.width;
+        let edges = {
+            let d = &self.dimensions;
+            d.padding.left + d.padding.right + d.border.left + d.border.right
+        };
+        if let Some(max_width) = self.size_limit("max-width", Some(containing_width)) {
+            if self.dimensions.content.width > content_size(style, "max-width", max_width, edges) {
+                self.calculate_used_block_width(containing_block, Length(max_width, Px), "max-width");
+            }
+        }
+        if let Some(min_width) = self.size_limit("min-width", Some(containing_width)) {
+            if self.dimensions.content.width < content_size(style, "min-width", min_width, edges) {
+                self.calculate_used_block_width(containing_block, Length(min_width, Px), "min-width");
+            }
+        }
+    }
+
+    /// Calculate the block's horizontal dimensions for a given `width`, which is the value of
+    /// the property `name`.
+    fn calculate_used_block_width(&mut self, containing_block: Dimensions, width: Value, name: &str) {
+        let font_size = self.font_info.size as f32;
+        let style = self.get_style_node();
+        let auto = Keyword("auto".to_string());
+        let mut width = width;
 
         // margin, border, and padding have initial value 0.
         let zero = Length(0.0, Px);
@@ -338,8 +1052,14 @@ impl<'a> LayoutBox<'a> {
         let padding_left = style.lookup("padding-left", "padding", &zero);
         let padding_right = style.lookup("padding-right", "padding", &zero);
 
+        if width != auto {
+            let edges: f32 = [&border_left, &border_right, &padding_left, &padding_right].iter()
+                .map(|v| v.length_to_px(containing_block.content.width, font_size)).sum();
+            width = Length(content_size(style, name, width.length_to_px(containing_block.content.width, font_size), edges), Px);
+        }
+
         let total = [&margin_left, &margin_right, &border_left, &border_right,
-                     &padding_left, &padding_right, &width].iter().map(|v| v.to_px().unwrap_or(v.percent_to_px(containing_block.content.width))).sum();
+                     &padding_left, &padding_right, &width].iter().map(|v| v.length_to_px(containing_block.content.width, font_size)).sum();
 
         // If width is not auto and the total is wider than the container, treat auto margins as 0.
         if width != auto && total > containing_block.content.width {
@@ -359,7 +1079,7 @@ impl<'a> LayoutBox<'a> {
         match (width == auto, margin_left == auto, margin_right == auto) {
             // If the values are overconstrained, calculate margin_right.
             (false, false, false) => {
-                margin_right = Length(margin_right.to_px().unwrap() + underflow, Px);
+                margin_right = Length(margin_right.length_to_px(containing_block.content.width, font_size) + underflow, Px);
             }
 
             // If exactly one size is auto, its used value follows from the equality.
@@ -377,7 +1097,7 @@ impl<'a> LayoutBox<'a> {
                 } else {
                     // Width can't be negative. Adjust the right margin instead.
                     width = Length(0.0, Px);
-                    margin_right = Length(margin_right.to_px().unwrap() + underflow, Px);
+                    margin_right = Length(margin_right.length_to_px(containing_block.content.width, font_size) + underflow, Px);
                 }
             }
 
@@ -389,19 +1109,20 @@ impl<'a> LayoutBox<'a> {
         }
 
         let d = &mut self.dimensions;
-        d.content.width = width.to_px().unwrap_or(width.percent_to_px(containing_block.content.width));
+        d.content.width = width.length_to_px(containing_block.content.width, font_size);
 
-        d.padding.left = padding_left.to_px().unwrap();
-        d.padding.right = padding_right.to_px().unwrap();
+        d.padding.left = padding_left.length_to_px(containing_block.content.width, font_size);
+        d.padding.right = padding_right.length_to_px(containing_block.content.width, font_size);
 
-        d.border.left = border_left.to_px().unwrap();
-        d.border.right = border_right.to_px().unwrap();
+        d.border.left = border_left.length_to_px(containing_block.content.width, font_size);
+        d.border.right = border_right.length_to_px(containing_block.content.width, font_size);
 
-        d.margin.left = margin_left.to_px().unwrap();
-        d.margin.right = margin_right.to_px().unwrap();
+        d.margin.left = margin_left.length_to_px(containing_block.content.width, font_size);
+        d.margin.right = margin_right.length_to_px(containing_block.content.width, font_size);
     }
 
     fn calculate_float_width(&mut self, containing_block: Dimensions) {
+        let font_size = self.font_info.size as f32;
         let style = self.get_style_node();
 
         // `width` has initial value `auto`.
@@ -410,25 +1131,136 @@ impl<'a> LayoutBox<'a> {
         // margin, border, and padding have initial value 0.
         let zero = Length(0.0, Px);
 
+        let mut width = self.resolve_intrinsic_width(style.value("width").unwrap_or(auto.clone()), containing_block);
         let d = &mut self.dimensions;
-        let mut width = style.value("width").unwrap_or(auto.clone());
 
-        d.padding.left = style.lookup("padding-left", "padding", &zero).to_px().unwrap();
-        d.padding.right = style.lookup("padding-right", "padding", &zero).to_px().unwrap();
+        d.padding.left = style.lookup("padding-left", "padding", &zero).length_to_px(containing_block.content.width, font_size);
+        d.padding.right = style.lookup("padding-right", "padding", &zero).length_to_px(containing_block.content.width, font_size);
 
-        d.border.left = style.lookup("border-left-width", "border-width", &zero).to_px().unwrap();
-        d.border.right = style.lookup("border-right-width", "border-width", &zero).to_px().unwrap();
+        d.border.left = style.lookup("border-left-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
+        d.border.right = style.lookup("border-right-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
 
-        d.margin.left = style.lookup("margin-left", "margin", &zero).to_px().unwrap();
-        d.margin.right = style.lookup("margin-right", "margin", &zero).to_px().unwrap();
+        d.margin.left = style.lookup("margin-left", "margin", &zero).length_to_px(containing_block.content.width, font_size);
+        d.margin.right = style.lookup("margin-right", "margin", &zero).length_to_px(containing_block.content.width, font_size);
 
         if width == auto {
             width = Length(containing_block.content.width - d.padding.left - d.padding.right - d.border.left - d.border.right - d.margin.left - d.margin.right, Px);
         }
-        d.content.width = width.to_px().unwrap_or(width.percent_to_px(containing_block.content.width));
+        let edges = d.padding.left + d.padding.right + d.border.left + d.border.right;
+        d.content.width = content_size(style, "width", width.length_to_px(containing_block.content.width, font_size), edges);
+    }
+
+    /// Turn a width of `min-content`, `max-content`, `fit-content`, or `fit-content(<length>)`
+    /// into a length in px, by measuring this box's content.  Other widths are returned as they
+    /// are.
+    ///
+    /// `fit-content` shrinks to fit the content, but no wider than the space available in the
+    /// containing block (or than the given length) unless the content can't be any narrower.
+    fn resolve_intrinsic_width(&self, width: Value, containing_block: Dimensions) -> Value {
+        let limit = match width {
+            Keyword(ref keyword) => match keyword.as_slice() {
+                "min-content" | "max-content" | "fit-content" => None,
+                _ => return width.clone()
+            },
+            Value::Function(ref name, ref args) if name.as_slice() == "fit-content" && args.len() == 1 => {
+                Some(args[0].length_to_px(containing_block.content.width, self.font_info.size as f32))
+            },
+            _ => return width.clone()
+        };
+
+        let (min_content, max_content) = self.intrinsic_widths(&self.font_info);
+        let px = match width {
+            Keyword(ref keyword) if keyword.as_slice() == "min-content" => min_content,
+            Keyword(ref keyword) if keyword.as_slice() == "max-content" => max_content,
+            _ => {
+                let available = limit.unwrap_or_else(|| {
+                    containing_block.content.width - self.horizontal_edges(containing_block.content.width)
+                });
+                max_content.min(min_content.max(available))
+            }
+        };
+        Length(px, Px)
+    }
+
+    /// The total width of the horizontal margins, borders, and padding from this box's style.
+    fn horizontal_edges(&self, containing_width: f32) -> f32 {
+        let font_size = self.font_info.size as f32;
+        let style = self.get_style_node();
+        let zero = Length(0.0, Px);
+        [("margin-left", "margin"), ("margin-right", "margin"),
+         ("border-left-width", "border-width"), ("border-right-width", "border-width"),
+         ("padding-left", "padding"), ("padding-right", "padding")].iter()
+            .map(|&(name, fallback)| style.lookup(name, fallback, &zero).length_to_px(containing_width, font_size))
+            .sum()
+    }
+
+    /// The min-content and max-content widths of this box's contents: the narrowest they can be
+    /// without overflowing, which is usually the longest word, and their width without any line
+    /// breaks.  This doesn't include the box's own margins, borders, or padding.
+    ///
+    /// Must be called before the contents are laid out.
+    fn intrinsic_widths(&self, font_info: &FontInfo) -> (f32, f32) {
+        let mut min_content = 0f32;
+        let mut max_content = 0f32;
+        // The width of the current run of inline content, which is all on one line.
+        let mut line = 0f32;
+
+        for child in self.children.iter() {
+            match child.box_type {
+                BlockNode(_) | FloatNode(_) => {
+                    let (child_min, child_max) = child.outer_intrinsic_widths(font_info);
+                    min_content = min_content.max(child_min);
+                    max_content = max_content.max(child_max).max(line);
+                    line = 0.0;
+                },
+                InlineNode(style) => {
+                    let (child_min, child_max) = match style.get_string_if_text_node() {
+                        Some(text) => text_intrinsic_widths(text, font_info, style.overflow_wrap(), style.white_space()),
+                        None => child.outer_intrinsic_widths(font_info),
+                    };
+                    min_content = min_content.max(child_min);
+                    line += child_max;
+                },
+                TextNode(ref text) => {
+                    let (child_min, child_max) = text_intrinsic_widths(text.as_slice(), font_info, OverflowWrap::Normal, WhiteSpace::Normal);
+                    min_content = min_content.max(child_min);
+                    line += child_max;
+                },
+                InlineBlockNode(_) => {
+                    let (child_min, child_max) = child.outer_intrinsic_widths(font_info);
+                    min_content = min_content.max(child_min);
+                    line += child_max;
+                },
+                AnonymousBlock => {
+                    let (child_min, child_max) = child.intrinsic_widths(font_info);
+                    min_content = min_content.max(child_min);
+                    max_content = max_content.max(child_max).max(line);
+                    line = 0.0;
+                },
+                AbsoluteNode(_) => {},
+            }
+        }
+        (min_content, max_content.max(line))
+    }
+
+    /// The intrinsic widths of this box including its margins, borders, and padding.  A box with
+    /// a fixed width in px is always that wide.
+    fn outer_intrinsic_widths(&self, parent_font_info: &FontInfo) -> (f32, f32) {
+        let style = self.get_style_node();
+        let font_info = self.specified_font(parent_font_info);
+        // Percentages can't be resolved yet, so they count as zero.
+        let edges = self.horizontal_edges(0.0);
+        match style.value("width") {
+            Some(Length(width, Px)) => (width + edges, width + edges),
+            _ => {
+                let (min_content, max_content) = self.intrinsic_widths(&font_info);
+                (min_content + edges, max_content + edges)
+            }
+        }
     }
 
     fn calculate_inline_width(&mut self, containing_block: Dimensions, previous_inline: &mut Option<(i32, i32)>) {
+        let font_size = self.font_info.size as f32;
         let style = self.get_style_node();
 
         // `width` has initial value `auto`.
@@ -441,14 +1273,14 @@ impl<'a> LayoutBox<'a> {
             let d = &mut self.dimensions;
             let mut width = style.value("width").unwrap_or(auto.clone());
 
-            d.padding.left = style.lookup("padding-left", "padding", &zero).to_px().unwrap();
-            d.padding.right = style.lookup("padding-right", "padding", &zero).to_px().unwrap();
+            d.padding.left = style.lookup("padding-left", "padding", &zero).length_to_px(containing_block.content.width, font_size);
+            d.padding.right = style.lookup("padding-right", "padding", &zero).length_to_px(containing_block.content.width, font_size);
 
-            d.border.left = style.lookup("border-left-width", "border-width", &zero).to_px().unwrap();
-            d.border.right = style.lookup("border-right-width", "border-width", &zero).to_px().unwrap();
+            d.border.left = style.lookup("border-left-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
+            d.border.right = style.lookup("border-right-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
 
-            d.margin.left = style.lookup("margin-left", "margin", &zero).to_px().unwrap();
-            d.margin.right = style.lookup("margin-right", "margin", &zero).to_px().unwrap();
+            d.margin.left = style.lookup("margin-left", "margin", &zero).length_to_px(containing_block.content.width, font_size);
+            d.margin.right = style.lookup("margin-right", "margin", &zero).length_to_px(containing_block.content.width, font_size);
 
             if width == auto {
                 let mut width_px = containing_block.content.width - d.padding.left - d.padding.right - d.border.left - d.border.right - d.margin.left - d.margin.right;
@@ -457,7 +1289,8 @@ impl<'a> LayoutBox<'a> {
                 }
                 width = Length(width_px, Px);
             }
-            d.content.width = width.to_px().unwrap_or(width.percent_to_px(containing_block.content.width));
+            let edges = d.padding.left + d.padding.right + d.border.left + d.border.right;
+            d.content.width = content_size(style, "width", width.length_to_px(containing_block.content.width, font_size), edges);
         }
     }
 
@@ -467,6 +1300,10 @@ impl<'a> LayoutBox<'a> {
     ///
     /// Sets the vertical margin/padding/border dimensions, and the `x`, `y` values.
     fn calculate_block_position(&mut self, containing_block: Dimensions) {
+        let font_size = self.font_info.size as f32;
+        // The top margin of the first child may collapse into this box's own top margin.
+        let margin_top = self.collapsed_margin_top(containing_block.content.width, font_size);
+
         let style = self.get_style_node();
         let d = &mut self.dimensions;
 
@@ -474,14 +1311,14 @@ impl<'a> LayoutBox<'a> {
         let zero = Length(0.0, Px);
 
         // If margin-top or margin-bottom is `auto`, the used value is zero.
-        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px().unwrap();
-        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px().unwrap();
+        d.margin.top = margin_top;
+        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).length_to_px(containing_block.content.width, font_size);
 
-        d.border.top = style.lookup("border-top-width", "border-width", &zero).to_px().unwrap();
-        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).to_px().unwrap();
+        d.border.top = style.lookup("border-top-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
+        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
 
-        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px().unwrap();
-        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px().unwrap();
+        d.padding.top = style.lookup("padding-top", "padding", &zero).length_to_px(containing_block.content.width, font_size);
+        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).length_to_px(containing_block.content.width, font_size);
 
         // Position the box below all the previous boxes in the container.
         d.content.x = containing_block.content.x +
@@ -491,6 +1328,7 @@ impl<'a> LayoutBox<'a> {
     }
 
     fn calculate_float_position(&mut self, containing_block: Dimensions, float_rect : &Rect) {
+        let font_size = self.font_info.size as f32;
         let style = self.get_style_node();
         let d = &mut self.dimensions;
 
@@ -498,14 +1336,14 @@ impl<'a> LayoutBox<'a> {
         let zero = Length(0.0, Px);
 
         // If margin-top or margin-bottom is `auto`, the used value is zero.
-        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px().unwrap();
-        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px().unwrap();
+        d.margin.top = style.lookup("margin-top", "margin", &zero).length_to_px(containing_block.content.width, font_size);
+        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).length_to_px(containing_block.content.width, font_size);
 
-        d.border.top = style.lookup("border-top-width", "border-width", &zero).to_px().unwrap();
-        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).to_px().unwrap();
+        d.border.top = style.lookup("border-top-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
+        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
 
-        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px().unwrap();
-        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px().unwrap();
+        d.padding.top = style.lookup("padding-top", "padding", &zero).length_to_px(containing_block.content.width, font_size);
+        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).length_to_px(containing_block.content.width, font_size);
 
         let float_direction = style.float_value();
         assert!(float_direction != None);
@@ -527,6 +1365,7 @@ impl<'a> LayoutBox<'a> {
     }
 
     fn calculate_inline_position(&mut self, containing_block: Dimensions, previous_inline: &mut Option<(i32, i32)>) {
+        let font_size = self.font_info.size as f32;
         let style = self.get_style_node();
         let d = &mut self.dimensions;
 
@@ -534,14 +1373,14 @@ impl<'a> LayoutBox<'a> {
         let zero = Length(0.0, Px);
 
         // If margin-top or margin-bottom is `auto`, the used value is zero.
-        d.margin.top = style.lookup("margin-top", "margin", &zero).to_px().unwrap();
-        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).to_px().unwrap();
+        d.margin.top = style.lookup("margin-top", "margin", &zero).length_to_px(containing_block.content.width, font_size);
+        d.margin.bottom = style.lookup("margin-bottom", "margin", &zero).length_to_px(containing_block.content.width, font_size);
 
-        d.border.top = style.lookup("border-top-width", "border-width", &zero).to_px().unwrap();
-        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).to_px().unwrap();
+        d.border.top = style.lookup("border-top-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
+        d.border.bottom = style.lookup("border-bottom-width", "border-width", &zero).length_to_px(containing_block.content.width, font_size);
 
-        d.padding.top = style.lookup("padding-top", "padding", &zero).to_px().unwrap();
-        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).to_px().unwrap();
+        d.padding.top = style.lookup("padding-top", "padding", &zero).length_to_px(containing_block.content.width, font_size);
+        d.padding.bottom = style.lookup("padding-bottom", "padding", &zero).length_to_px(containing_block.content.width, font_size);
 
         // Position the box below all the previous boxes in the container.
         d.content.x = containing_block.content.x +
@@ -629,70 +1468,145 @@ impl<'a> LayoutBox<'a> {
         return shift_by;
     }
 
-    fn split_text(&mut self, containing_block: Dimensions, font_info: &FontInfo, text: &str, previous_inline: &mut Option<(i32, i32)>) {
-        let mut width_px = containing_block.content.width;
+    fn split_text(&mut self, containing_block: Dimensions, font_info: &FontInfo, text: &str, float_list: &[(Float, Dimensions)], previous_inline: &mut Option<(i32, i32)>) {
+        let line_width = containing_block.content.width;
+        let mut first_line_width = line_width;
+        if let Some((inline_x, _)) = *previous_inline {
+            first_line_width -= inline_x as f32 - containing_block.content.x;
+        }
 
-        if let Some((inline_x, inline_y)) = *previous_inline {
-            width_px -= inline_x as f32 - containing_block.content.x;
+        let face = match cached_face(font_info) {
+            Some(face) => face,
+            None => return
+        };
+
+        // Each line, and whether it starts after a preserved newline.  Text that can't wrap at
+        // spaces is only broken at preserved newlines, and may overflow.
+        let mut lines: Vec<(String, bool)> = Vec::new();
+        let white_space = self.get_style_node().white_space();
+        match white_space {
+            WhiteSpace::Normal => {
+                let text = collapse_whitespace(text);
+                for line in self.wrap_text(text.as_slice(), &face, first_line_width, line_width).into_iter() {
+                    lines.push((line, false));
+                }
+            }
+            WhiteSpace::NoWrap => {
+                let line = collapse_whitespace(text).trim().to_string();
+                // With `text-overflow: ellipsis`, the line is cut short where it would overflow
+                // its containing block or run into a float.
+                if self.get_style_node().value("text-overflow") == Some(Keyword("ellipsis".to_string())) {
+                    let line_height = if font_info.line_height > 0 { font_info.line_height } else { normal_line_height(&face) };
+                    let (x, y) = match *previous_inline {
+                        Some((inline_x, inline_y)) => (inline_x as f32, inline_y as f32),
+                        None => (containing_block.content.x, containing_block.content.max_y()),
+                    };
+                    let width = available_width(containing_block.content, x, y, line_height as f32, float_list);
+                    lines.push((truncate_with_ellipsis(line.as_slice(), &face, width), false));
+                } else {
+                    lines.push((line, false));
+                }
+            }
+            WhiteSpace::Pre | WhiteSpace::PreLine => {
+                let text = text.replace("\r\n", "\n");
+                for (i, line) in text.trim_right_matches('\n').split('\n').enumerate() {
+                    let line = match white_space {
+                        WhiteSpace::Pre => line.to_string(),
+                        _ => collapse_whitespace(line).trim().to_string(),
+                    };
+                    if white_space == WhiteSpace::Pre || line.is_empty() {
+                        lines.push((line, i > 0));
+                        continue;
+                    }
+                    let width = if i == 0 { first_line_width } else { line_width };
+                    for (j, wrapped) in self.wrap_text(line.as_slice(), &face, width, line_width).into_iter().enumerate() {
+                        lines.push((wrapped, i > 0 && j == 0));
+                    }
+                }
+            }
         }
 
+        // Each line is stored in the order it will be displayed in.
+        let direction = match self.get_style_node().value("direction") {
+            Some(Keyword(ref direction)) if direction.as_slice() == "rtl" => Direction::Rtl,
+            _ => Direction::Ltr
+        };
+        for (line, starts_line) in lines.into_iter() {
+            // Ligatures are put in once here, so that the text is measured and painted with them.
+            let line = match font_info.ligatures {
+                Ligatures::Common => apply_ligatures(line.as_slice(), &face),
+                Ligatures::None => line,
+            };
+            let mut text_box = LayoutBox::new(TextNode(bidi::reorder(line.as_slice(), direction)));
+            text_box.starts_line = starts_line;
+            self.children.push(text_box);
+        }
+    }
+
+    /// Break `text` into lines at spaces.  The first line is `first_line_width` wide, after any
+    /// inline content before it, and the rest are `line_width` wide.
+    fn wrap_text(&self, text: &str, face: &FT_Face, first_line_width: f32, line_width: f32) -> Vec<String> {
+        let mut width_px = first_line_width;
         let mut result: Vec<String> = Vec::new();
         let words: Vec<&str> = text.trim().split(' ').collect();
 
-        unsafe {
-            let handle = FontContextHandle::new();
-            let mut face: FT_Face = ptr::null_mut();
-            let mut error: FT_Error;
-            let filename = "./examples/verdana.ttf".as_ptr() as *mut i8;
-            error = FT_New_Face(handle.ctx.ctx, filename, 0, &mut face);
-
-            if error != 0 || face.is_null() {
-                println!("failed to new face");
-                return;
-            }
-
-            error = FT_Set_Pixel_Sizes(face, 0, font_info.size as u32);
-            if error != 0 {
-                println!("failed to set pixel size: {}", font_info.size);
-                return;
-            }
-
-            let space_width = calculate_text_dimension(" ", &face).width;
+        let space_width = calculate_text_dimension(" ", face).width;
+        // With `overflow-wrap: break-word` or `anywhere`, a word too long for a line of its own
+        // is broken between characters instead of overflowing.
+        let break_words = self.get_style_node().overflow_wrap() != OverflowWrap::Normal;
 
-            let mut text_width = 0;
-            let mut text_chunk = String::new();
+        let mut text_width = 0;
+        let mut text_chunk = String::new();
 
-            for word in words.iter() {
-                let word_dimension = calculate_text_dimension(*word, &face);
+        for word in words.iter() {
+            let word_dimension = calculate_text_dimension(*word, face);
 
-                if (text_width + word_dimension.width) >= width_px as i32 {
-                    result.push(text_chunk.to_string());
-                    text_chunk.clear();
-                    text_width = 0;
-                    width_px = containing_block.content.width;
-                }
-                text_width += (word_dimension.width + space_width);
-                text_chunk.push_str(*word);
-                text_chunk.push(' ');
+            if (text_width + word_dimension.width) >= width_px as i32 {
+                result.push(text_chunk.to_string());
+                text_chunk.clear();
+                text_width = 0;
+                width_px = line_width;
             }
-            if text_chunk.is_empty() == false {
-                if text.char_at_reverse(0) == text_chunk.char_at_reverse(1) {
-                    result.push(text_chunk.trim().to_string());
-                } else {
-                    result.push(text_chunk);
+            if break_words && word_dimension.width >= width_px as i32 {
+                let mut piece = String::new();
+                for c in word.chars() {
+                    piece.push(c);
+                    if piece.chars().count() > 1 && calculate_text_dimension(piece.as_slice(), face).width >= width_px as i32 {
+                        piece.pop();
+                        result.push(piece);
+                        piece = c.to_string();
+                    }
                 }
+                // The rest of the word starts the next line.
+                text_width = calculate_text_dimension(piece.as_slice(), face).width + space_width;
+                text_chunk.push_str(piece.as_slice());
+                text_chunk.push(' ');
+                continue;
             }
+            text_width += (word_dimension.width + space_width);
+            text_chunk.push_str(*word);
+            text_chunk.push(' ');
         }
-
-        for new_str in result.into_iter() {
-            self.children.push(LayoutBox::new(TextNode(new_str)));
+        if text_chunk.is_empty() == false {
+            if text.char_at_reverse(0) == text_chunk.char_at_reverse(1) {
+                result.push(text_chunk.trim().to_string());
+            } else {
+                result.push(text_chunk);
+            }
         }
+        result
     }
 
     /// Lay out the block's children within its content area.
     ///
     /// Sets `self.dimensions.height` to the total content height.
     fn layout_block_children(&mut self, float_list: &mut Vec<(Float, Dimensions)>, previous_inline: &mut Option<(i32, i32)>) {
+        let new_formatting_context = self.establishes_formatting_context();
+        // Anonymous blocks pass their containing block's height on to their children.
+        let containing_height = match self.box_type {
+            AnonymousBlock | TextNode(_) => self.containing_height,
+            _ => self.definite_height()
+        };
         let d = &mut self.dimensions;
 
         let mut left_float_rect: Rect = Default::default();
@@ -701,16 +1615,54 @@ impl<'a> LayoutBox<'a> {
         let mut previous_left_float: Option<Dimensions> = None;
         let mut previous_right_float: Option<Dimensions> = None;
 
+        // With nothing between this block's top edge and its first child, their top margins
+        // collapse: the child's margin has already been added to this box's margin instead.
+        let collapse_top = match self.box_type {
+            BlockNode(_) => !new_formatting_context && d.border.top == 0.0 && d.padding.top == 0.0,
+            _ => false
+        };
+        let mut first_in_flow = true;
+        // The bottom margin of the previous in-flow box, if it was a block, which collapses with
+        // the top margin of the next block.
+        let mut previous_margin_bottom: Option<f32> = None;
+
         let mut b_log = false;
         for child in self.children.iter_mut() {
+            child.containing_height = containing_height;
+
             // Check clear
-            d.content.height += child.calculate_clear_height(&self.float_info, d.content.max_y());
+            let clearance = child.calculate_clear_height(&self.float_info, d.content.max_y());
+            d.content.height += clearance;
+
+            match child.box_type {
+                FloatNode(_) | AbsoluteNode(_) => {},
+                BlockNode(_) if first_in_flow && collapse_top => {
+                    d.content.height -= child.collapsed_margin_top(d.content.width, child.specified_font_size(self.font_info.size as f32));
+                    first_in_flow = false;
+                },
+                BlockNode(_) => {
+                    // Move the block up by the part of the two margins that overlaps.  Clearance
+                    // keeps the margins apart.
+                    if let Some(margin_bottom) = previous_margin_bottom {
+                        if clearance == 0.0 {
+                            let margin_top = child.collapsed_margin_top(d.content.width, child.specified_font_size(self.font_info.size as f32));
+                            d.content.height -= margin_bottom + margin_top - collapse_margins(margin_bottom, margin_top);
+                        }
+                    }
+                    first_in_flow = false;
+                },
+                _ => {
+                    first_in_flow = false;
+                    previous_margin_bottom = None;
+                }
+            }
 
             match child.box_type {
                 BlockNode(style) => {
-                    child.layout_block(*d, float_list, previous_inline);
+                    child.layout_block(*d, self.font_info.clone(), float_list, previous_inline);
                     // Increment the height so each child is laid out below the previous one.
                     d.content.height = d.content.height + child.dimensions.margin_box().height;
+                    previous_margin_bottom = Some(child.dimensions.margin.bottom);
 
                     previous_left_float = None;
                     previous_right_float = None;
@@ -719,13 +1671,14 @@ impl<'a> LayoutBox<'a> {
                 FloatNode(style) => {
                     match style.float_value().unwrap() {
                         Float::FloatLeft => {
-                            child.layout_float(*d, &mut left_float_rect, previous_left_float, float_list, previous_inline);
-                            previous_left_float = Some(child.dimensions);
+                            child.layout_float(*d, self.font_info.clone(), &mut left_float_rect, previous_left_float, float_list, previous_inline);
+                            // Where the float was placed, before any relative offset.
+                            previous_left_float = float_list.last().map(|&(_, dimensions)| dimensions);
                             previous_right_float = None;
                         },
                         Float::FloatRight => {
-                            child.layout_float(*d, &mut right_float_rect, previous_right_float, float_list, previous_inline);
-                            previous_right_float = Some(child.dimensions);
+                            child.layout_float(*d, self.font_info.clone(), &mut right_float_rect, previous_right_float, float_list, previous_inline);
+                            previous_right_float = float_list.last().map(|&(_, dimensions)| dimensions);
                             previous_left_float = None;
                         },
                     };
@@ -733,12 +1686,12 @@ impl<'a> LayoutBox<'a> {
                 },
                 InlineNode(style) => {
                     if let Some(text) = style.get_string_if_text_node() {
-                        child.split_text(*d, &self.font_info, text.as_slice(), previous_inline);
+                        child.split_text(*d, &self.font_info, text.as_slice(), float_list.as_slice(), previous_inline);
                         child.box_type = AnonymousBlock;
 
-                        child.layout_anonymous(*d, self.font_info, float_list, previous_inline);
+                        child.layout_anonymous(*d, self.font_info.clone(), float_list, previous_inline);
                     } else {
-                        child.layout_inline(*d, float_list, previous_inline);
+                        child.layout_inline(*d, self.font_info.clone(), float_list, previous_inline);
 
                         // *previous_inline = Some((child.dimensions.margin_box().max_x() as i32, child.dimensions.margin_box().y as i32));
                     }
@@ -749,8 +1702,17 @@ impl<'a> LayoutBox<'a> {
                     previous_left_float = None;
                     previous_right_float = None;
                 },
+                InlineBlockNode(_) => {
+                    child.layout_inline_block(*d, self.font_info.clone(), previous_inline);
+
+                    let diff = child.dimensions.margin_box().max_y() - d.content.max_y();
+                    if diff > 0f32 { d.content.height += diff; }
+
+                    previous_left_float = None;
+                    previous_right_float = None;
+                },
                 TextNode(_) => {
-                    child.layout_text(*d, self.font_info, previous_inline);
+                    child.layout_text(*d, self.font_info.clone(), previous_inline);
 
                     let diff = child.dimensions.margin_box().max_y() - d.content.max_y();
                     if diff > 0f32 { d.content.height += diff; }
@@ -761,7 +1723,7 @@ impl<'a> LayoutBox<'a> {
                     previous_right_float = None;
                 },
                 AnonymousBlock => {
-                    child.layout_anonymous(*d, self.font_info, float_list, previous_inline);
+                    child.layout_anonymous(*d, self.font_info.clone(), float_list, previous_inline);
 
                     let diff = child.dimensions.margin_box().max_y() - d.content.max_y();
                     if diff > 0f32 { d.content.height += diff; }
@@ -769,6 +1731,9 @@ impl<'a> LayoutBox<'a> {
                     previous_left_float = None;
                     previous_right_float = None;
                 },
+                // Absolutely positioned boxes are out of flow, and are laid out after their
+                // containing block.
+                AbsoluteNode(_) => {},
             }
             // Update maximum float y
             if child.float_info.left_float_max_y > self.float_info.left_float_max_y {
@@ -782,20 +1747,128 @@ impl<'a> LayoutBox<'a> {
 
     /// Height of a block-level non-replaced element in normal flow with overflow visible.
     fn calculate_block_height(&mut self) {
-        let style = self.get_style_node();
         // If the height is set to an explicit length, use that exact length.
         // Otherwise, just keep the value set by `layout_block_children`.
-        match style.value("height") {
-            Some(value) => { self.dimensions.content.height = value.to_px().unwrap(); }
-            _ => {}
+        match self.definite_height() {
+            Some(height) => self.dimensions.content.height = height,
+            None => self.collapse_margin_bottom()
+        }
+        self.clamp_height();
+    }
+
+    /// The content height set by this box's `height` property, or `None` if it depends on the
+    /// box's contents.  This is known before the contents are laid out, so it is passed down as
+    /// the containing block height of the children.
+    ///
+    /// A percentage height only applies when the containing block's height is known; otherwise
+    /// it is treated as `auto`.
+    fn definite_height(&self) -> Option<f32> {
+        let style = self.get_style_node();
+        self.size_limit("height", self.containing_height).map(|height| {
+            let d = &self.dimensions;
+            let edges = d.padding.top + d.padding.bottom + d.border.top + d.border.bottom;
+            self.clamped_height(content_size(style, "height", height, edges))
+        })
+    }
+
+    /// Keep the content height within `min-height` and `max-height`.
+    fn clamp_height(&mut self) {
+        self.dimensions.content.height = self.clamped_height(self.dimensions.content.height);
+    }
+
+    /// The content height `height` kept within `min-height` and `max-height`.
+    ///
+    /// Like `height`, percentages are ignored when the containing block's height isn't known.
+    fn clamped_height(&self, height: f32) -> f32 {
+        let style = self.get_style_node();
+        let d = &self.dimensions;
+        let edges = d.padding.top + d.padding.bottom + d.border.top + d.border.bottom;
+        let mut height = height;
+
+        if let Some(max_height) = self.size_limit("max-height", self.containing_height) {
+            height = height.min(content_size(style, "max-height", max_height, edges));
+        }
+        if let Some(min_height) = self.size_limit("min-height", self.containing_height) {
+            height = height.max(content_size(style, "min-height", min_height, edges));
+        }
+        height
+    }
+
+    /// The value in px of a size property such as `height` or `min-width`, or of an offset such
+    /// as `top`, or `None` if it is `auto`, `none`, or unset.
+    /// Percentages are of `base`, and are ignored without one.
+    fn size_limit(&self, name: &str, base: Option<f32>) -> Option<f32> {
+        let font_size = self.font_info.size as f32;
+        match (self.get_style_node().value(name), base) {
+            (Some(Length(_, Unit::Percent)), None) => None,
+            (Some(value @ Length(_, _)), _) | (Some(value @ Value::Function(_, _)), _) => {
+                Some(value.length_to_px(base.unwrap_or(0.0), font_size))
+            }
+            _ => None
+        }
+    }
+
+    /// If nothing separates the bottom edge of an auto-height block from its last in-flow child,
+    /// the child's bottom margin collapses with the block's, instead of adding space inside it.
+    fn collapse_margin_bottom(&mut self) {
+        match self.box_type {
+            BlockNode(_) if !self.establishes_formatting_context() => {}
+            _ => return
+        }
+        let d = &mut self.dimensions;
+        if d.border.bottom != 0.0 || d.padding.bottom != 0.0 {
+            return;
+        }
+        let last_child = self.children.iter().rev().find(|child| match child.box_type {
+            FloatNode(_) | AbsoluteNode(_) => false,
+            _ => true
+        });
+        if let Some(child) = last_child {
+            if let BlockNode(_) = child.box_type {
+                d.content.height -= child.dimensions.margin.bottom;
+                d.margin.bottom = collapse_margins(d.margin.bottom, child.dimensions.margin.bottom);
+            }
+        }
+    }
+
+    /// The top margin of this box, collapsed with the top margins of its first in-flow children
+    /// when no border or padding separates them.
+    ///
+    /// This is worked out before the children are laid out, so percentages in their margins are
+    /// resolved against this box's current width, and ems against the font size they will have.
+    fn collapsed_margin_top(&self, containing_width: f32, font_size: f32) -> f32 {
+        let style = self.get_style_node();
+        let zero = Length(0.0, Px);
+        let margin_top = style.lookup("margin-top", "margin", &zero).length_to_px(containing_width, font_size);
+
+        match self.box_type {
+            BlockNode(_) if !self.establishes_formatting_context() => {}
+            _ => return margin_top
+        }
+        let border_top = style.lookup("border-top-width", "border-width", &zero).length_to_px(containing_width, font_size);
+        let padding_top = style.lookup("padding-top", "padding", &zero).length_to_px(containing_width, font_size);
+        if border_top != 0.0 || padding_top != 0.0 {
+            return margin_top;
+        }
+
+        let first_child = self.children.iter().find(|child| match child.box_type {
+            FloatNode(_) | AbsoluteNode(_) => false,
+            _ => true
+        });
+        match first_child {
+            Some(child) => match child.box_type {
+                BlockNode(_) => collapse_margins(margin_top, child.collapsed_margin_top(self.dimensions.content.width, child.specified_font_size(font_size))),
+                _ => margin_top
+            },
+            None => margin_top
         }
     }
 
     fn calculate_float_height(&mut self) {
         let float_value = self.get_style_node().float_value().unwrap();
 
-        match self.get_style_node().value("height") {
-            Some(value) => { self.dimensions.content.height = value.to_px().unwrap(); }
+        match self.definite_height() {
+            Some(height) => self.dimensions.content.height = height,
             _ => {
                 self.dimensions.content.height +=
                 match self.float_info.left_float_max_y > self.float_info.right_float_max_y {
@@ -822,7 +1895,7 @@ impl<'a> LayoutBox<'a> {
         let mut clear_height = 0f32;
 
         match self.box_type {
-            AnonymousBlock | TextNode(_) => return clear_height,
+            AnonymousBlock | TextNode(_) | AbsoluteNode(_) => return clear_height,
             _ => {}
         }
 
@@ -852,11 +1925,122 @@ impl<'a> LayoutBox<'a> {
         return clear_height;
     }
 
+    /// The area covered by this box's margin box together with everything it and its descendants
+    /// paint, such as text shadows and content that overflows the box.
+    pub fn margin_box_union(&self) -> Rect {
+        let mut extent = self.dimensions.margin_box();
+        if let TextNode(_) = self.box_type {
+            if let Some(shadow) = self.font_info.shadow {
+                let content = self.dimensions.content;
+                extent = extent.union(&Rect {
+                    x: content.x + shadow.offset_x as f32,
+                    y: content.y + shadow.offset_y as f32,
+                    ..content
+                });
+            }
+        }
+        for child in self.children.iter() {
+            extent = extent.union(&child.margin_box_union());
+        }
+        extent
+    }
+
+    /// Find the first box, in tree order, generated by an element that matches `selector`.
+    pub fn find_box(&self, selector: &Selector) -> Option<&LayoutBox<'a>> {
+        match self.box_type {
+            BlockNode(style) | InlineNode(style) | InlineBlockNode(style) | FloatNode(style) | AbsoluteNode(style) => {
+                if style::matches_node(&style.node, selector) {
+                    return Some(self);
+                }
+            }
+            TextNode(_) | AnonymousBlock => {}
+        }
+        for child in self.children.iter() {
+            if let Some(found) = child.find_box(selector) {
+                return Some(found);
+            }
+        }
+        None
+    }
+
+    /// Find the topmost box whose border box contains the point `(x, y)`.
+    ///
+    /// Boxes with `pointer-events: none` are transparent to hit testing, so the box behind them
+    /// is returned instead.
+    pub fn hit_test(&self, x: f32, y: f32) -> Option<&LayoutBox<'a>> {
+        self.hit_test_within(x, y, true)
+    }
+
+    /// Text and anonymous boxes accept pointer events if their parent does.
+    fn hit_test_within(&self, x: f32, y: f32, parent_accepts: bool) -> Option<&LayoutBox<'a>> {
+        let accepts = match self.box_type {
+            TextNode(_) | AnonymousBlock => parent_accepts,
+            _ => self.get_style_node().value("pointer-events") != Some(Keyword("none".to_string())),
+        };
+
+        // Test children in the reverse of the order they are painted in.
+        for layer in [2us, 1, 0].iter() {
+            for child in self.children.iter().rev() {
+                if child.paint_layer() != *layer {
+                    continue;
+                }
+                if let Some(hit) = child.hit_test_within(x, y, accepts) {
+                    return Some(hit);
+                }
+            }
+        }
+
+        if accepts && self.dimensions.border_box().contains(x, y) {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Absolutely positioned boxes paint above floats, which paint above the normal flow.
+    pub fn paint_layer(&self) -> usize {
+        match self.box_type {
+            AbsoluteNode(_) => 2,
+            FloatNode(_) => 1,
+            _ => 0,
+        }
+    }
+
+    /// How far the border boxes of this box's descendants extend past its content box, on each
+    /// side. Overflow is not clipped, but this lets callers detect it.
+    pub fn scroll_extent(&self) -> EdgeSizes {
+        let content = self.dimensions.content;
+        let mut extent: EdgeSizes = Default::default();
+        if let Some(bounds) = self.descendant_bounds() {
+            extent.left = (content.x - bounds.x).max(0.0);
+            extent.right = (bounds.max_x() - content.max_x()).max(0.0);
+            extent.top = (content.y - bounds.y).max(0.0);
+            extent.bottom = (bounds.max_y() - content.max_y()).max(0.0);
+        }
+        extent
+    }
+
+    /// The union of the border boxes of all descendants, or `None` if there are none.
+    fn descendant_bounds(&self) -> Option<Rect> {
+        let mut bounds: Option<Rect> = None;
+        for child in self.children.iter() {
+            let mut child_bounds = child.dimensions.border_box();
+            if let Some(inner) = child.descendant_bounds() {
+                child_bounds = child_bounds.union(&inner);
+            }
+            bounds = Some(match bounds {
+                Some(b) => b.union(&child_bounds),
+                None => child_bounds,
+            });
+        }
+        bounds
+    }
+
     /// Where a new inline child should go.
     fn get_inline_container(&mut self) -> &mut LayoutBox<'a> {
         match self.box_type {
-            InlineNode(_) | TextNode(_) | AnonymousBlock => self,
-            BlockNode(_) | FloatNode(_) => {
+            InlineNode(_) | InlineBlockNode(_) | TextNode(_) | AnonymousBlock => self,
+            BlockNode(_) | FloatNode(_) | AbsoluteNode(_) => {
                 // If we've just generated an anonymous block box, keep using it.
                 // Otherwise, create a new one.
                 match self.children.last() {
@@ -879,6 +2063,22 @@ impl Rect {
         }
     }
 
+    /// The smallest rectangle containing both `self` and `other`.
+    pub fn union(self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x: x,
+            y: y,
+            width: self.max_x().max(other.max_x()) - x,
+            height: self.max_y().max(other.max_y()) - y,
+        }
+    }
+
+    pub fn contains(self, x: f32, y: f32) -> bool {
+        return self.x <= x && x < self.max_x() && self.y <= y && y < self.max_y();
+    }
+
     pub fn intersect(self, other: &Rect) -> bool {
         return !self.is_empty() && !other.is_empty()
             && self.x < other.max_x() && other.x < self.max_x()
@@ -913,17 +2113,20 @@ impl Dimensions {
     }
 }
 
-pub fn show(node: &LayoutBox, depth: usize) {
+/// Describe a layout box and all its descendants, one line per box.
+pub fn dump(node: &LayoutBox, depth: usize) -> String {
     let mut info = String::new();
 
-    for i in range(0us, depth) {
+    for _ in range(0us, depth) {
         info.push_str("--");
     }
 
     let box_type_str = match node.box_type {
         BlockNode(node) => { add_tag_name(&mut info, node); "BlockNode" },
         InlineNode(node) => { add_tag_name(&mut info, node); "InlineNode" },
+        InlineBlockNode(node) => { add_tag_name(&mut info, node); "InlineBlockNode" },
         FloatNode(node) => { add_tag_name(&mut info, node); "FloatNode" },
+        AbsoluteNode(node) => { add_tag_name(&mut info, node); "AbsoluteNode" },
         TextNode(ref text) => {
             info.push_str("<text node> ");
             info.push_str(text.as_slice());
@@ -933,11 +2136,12 @@ pub fn show(node: &LayoutBox, depth: usize) {
     };
     info.push_str(box_type_str);
 
-    println!("{} : {:?}", info, node.dimensions.content);
+    let mut result = format!("{} : {:?}\n", info, node.dimensions.content);
 
     for i in node.children.iter() {
-        show(i, depth+1);
+        result.push_str(dump(i, depth+1).as_slice());
     }
+    result
 }
 
 fn add_tag_name(info: &mut String, node: &StyledNode) {
@@ -945,3 +2149,587 @@ fn add_tag_name(info: &mut String, node: &StyledNode) {
     info.push_str(node.tag_name().as_slice());
     info.push('>'); info.push(' ');
 }
+
+#[cfg(test)]
+mod tests {
+    use std::iter::range;
+    use super::{LayoutBox, Rect, TextNode, collapse_whitespace, dump};
+    use font::{Ligatures, TextDecoration};
+    use color::Color;
+    use painting;
+    use render::{render, RenderOptions, RenderResult};
+
+    /// Render `html` with `css` in an 800x600 viewport.
+    fn render_page(html: &str, css: &str) -> RenderResult {
+        let mut options = RenderOptions::new(Rect { x: 0.0, y: 0.0, width: 800.0, height: 600.0 });
+        options.css = Some(css.to_string());
+        render(html.to_string(), &options)
+    }
+
+    /// The text boxes under `layout_box`, in tree order.
+    fn text_boxes<'b>(layout_box: &'b LayoutBox<'b>) -> Vec<&'b LayoutBox<'b>> {
+        let mut boxes = Vec::new();
+        for child in layout_box.children.iter() {
+            match child.box_type {
+                TextNode(_) => boxes.push(child),
+                _ => boxes.extend(text_boxes(child).into_iter()),
+            }
+        }
+        boxes
+    }
+
+    #[test]
+    fn first_line_colors_only_the_first_line() {
+        let result = render_page("<html><p>one two three four five six seven eight nine ten</p></html>",
+                                 "p { display: block; width: 60px; font-size: 16px; color: black } p::first-line { color: red }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        assert!(lines.len() > 1);
+        let first_y = lines[0].dimensions.content.y;
+        for line in lines.iter() {
+            let expected = if line.dimensions.content.y == first_y { Color::new(255, 0, 0, 255) } else { Color::new(0, 0, 0, 255) };
+            assert_eq!(line.font_info.color, expected);
+        }
+        assert!(lines.iter().any(|line| line.dimensions.content.y > first_y));
+    }
+
+    #[test]
+    fn text_shadow_extends_past_the_margin_box() {
+        let result = render_page("<html><p>shadow</p></html>",
+                                 "p { display: block; margin: 5px; font-size: 16px; text-shadow: 10px 40px red }");
+        let layout = result.layout();
+        let p = &layout.children[0];
+        let margin_box = p.dimensions.margin_box();
+        let extent = p.margin_box_union();
+        assert!(extent.max_y() > margin_box.max_y());
+        assert!(extent.max_x() >= margin_box.max_x());
+
+        // Cropping keeps the whole shadow.
+        let canvas = painting::paint_cropped(p, Color::new(255, 255, 255, 255), false, false);
+        assert_eq!(canvas.height, extent.height as usize);
+        assert!(canvas.height > margin_box.height as usize);
+    }
+
+    #[test]
+    fn right_aligned_text_ends_at_a_float() {
+        let result = render_page("<html><div><span id=\"f\"></span>hi</div></html>",
+                                 "div { display: block; width: 300px; text-align: right; font-size: 16px } \
+                                  #f { float: right; width: 100px; height: 50px }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].dimensions.content.max_x(), 200.0);
+    }
+
+    #[test]
+    fn inline_block_aligns_its_own_lines() {
+        let result = render_page("<html><div><span id=\"ib\">hi</span></div></html>",
+                                 "div { display: block; width: 500px; font-size: 16px } \
+                                  #ib { display: inline-block; width: 200px; text-align: right }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0].dimensions.content.max_x(), 200.0);
+    }
+
+    #[test]
+    fn relative_offsets_move_inline_blocks_and_floats() {
+        let result = render_page("<html><div><span id=\"f\"></span><span id=\"ib\"></span>after</div></html>",
+                                 "div { display: block; width: 500px; font-size: 16px } \
+                                  #f { float: right; width: 40px; height: 20px; position: relative; top: 5px } \
+                                  #ib { display: inline-block; width: 50px; height: 20px; position: relative; left: 10px }");
+        // The float, the inline-block, and the text share the div's inline container.
+        let layout = result.layout();
+        let inline_container = &layout.children[0].children[0];
+        let float = &inline_container.children[0];
+        assert_eq!(float.dimensions.content.x, 460.0);
+        assert_eq!(float.dimensions.content.y, 5.0);
+
+        assert_eq!(inline_container.children[1].dimensions.content.x, 10.0);
+        // The text after the inline-block stays where the inline-block was laid out.
+        assert_eq!(text_boxes(&layout)[0].dimensions.content.x, 50.0);
+    }
+
+    #[test]
+    fn font_feature_settings_turns_ligatures_off() {
+        let result = render_page("<html><p id=\"off\">fi</p><p id=\"on\">fi</p></html>",
+                                 "p { display: block; font-size: 16px } \
+                                  #off { font-feature-settings: \"kern\", \"liga\" 0 } \
+                                  #on { font-variant-ligatures: none; font-feature-settings: \"liga\" on }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        assert_eq!(lines[0].font_info.ligatures, Ligatures::None);
+        assert_eq!(lines[1].font_info.ligatures, Ligatures::Common);
+    }
+
+    #[test]
+    fn universal_border_box_sizes_nested_boxes() {
+        let result = render_page("<html><div id=\"outer\"><div id=\"inner\"><div id=\"leaf\"></div></div></div></html>",
+                                 "* { box-sizing: border-box } \
+                                  div { display: block; padding: 5px; border-width: 2px } \
+                                  #outer { width: 300px; height: 200px } \
+                                  #inner { width: 100px; height: 80px; padding: 10px } \
+                                  #leaf { width: 50px; height: 30px }");
+        let layout = result.layout();
+        let outer = &layout.children[0];
+        assert_eq!(outer.dimensions.content.width, 286.0);
+        assert_eq!(outer.dimensions.content.height, 186.0);
+        assert_eq!(outer.dimensions.border_box().width, 300.0);
+        assert_eq!(outer.dimensions.border_box().height, 200.0);
+
+        let inner = &outer.children[0];
+        assert_eq!(inner.dimensions.content.width, 76.0);
+        assert_eq!(inner.dimensions.content.height, 56.0);
+        assert_eq!(inner.dimensions.border_box().width, 100.0);
+
+        let leaf = &inner.children[0];
+        assert_eq!(leaf.dimensions.content.width, 36.0);
+        assert_eq!(leaf.dimensions.content.height, 16.0);
+        assert_eq!(leaf.dimensions.border_box().height, 30.0);
+    }
+
+    #[test]
+    fn auto_margins_center_a_percentage_width() {
+        let result = render_page("<html><div></div></html>",
+                                 "div { display: block; width: 50%; height: 10px; margin: 0 auto }");
+        let layout = result.layout();
+        let d = layout.children[0].dimensions;
+        assert_eq!(d.content.width, 400.0);
+        assert_eq!(d.margin.left, 200.0);
+        assert_eq!(d.margin.right, 200.0);
+    }
+
+    #[test]
+    fn percentage_width_and_margin_resolve_against_the_container() {
+        let result = render_page("<html><div id=\"outer\"><div id=\"inner\"></div></div></html>",
+                                 "div { display: block; height: 10px } \
+                                  #outer { width: 600px } \
+                                  #inner { width: 50%; margin-left: 10% }");
+        let layout = result.layout();
+        let inner = layout.children[0].children[0].dimensions;
+        assert_eq!(inner.content.width, 300.0);
+        assert_eq!(inner.margin.left, 60.0);
+        assert_eq!(inner.content.x, 60.0);
+    }
+
+    #[test]
+    fn collapse_whitespace_merges_tabs_and_line_breaks() {
+        assert_eq!(collapse_whitespace("one\t\ttwo"), "one two");
+        assert_eq!(collapse_whitespace("one\r\ntwo\n\nthree"), "one two three");
+        assert_eq!(collapse_whitespace("one \t\r\n two"), "one two");
+    }
+
+    #[test]
+    fn collapse_whitespace_leaves_one_space_at_each_end() {
+        assert_eq!(collapse_whitespace("  \n\tone two\t \r\n"), " one two ");
+        assert_eq!(collapse_whitespace("\t\n"), " ");
+        assert_eq!(collapse_whitespace(""), "");
+    }
+
+    #[test]
+    fn absolute_box_is_placed_in_its_relative_parent() {
+        let result = render_page("<html><div id=\"above\"></div><div id=\"rel\"><div id=\"abs\"></div></div></html>",
+                                 "div { display: block } \
+                                  #above { height: 30px } \
+                                  #rel { position: relative; margin-left: 40px; padding: 5px; width: 200px; height: 100px } \
+                                  #abs { position: absolute; top: 10px; left: 20px; width: 30px; height: 10px }");
+        let layout = result.layout();
+        let abs = layout.children[1].children[0].dimensions;
+        // Offsets are from the relative box's padding box, at (40, 30), not the viewport.
+        assert_eq!(abs.content.x, 60.0);
+        assert_eq!(abs.content.y, 40.0);
+        assert_eq!(abs.content.width, 30.0);
+    }
+
+    #[test]
+    fn scroll_extent_measures_a_child_wider_than_its_parent() {
+        let result = render_page("<html><div id=\"parent\"><div id=\"child\"></div></div></html>",
+                                 "div { display: block } \
+                                  #parent { width: 100px } \
+                                  #child { width: 300px; height: 20px }");
+        let layout = result.layout();
+        let parent = &layout.children[0];
+        let extent = parent.scroll_extent();
+        assert!(extent.right > 0.0);
+        assert_eq!(extent.right, 200.0);
+        assert_eq!(extent.left, 0.0);
+        assert_eq!(extent.bottom, 0.0);
+
+        // A box with nothing inside it has nothing to overflow.
+        assert_eq!(parent.children[0].scroll_extent().right, 0.0);
+    }
+
+    #[test]
+    fn first_child_top_margin_collapses_through_its_parent() {
+        let html = "<html><div id=\"above\"></div><div id=\"parent\"><div id=\"child\"></div></div></html>";
+        let css = "div { display: block } #above { height: 10px } #child { margin-top: 20px; height: 10px }";
+
+        let result = render_page(html, css);
+        let layout = result.layout();
+        let parent = &layout.children[1];
+        assert_eq!(parent.dimensions.margin.top, 20.0);
+        assert_eq!(parent.dimensions.content.y, 30.0);
+        assert_eq!(parent.dimensions.content.height, 10.0);
+        assert_eq!(parent.children[0].dimensions.content.y, 30.0);
+
+        // Padding keeps the margins apart.
+        let result = render_page(html, format!("{} #parent {{ padding-top: 1px }}", css).as_slice());
+        let layout = result.layout();
+        let parent = &layout.children[1];
+        assert_eq!(parent.dimensions.margin.top, 0.0);
+        assert_eq!(parent.dimensions.content.y, 11.0);
+        assert_eq!(parent.children[0].dimensions.content.y, 31.0);
+    }
+
+    #[test]
+    fn first_letter_is_enlarged() {
+        let result = render_page("<html><p>Hello world</p></html>",
+                                 "p { display: block; width: 400px; font-size: 16px } p::first-letter { font-size: 32px }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        assert_eq!(lines.len(), 2);
+        match lines[0].box_type {
+            TextNode(ref text) => assert_eq!(text.as_slice(), "H"),
+            _ => panic!("expected a text box"),
+        }
+        assert_eq!(lines[0].font_info.size, 32);
+        assert_eq!(lines[1].font_info.size, 16);
+        assert!(lines[1].dimensions.content.x >= lines[0].dimensions.content.max_x());
+    }
+
+    #[test]
+    fn fit_content_with_a_limit_shrinks_and_caps_the_width() {
+        let result = render_page("<html><div>hi</div><div>one two three four five six seven</div>\
+                                  <div>supercalifragilisticexpialidocious</div></html>",
+                                 "div { display: block; width: fit-content(100px); font-size: 16px }");
+        let layout = result.layout();
+        let divs = &layout.children;
+
+        // Narrow content is as wide as its text.
+        let narrow = divs[0].dimensions.content.width;
+        assert!(narrow > 0.0 && narrow < 100.0);
+        assert_eq!(narrow, text_boxes(&divs[0])[0].dimensions.content.width);
+
+        // Wide content wraps at the limit.
+        assert_eq!(divs[1].dimensions.content.width, 100.0);
+        assert!(text_boxes(&divs[1]).len() > 1);
+
+        // A word longer than the limit can't be any narrower.
+        assert!(divs[2].dimensions.content.width > 100.0);
+    }
+
+    #[test]
+    fn edge_sizes_resolve_em_and_rem() {
+        let result = render_page("<html><div></div></html>",
+                                 "html { font-size: 8px } \
+                                  div { display: block; font-size: 20px; height: 10px; \
+                                        padding: 0.5em; border-width: 0.1em; margin-left: 2rem }");
+        let layout = result.layout();
+        let d = layout.children[0].dimensions;
+        assert_eq!(d.padding.top, 10.0);
+        assert_eq!(d.padding.left, 10.0);
+        assert_eq!(d.border.bottom, 2.0);
+        assert_eq!(d.border.right, 2.0);
+        assert_eq!(d.margin.left, 16.0);
+    }
+
+    #[test]
+    fn relative_offsets_move_a_block_but_not_its_siblings() {
+        let result = render_page("<html><div id=\"outer\"><div id=\"moved\"></div><div id=\"next\"></div></div></html>",
+                                 "div { display: block; height: 20px } \
+                                  #outer { width: 400px; height: auto } \
+                                  #moved { position: relative; top: 5px; left: 10% }");
+        let layout = result.layout();
+        let outer = &layout.children[0];
+        assert_eq!(outer.children[0].dimensions.content.x, 40.0);
+        assert_eq!(outer.children[0].dimensions.content.y, 5.0);
+        assert_eq!(outer.children[1].dimensions.content.x, 0.0);
+        assert_eq!(outer.children[1].dimensions.content.y, 20.0);
+        assert_eq!(outer.dimensions.content.height, 40.0);
+    }
+
+    #[test]
+    fn max_width_clamp_redistributes_auto_margins() {
+        let result = render_page("<html><div></div></html>",
+                                 "div { display: block; height: 10px; max-width: 50%; margin: 0 auto }");
+        let layout = result.layout();
+        let d = layout.children[0].dimensions;
+        assert_eq!(d.content.width, 400.0);
+        assert_eq!(d.margin.left, 200.0);
+        assert_eq!(d.margin.right, 200.0);
+    }
+
+    #[test]
+    fn min_width_wins_over_a_smaller_width_and_max_width() {
+        let result = render_page("<html><div></div></html>",
+                                 "div { display: block; height: 10px; width: 100px; max-width: 150px; min-width: 200px }");
+        let layout = result.layout();
+        assert_eq!(layout.children[0].dimensions.content.width, 200.0);
+    }
+
+    #[test]
+    fn heights_are_clamped_to_min_and_max_height() {
+        let result = render_page("<html><div id=\"short\"></div><div id=\"tall\"><div id=\"content\"></div></div></html>",
+                                 "div { display: block } \
+                                  #short { height: 10px; min-height: 30px } \
+                                  #tall { max-height: 50px } \
+                                  #content { height: 100px }");
+        let layout = result.layout();
+        let boxes = &layout.children;
+        assert_eq!(boxes[0].dimensions.content.height, 30.0);
+        assert_eq!(boxes[1].dimensions.content.height, 50.0);
+        assert_eq!(boxes[1].dimensions.content.y, 30.0);
+    }
+
+    #[test]
+    fn text_align_shifts_lines_by_the_remaining_space() {
+        let result = render_page("<html><p id=\"l\">hi</p><p id=\"c\">hi</p><p id=\"r\">hi</p></html>",
+                                 "p { display: block; width: 300px; font-size: 16px } \
+                                  #c { text-align: center } #r { text-align: right }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        let width = lines[0].dimensions.content.width;
+        assert_eq!(lines[0].dimensions.content.x, 0.0);
+        assert_eq!(lines[1].dimensions.content.x, (300.0 - width) / 2.0);
+        assert_eq!(lines[2].dimensions.content.x, 300.0 - width);
+    }
+
+    #[test]
+    fn nested_percentage_heights_resolve_against_explicit_heights() {
+        let result = render_page("<html><div id=\"a\"><div id=\"b\"><div id=\"c\"></div></div></div>\
+                                  <div id=\"auto\"><div id=\"d\"><div></div></div></div></html>",
+                                 "div { display: block } \
+                                  #a { height: 200px } #b { height: 50% } #c { height: 25% } \
+                                  #d { height: 50% } #d div { height: 10px }");
+        let layout = result.layout();
+        let a = &layout.children[0];
+        assert_eq!(a.children[0].dimensions.content.height, 100.0);
+        assert_eq!(a.children[0].children[0].dimensions.content.height, 25.0);
+
+        // Under a parent with an auto height, a percentage height acts like auto.
+        let d = &layout.children[1].children[0];
+        assert_eq!(d.dimensions.content.height, 10.0);
+    }
+
+    #[test]
+    fn adjacent_sibling_margins_collapse_to_the_larger() {
+        let result = render_page("<html><div id=\"a\"></div><div id=\"b\"></div><div id=\"c\"></div></html>",
+                                 "div { display: block; height: 10px } \
+                                  #a { margin-bottom: 20px } #b { margin-top: 30px; margin-bottom: -5px } #c { margin-top: 15px }");
+        let layout = result.layout();
+        let boxes = &layout.children;
+        assert_eq!(boxes[0].dimensions.content.y, 0.0);
+        // max(20, 30), not 20 + 30.
+        assert_eq!(boxes[1].dimensions.content.y, 40.0);
+        // A negative margin is added to the largest positive one.
+        assert_eq!(boxes[2].dimensions.content.y, 60.0);
+    }
+
+    #[test]
+    fn chain_of_full_heights_matches_the_root_height() {
+        let result = render_page("<html><div><div><div><p>text</p></div></div></div></html>",
+                                 "html { height: 300px } div { display: block; height: 100% } p { display: block; font-size: 16px }");
+        let layout = result.layout();
+        let mut layout_box = &layout.children[0];
+        for _ in range(0us, 3) {
+            assert_eq!(layout_box.dimensions.content.height, 300.0);
+            layout_box = &layout_box.children[0];
+        }
+        // The paragraph's own height is still its content's.
+        assert!(layout_box.dimensions.content.height < 300.0);
+    }
+
+    #[test]
+    fn overflow_wrap_anywhere_shrinks_the_min_content_width() {
+        let result = render_page("<html><div>a supercalifragilistic word</div><div id=\"anywhere\">a supercalifragilistic word</div></html>",
+                                 "div { display: block; width: min-content; font-size: 16px } \
+                                  #anywhere { overflow-wrap: anywhere }");
+        let layout = result.layout();
+        let normal = layout.children[0].dimensions.content.width;
+        let anywhere = layout.children[1].dimensions.content.width;
+        assert!(anywhere > 0.0);
+        assert!(anywhere < normal);
+    }
+
+    #[test]
+    fn caption_side_places_the_caption_above_or_below_the_rows() {
+        let html = "<html><table><caption>Caption</caption><tr id=\"row\"></tr></table></html>";
+        let css = "table, caption, tr { display: block } table { width: 300px } \
+                   caption { font-size: 16px } tr { height: 20px }";
+
+        let result = render_page(html, css);
+        let layout = result.layout();
+        let table = &layout.children[0];
+        assert_eq!(table.children[1].dimensions.content.y, table.children[0].dimensions.margin_box().max_y());
+        assert_eq!(table.children[0].dimensions.content.y, 0.0);
+
+        let result = render_page(html, format!("{} caption {{ caption-side: bottom }}", css).as_slice());
+        let layout = result.layout();
+        let table = &layout.children[0];
+        let (row, caption) = (&table.children[0], &table.children[1]);
+        assert_eq!(row.dimensions.content.y, 0.0);
+        assert_eq!(caption.dimensions.content.y, 20.0);
+        assert_eq!(caption.dimensions.content.width, 300.0);
+    }
+
+    #[test]
+    fn text_decoration_from_a_style_attribute_is_inherited() {
+        let result = render_page("<html><p style=\"text-decoration: line-through\">one <span>two</span></p><p>three</p></html>",
+                                 "p { display: block; font-size: 16px }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0].font_info.deco, TextDecoration::LineThrough);
+        assert_eq!(lines[1].font_info.deco, TextDecoration::LineThrough);
+        assert_eq!(lines[2].font_info.deco, TextDecoration::Normal);
+    }
+
+    #[test]
+    fn pre_keeps_spaces_and_breaks_only_at_newlines() {
+        let result = render_page("<html><pre>a  b\nc d e f g h</pre></html>",
+                                 "pre { display: block; width: 20px; font-size: 16px; white-space: pre }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        let texts: Vec<String> = lines.iter().map(|line| match line.box_type {
+            TextNode(ref text) => text.clone(),
+            _ => String::new(),
+        }).collect();
+        assert_eq!(texts, vec!["a  b".to_string(), "c d e f g h".to_string()]);
+        assert!(lines[1].dimensions.content.y > lines[0].dimensions.content.y);
+    }
+
+    #[test]
+    fn nowrap_overflows_instead_of_wrapping() {
+        let result = render_page("<html><div>one two three four five</div></html>",
+                                 "div { display: block; width: 40px; font-size: 16px; white-space: nowrap }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].dimensions.content.width > 40.0);
+        assert!(layout.children[0].scroll_extent().right > 0.0);
+    }
+
+    #[test]
+    fn ellipsis_truncates_to_the_width_left_by_a_float() {
+        let result = render_page("<html><div><span id=\"f\"></span>one two three four five six seven</div></html>",
+                                 "div { display: block; width: 200px; font-size: 16px; white-space: nowrap; \
+                                        overflow: hidden; text-overflow: ellipsis } \
+                                  #f { float: left; width: 120px; height: 50px }");
+        let layout = result.layout();
+        let lines = text_boxes(&layout);
+        assert_eq!(lines.len(), 1);
+        let line = lines[0];
+        assert_eq!(line.dimensions.content.x, 120.0);
+        assert!(line.dimensions.content.max_x() <= 200.0);
+        match line.box_type {
+            TextNode(ref text) => assert!(text.as_slice().ends_with("\u{2026}")),
+            _ => panic!("expected a text box"),
+        }
+    }
+
+    #[test]
+    fn floats_escape_overflow_clip_but_not_overflow_hidden() {
+        let html = "<html><div id=\"box\"><span id=\"f\"></span></div><p>after</p></html>";
+        let css = "div, p { display: block; font-size: 16px } #f { float: left; width: 50px; height: 50px }";
+
+        let result = render_page(html, format!("{} #box {{ overflow: clip }}", css).as_slice());
+        let layout = result.layout();
+        let text = text_boxes(&layout)[0].dimensions.content;
+        assert_eq!(layout.children[0].dimensions.content.height, 0.0);
+        assert_eq!(text.x, 50.0);
+
+        let result = render_page(html, format!("{} #box {{ overflow: hidden }}", css).as_slice());
+        let layout = result.layout();
+        let text = text_boxes(&layout)[0].dimensions.content;
+        assert_eq!(layout.children[0].dimensions.content.height, 50.0);
+        assert_eq!(text.x, 0.0);
+        assert_eq!(text.y, 50.0);
+    }
+
+    #[test]
+    fn vertical_offset_percentages_use_the_containing_block_height() {
+        let result = render_page("<html><div id=\"outer\"><div id=\"abs\"></div><div id=\"rel\"></div><div id=\"min\"></div></div>\
+                                  <div id=\"wrap\"><div id=\"auto\"></div></div></html>",
+                                 "div { display: block; height: 10px } \
+                                  #outer { position: relative; width: 800px; height: 400px } \
+                                  #abs { position: absolute; top: 50% } \
+                                  #rel { position: relative; top: 25% } \
+                                  #min { position: relative; top: min(50%, 300px) } \
+                                  #wrap { height: auto } \
+                                  #auto { position: relative; top: 50%; height: 10px }");
+        let layout = result.layout();
+        let outer = &layout.children[0];
+        assert_eq!(outer.children[0].dimensions.content.y, 200.0);
+        // `#rel` is laid out at y = 0, and `#min` at y = 10, before their offsets.
+        assert_eq!(outer.children[1].dimensions.content.y, 100.0);
+        assert_eq!(outer.children[2].dimensions.content.y, 210.0);
+
+        // Without a definite height to resolve against, a percentage offset is `auto`.
+        let auto = &layout.children[1].children[0];
+        assert_eq!(auto.dimensions.content.y, 400.0);
+    }
+
+    #[test]
+    fn dump_lists_each_box_indented_by_depth() {
+        let result = render_page("<html><div></div></html>", "div { display: block; width: 10px; height: 20px }");
+        let layout = result.layout();
+        let dumped = dump(&layout, 0);
+        let lines: Vec<&str> = dumped.as_slice().lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("<html> BlockNode : "));
+        assert!(lines[1].starts_with("--<div> BlockNode : "));
+    }
+
+    #[test]
+    fn math_functions_resolve_percentages_against_the_containing_block() {
+        let result = render_page("<html><div id=\"outer\"><div id=\"high\"></div><div id=\"low\"></div>\
+                                  <div id=\"min\"></div><div id=\"max\"></div></div></html>",
+                                 "div { display: block; height: 10px } \
+                                  #outer { width: 1000px; height: auto } \
+                                  #high { width: clamp(100px, 50%, 300px) } \
+                                  #low { width: clamp(100px, 5%, 300px) } \
+                                  #min { width: min(20%, 300px) } \
+                                  #max { width: max(10%, 50px) }");
+        let layout = result.layout();
+        let widths: Vec<f32> = layout.children[0].children.iter()
+            .map(|child| child.dimensions.content.width)
+            .collect();
+        assert_eq!(widths, vec![300.0, 100.0, 200.0, 100.0]);
+    }
+
+    #[test]
+    fn display_contents_lays_out_its_children_as_siblings_of_its_own() {
+        let result = render_page("<html><div id=\"outer\"><div id=\"wrap\"><div id=\"a\"></div><div id=\"b\"></div></div>\
+                                  <div id=\"c\"></div></div></html>",
+                                 "div { display: block } \
+                                  #wrap { display: contents; padding: 50px; height: 100px } \
+                                  #a { height: 10px } #b { height: 20px } #c { height: 30px }");
+        let layout = result.layout();
+        let outer = &layout.children[0];
+        assert_eq!(outer.children.len(), 3);
+        let heights: Vec<f32> = outer.children.iter().map(|child| child.dimensions.content.height).collect();
+        let tops: Vec<f32> = outer.children.iter().map(|child| child.dimensions.content.y).collect();
+        let lefts: Vec<f32> = outer.children.iter().map(|child| child.dimensions.content.x).collect();
+        assert_eq!(heights, vec![10.0, 20.0, 30.0]);
+        assert_eq!(tops, vec![0.0, 10.0, 30.0]);
+        assert_eq!(lefts, vec![0.0, 0.0, 0.0]);
+        assert_eq!(outer.dimensions.content.height, 60.0);
+    }
+
+    #[test]
+    fn bordered_span_wrapping_onto_two_lines_has_a_fragment_per_line() {
+        let result = render_page("<html><p><span>aaaa bbbb</span></p></html>",
+                                 "p { display: block; width: 60px; font-size: 16px } \
+                                  span { border-width: 2px; border-color: red }");
+        let layout = result.layout();
+        let span = &layout.children[0].children[0].children[0];
+        let lines = text_boxes(span);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(span.fragments.len(), 2);
+        assert!(span.fragments[0].y < span.fragments[1].y);
+        for (fragment, line) in span.fragments.iter().zip(lines.iter()) {
+            assert_eq!((fragment.x, fragment.y), (line.dimensions.content.x, line.dimensions.content.y));
+            assert_eq!(fragment.width, line.dimensions.content.width);
+        }
+    }
+}
@@ -5,12 +5,13 @@ extern crate image;
 extern crate freetype;
 extern crate libc;
 
-use getopts::{optopt,getopts};
-use std::default::Default;
+use getopts::{optopt,optflag,optmulti,getopts};
 use std::io::fs::File;
+use std::io::{stdout, stderr};
 use std::os::args;
 use std::rc::Rc;
 use std::collections::HashMap;
+use std::str::FromStr;
 
 mod css;
 mod dom;
@@ -22,6 +23,10 @@ mod color;
 mod shorthand;
 mod font_context;
 mod font;
+mod animation;
+mod properties;
+mod bidi;
+mod render;
 
 fn main() {
     // Parse command-line options:
@@ -29,11 +34,53 @@ fn main() {
         optopt("h", "html", "HTML document", "FILENAME"),
         optopt("c", "css", "CSS stylesheet", "FILENAME"),
         optopt("o", "output", "Output file", "FILENAME"),
+        optopt("b", "background", "Canvas background color", "COLOR"),
+        optopt("s", "render-selector", "Render only the first element matching a selector", "SELECTOR"),
+        optopt("t", "time", "Render animations as they appear at a time", "SECONDS"),
+        optopt("", "css-state", "Also render with an extra stylesheet applied, e.g. for hover", "FILENAME"),
+        optflag("", "gamma", "Blend anti-aliased text in linear light"),
+        optflag("", "checkerboard", "Paint a checkerboard instead of the background color, to show transparency"),
+        optflag("q", "quiet", "Only print errors"),
+        optflag("", "profile", "Print the time spent parsing, styling, laying out, and painting"),
+        optopt("", "style-json", "Write the styled tree as JSON", "FILENAME"),
+        optflag("", "json-data", "Include data-* attributes in the --style-json output"),
+        optflag("", "dump-display-list", "Print the display list as JSON to stderr before painting"),
+        optopt("", "font", "TrueType font file used for all text", "FILENAME"),
+        optmulti("", "font-family", "TrueType font file used for a font-family name", "NAME=FILENAME"),
+        optmulti("", "assert-pixel", "Exit with an error unless the pixel at x,y has this color", "X,Y=COLOR"),
     ];
     let matches = match getopts(args().tail(), &opts) {
         Ok(m) => m,
         Err(f) => panic!(f.to_string())
     };
+    let quiet = matches.opt_present("q");
+
+    // Check the selector before doing any work:
+    let crop_selectors = match matches.opt_str("s") {
+        Some(source) => match css::parse_selectors(source.clone()) {
+            Ok(selectors) => Some((source, selectors)),
+            Err(error) => {
+                report_error(format!("Invalid selector {}: {}", source, error.message));
+                return;
+            }
+        },
+        None => None
+    };
+    let filename = matches.opt_str("o").unwrap_or("output.png".to_string());
+
+    // `-o -` writes the image to stdout, so everything else goes to stderr instead.
+    let mut log = if filename == "-" { stderr() } else { stdout() };
+    let mut font_families = HashMap::new();
+    for family in matches.opt_strs("font-family").iter() {
+        let parts: Vec<&str> = family.splitn(1, '=').collect();
+        if parts.len() != 2 {
+            report_error(format!("--font-family expects NAME=FILENAME but got '{}'", family));
+            return;
+        }
+        font_families.insert(parts[0].trim().to_string(), parts[1].trim().to_string());
+    }
+    let font_path = matches.opt_str("font").unwrap_or(font::DEFAULT_FONT_PATH.to_string());
+    let fonts = Rc::new(font::FontConfig::new(font_path, font_families));
 
     // Read input files:
     let read_source = |&: arg_filename: Option<String>, default_filename: &str| {
@@ -46,45 +93,194 @@ fn main() {
     let html = read_source(matches.opt_str("h"), "examples/test.html");
 
     // Since we don't have an actual window, hard-code the "viewport" size.
-    let initial_containing_block = layout::Dimensions {
-        content: layout::Rect { x: 0.0, y: 0.0, width: 1200.0, height: 800.0 },
-        padding: Default::default(),
-        border: Default::default(),
-        margin: Default::default(),
-    };
+    let mut options = render::RenderOptions::new(layout::Rect { x: 0.0, y: 0.0, width: 1200.0, height: 800.0 });
+    options.css = matches.opt_str("c").map(|filename| read_source(Some(filename), ""));
+    if let Some(time) = matches.opt_str("t") {
+        match FromStr::from_str(time.as_slice()) {
+            Some(t) => options.time = Some(t),
+            None => {
+                report_error(format!("Invalid time: {}", time));
+                return;
+            }
+        }
+    }
+    // The `--background` option overrides the root element's background color.
+    if let Some(color) = matches.opt_str("b") {
+        match color::parse(color.as_slice()) {
+            Some(c) => options.background = Some(c),
+            None => {
+                report_error(format!("Invalid background color: {}", color));
+                return;
+            }
+        }
+    }
+    options.gamma_correct = matches.opt_present("gamma");
+    options.checkerboard = matches.opt_present("checkerboard");
+    options.profile = matches.opt_present("profile");
+    options.fonts = fonts;
 
     // Parsing and rendering:
-    let root_node = html::parse(html);
-    dom::show_all(&root_node, 1);
-    println!("=================================================");
-    let css_string = dom::find_style(&root_node);
-    let stylesheet = css::parse(css_string);
-    // css::show(stylesheet);
-    // println!("=================================================");
-    let style_root = style::style_tree(&root_node, &stylesheet, &HashMap::new());
-    // style::show(&style_root, 1);
-    // println!("=================================================");
-    let layout_root = layout::layout_tree(&style_root, initial_containing_block);
-    layout::show(&layout_root, 1);
-
-    let canvas = painting::paint(&layout_root, initial_containing_block.content, style_root.background_color());
-
-    // Create the output file:
-    let filename = matches.opt_str("o").unwrap_or("output.png".to_string());
-    let file = File::create(&Path::new(&*filename)).unwrap();
+    let result = render::render(html.clone(), &options);
+    if !quiet {
+        let _ = log.write_str(dom::dump_all(&result.document, 1).as_slice());
+        let _ = log.write_line("=================================================");
+        let _ = log.write_str(style::dump(result.style_root(), 1).as_slice());
+        let _ = log.write_line("=================================================");
+    }
+    if let Some(json_filename) = matches.opt_str("style-json") {
+        let json = style::to_json(result.style_root(), matches.opt_present("json-data"));
+        let mut file = File::create(&Path::new(&*json_filename)).unwrap();
+        match file.write_str(json.as_slice()) {
+            Ok(_) => if !quiet { let _ = log.write_line(format!("Saved styles as {}", json_filename).as_slice()); },
+            Err(_) => report_error(format!("Error saving styles as {}", json_filename))
+        }
+    }
+    let layout_root = result.layout();
+    if !quiet {
+        let _ = log.write_str(layout::dump(&layout_root, 1).as_slice());
+    }
 
-    // Save an image:
+    // The box to paint on its own, if there is a selector for it.
+    let cropped_box = match crop_selectors {
+        Some((ref source, ref selectors)) => {
+            match selectors.iter().filter_map(|selector| layout_root.find_box(selector)).next() {
+                Some(layout_box) => Some(layout_box),
+                None => {
+                    report_error(format!("No element matches {}", source));
+                    return;
+                }
+            }
+        }
+        None => None
+    };
+    if matches.opt_present("dump-display-list") {
+        let json = painting::display_list_json(cropped_box.unwrap_or(&layout_root));
+        let _ = stderr().write_line(json.as_slice());
+    }
+    let cropped_canvas = cropped_box.map(|layout_box| {
+        let background = options.background.unwrap_or_else(|| result.style_root().background_color());
+        painting::paint_cropped(layout_box, background, options.gamma_correct, options.checkerboard)
+    });
+    let canvas = cropped_canvas.as_ref().unwrap_or(&result.canvas);
+
+    // Check the rendered pixels before the canvas is saved:
+    for assertion in matches.opt_strs("assert-pixel").iter() {
+        if let Err(message) = check_pixel(canvas, assertion.as_slice()) {
+            report_error(format!("Assertion failed: {}", message));
+        }
+    }
+
+    // An output filename ending in `.svg` saves the layout as vector graphics instead:
+    if filename.ends_with(".svg") {
+        let mut file = File::create(&Path::new(&*filename)).unwrap();
+        let svg = layout_root.to_svg(options.viewport);
+        match file.write_str(svg.as_slice()) {
+            Ok(_) => if !quiet { let _ = log.write_line(format!("Saved output as {}", filename).as_slice()); },
+            Err(_) => report_error(format!("Error saving output as {}", filename))
+        }
+    } else {
+        save_png(canvas, filename.as_slice(), quiet, &mut log);
+    }
+
+    // Render the page again with the state stylesheet taking priority over the page's own, and
+    // save it next to the first image:
+    if let Some(state_filename) = matches.opt_str("css-state") {
+        options.state_css = Some(read_source(Some(state_filename), ""));
+        let state_result = render::render(html, &options);
+
+        let base_name = if filename.ends_with(".png") || filename.ends_with(".svg") {
+            filename.slice_to(filename.len() - 4)
+        } else {
+            filename.as_slice()
+        };
+        save_png(&state_result.canvas, format!("{}-state.png", base_name).as_slice(), quiet, &mut log);
+    }
+}
+
+/// Print `message` to stderr, and make robinson exit with an error status.
+fn report_error(message: String) {
+    let _ = stderr().write_line(message.as_slice());
+    std::os::set_exit_status(1);
+}
+
+/// Check an assertion of the form `x,y=color` against the pixels of a canvas.
+fn check_pixel(canvas: &painting::Canvas, assertion: &str) -> Result<(), String> {
+    let parts: Vec<&str> = assertion.splitn(1, '=').collect();
+    if parts.len() != 2 {
+        return Err(format!("expected x,y=color but got '{}'", assertion));
+    }
+    let coords: Vec<Option<usize>> = parts[0].split(',').map(|n| FromStr::from_str(n.trim())).collect();
+    let (x, y) = match coords.as_slice() {
+        [Some(x), Some(y)] => (x, y),
+        _ => return Err(format!("invalid pixel position '{}'", parts[0]))
+    };
+    let expected = match color::parse(parts[1].trim()) {
+        Some(color) => color,
+        None => return Err(format!("invalid color '{}'", parts[1]))
+    };
+    if x >= canvas.width || y >= canvas.height {
+        return Err(format!("pixel {},{} is outside the {}x{} canvas", x, y, canvas.width, canvas.height));
+    }
+
+    let actual = canvas.pixels[y * canvas.width + x];
+    if actual != expected {
+        return Err(format!("pixel {},{} is {:?}, not {:?}", x, y, actual, expected));
+    }
+    Ok(())
+}
+
+/// Save a canvas as a PNG image, or write it to stdout if `filename` is `-`.  Unless `quiet` is
+/// set, report where it was saved to `log`.
+fn save_png(canvas: &painting::Canvas, filename: &str, quiet: bool, log: &mut Writer) {
+    let result = if filename == "-" {
+        write_png(canvas, stdout())
+    } else {
+        write_png(canvas, File::create(&Path::new(filename)).unwrap())
+    };
+    match result {
+        Ok(_) => if !quiet { let _ = log.write_line(format!("Saved output as {}", filename).as_slice()); },
+        Err(_) => report_error(format!("Error saving output as {}", filename))
+    }
+}
+
+/// Encode a canvas as a PNG image, and write it to `writer`.
+fn write_png<W: Writer>(canvas: &painting::Canvas, writer: W) -> image::ImageResult<()> {
     let (w, h) = (canvas.width as u32, canvas.height as u32);
-    let buffer: Vec<image::Rgba<u8>> = unsafe { std::mem::transmute(canvas.pixels) };
+    let buffer: &[image::Rgba<u8>] = unsafe { std::mem::transmute(canvas.pixels.as_slice()) };
     let img = image::ImageBuffer::from_fn(w, h, Box::new(|&: x: u32, y: u32| buffer[(y * w + x) as usize]));
+    image::ImageRgba8(img).save(writer, image::PNG)
+}
 
-    let result = image::ImageRgba8(img).save(file, image::PNG);
-    match result {
-        Ok(_) => println!("Saved output as {}", filename),
-        Err(_) => println!("Error saving output as {}", filename)
+#[cfg(test)]
+mod tests {
+    use std::io::MemWriter;
+    use color::Color;
+    use painting::Canvas;
+    use super::{write_png, check_pixel};
+
+    #[test]
+    fn png_output_starts_with_the_png_signature() {
+        let mut output = MemWriter::new();
+        write_png(&Canvas::new(4, 3, Color::new(255, 0, 0, 255)), &mut output).unwrap();
+        assert_eq!(output.get_ref().slice_to(8), [137u8, 80, 78, 71, 13, 10, 26, 10].as_slice());
     }
 
-    // Debug output:
-    // println!("{}", layout_root.dimensions);
-    // println!("{}", display_list);
+    #[test]
+    fn check_pixel_passes_only_for_the_right_color() {
+        let mut canvas = Canvas::new(4, 3, Color::new(255, 255, 255, 255));
+        canvas.pixels[2 * 4 + 1] = Color::new(255, 0, 0, 255);
+        assert_eq!(check_pixel(&canvas, "1,2=red"), Ok(()));
+        assert_eq!(check_pixel(&canvas, "1, 2 = #f00"), Ok(()));
+        assert!(check_pixel(&canvas, "1,2=blue").is_err());
+        assert!(check_pixel(&canvas, "0,0=red").is_err());
+    }
+
+    #[test]
+    fn check_pixel_rejects_bad_assertions() {
+        let canvas = Canvas::new(4, 3, Color::new(255, 255, 255, 255));
+        assert!(check_pixel(&canvas, "1,2").is_err());
+        assert!(check_pixel(&canvas, "1=white").is_err());
+        assert!(check_pixel(&canvas, "1,2=notacolor").is_err());
+        assert!(check_pixel(&canvas, "4,0=white").is_err());
+    }
 }
@@ -1,6 +1,8 @@
-use layout::{AnonymousBlock, BlockNode, InlineNode, FloatNode, TextNode, LayoutBox, Rect};
-use css::{Value};
+use layout::{AnonymousBlock, BlockNode, InlineNode, InlineBlockNode, FloatNode, AbsoluteNode, TextNode, LayoutBox, Rect, EdgeSizes};
+use css::{Value, Unit};
+use style::{Position, json_string};
 use std::iter::{repeat, range};
+use std::cmp::max;
 use std::num::Float;
 use color::{Color};
 
@@ -13,34 +15,108 @@ use freetype::freetype::{FT_Set_Transform, FT_Matrix, struct_FT_Matrix_};
 use freetype::freetype::{FT_Load_Char, FT_LOAD_RENDER};
 use freetype::freetype::{FT_Bitmap, FT_Int, FT_Set_Pixel_Sizes};
 
-use font::{TextDecoration, FontInfo, Glyph, Text_Dimension, get_glyph, calculate_text_dimension, kerning_offset};
+use font::{cached_face, TextDecoration, FontInfo, Glyph, Text_Dimension, get_glyph, calculate_text_dimension, kerning_offset};
 
 use std::mem;
 use std::ptr;
 use std::slice;
 use std::default::Default;
 
-#[derive(Default, Show)]
+#[derive(Default, Show, Clone)]
 pub struct Canvas {
     pub pixels: Vec<Color>,
     pub width: usize,
     pub height: usize,
+    /// Blend anti-aliased text in linear light instead of sRGB.
+    pub gamma_correct: bool,
 }
 
 /// Paint a tree of LayoutBoxes to an array of pixels.
-pub fn paint(layout_root: &LayoutBox, bounds: Rect, background_color: Color) -> Canvas {
+pub fn paint(layout_root: &LayoutBox, bounds: Rect, background_color: Color, gamma_correct: bool, checkerboard: bool) -> Canvas {
     let display_list = build_display_list(layout_root);
     let mut canvas = Canvas::new(bounds.width as usize, bounds.height as usize, background_color);
+    canvas.gamma_correct = gamma_correct;
+    if checkerboard {
+        canvas.fill_checkerboard();
+    }
     for item in display_list.iter() {
         canvas.paint_item(item);
     }
     return canvas;
 }
 
+/// Paint only the subtree rooted at `layout_box`, cropped to everything it paints (see
+/// `LayoutBox::margin_box_union`) so that this area sits at the origin of the canvas.
+pub fn paint_cropped(layout_box: &LayoutBox, background_color: Color, gamma_correct: bool, checkerboard: bool) -> Canvas {
+    let bounds = layout_box.margin_box_union();
+    let mut canvas = Canvas::new(bounds.width as usize, bounds.height as usize, background_color);
+    canvas.gamma_correct = gamma_correct;
+    if checkerboard {
+        canvas.fill_checkerboard();
+    }
+    for item in build_display_list(layout_box).iter() {
+        canvas.paint_item(&item.translated(-bounds.x, -bounds.y));
+    }
+    return canvas;
+}
+
+/// The size in pixels of the squares drawn by `Canvas::fill_checkerboard`.
+static CHECKERBOARD_SQUARE: usize = 8;
+
 #[derive(Show)]
 enum DisplayCommand {
     SolidColor(Color, Rect),
     Text(String, Rect, FontInfo),
+    /// Commands painted into a layer of their own, which is filtered and then composited.
+    Filtered(Filter, DisplayList),
+}
+
+/// A `filter` function, with its amount.
+#[derive(Show)]
+enum Filter {
+    /// `grayscale()`: 1 replaces each color by its luminance.
+    Grayscale(f32),
+    /// `brightness()`: each channel is multiplied by the amount.
+    Brightness(f32),
+}
+
+impl Copy for Filter {}
+
+impl Filter {
+    fn apply(self, color: Color) -> Color {
+        match self {
+            Filter::Grayscale(amount) => {
+                let luminance = (0.2126 * color.r as f32 + 0.7152 * color.g as f32 + 0.0722 * color.b as f32).round() as u8;
+                color.lerp(Color::new(luminance, luminance, luminance, color.a), amount)
+            }
+            Filter::Brightness(amount) => {
+                let scale = |&: channel: u8| (channel as f32 * amount).min(255.0).round() as u8;
+                Color::new(scale(color.r), scale(color.g), scale(color.b), color.a)
+            }
+        }
+    }
+
+    /// The filter as CSS, for SVG output.
+    fn to_css(self) -> String {
+        match self {
+            Filter::Grayscale(amount) => format!("grayscale({})", amount),
+            Filter::Brightness(amount) => format!("brightness({})", amount),
+        }
+    }
+}
+
+impl DisplayCommand {
+    /// The same command, moved by `dx` and `dy`.
+    fn translated(&self, dx: f32, dy: f32) -> DisplayCommand {
+        match *self {
+            DisplayCommand::SolidColor(color, rect) =>
+                DisplayCommand::SolidColor(color, Rect { x: rect.x + dx, y: rect.y + dy, ..rect }),
+            DisplayCommand::Text(ref text, rect, ref font_info) =>
+                DisplayCommand::Text(text.clone(), Rect { x: rect.x + dx, y: rect.y + dy, ..rect }, font_info.clone()),
+            DisplayCommand::Filtered(filter, ref items) =>
+                DisplayCommand::Filtered(filter, items.iter().map(|item| item.translated(dx, dy)).collect()),
+        }
+    }
 }
 
 type DisplayList = Vec<DisplayCommand>;
@@ -52,27 +128,101 @@ fn build_display_list(layout_root: &LayoutBox) -> DisplayList {
 }
 
 fn render_layout_box(list: &mut DisplayList, layout_box: &LayoutBox) {
+    // A box with a `filter` is painted, along with its descendants, into a layer of its own.
+    match filter(layout_box) {
+        Some(filter) => {
+            let mut items = Vec::new();
+            render_unfiltered_layout_box(&mut items, layout_box);
+            list.push(DisplayCommand::Filtered(filter, items));
+        }
+        None => render_unfiltered_layout_box(list, layout_box),
+    }
+}
+
+fn render_unfiltered_layout_box(list: &mut DisplayList, layout_box: &LayoutBox) {
+    // Positioned children with a negative z-index paint behind this box's background.
+    let mut behind: Vec<&LayoutBox> = layout_box.children.iter().filter(|child| z_index(*child) < 0).collect();
+    behind.sort_by(|a, b| z_index(*a).cmp(&z_index(*b)));
+    for child in behind.iter() {
+        render_layout_box(list, *child);
+    }
+
     render_background(list, layout_box);
     render_borders(list, layout_box);
     render_text(list, layout_box);
 
     for child in layout_box.children.iter() {
         match child.box_type {
-            FloatNode(_) => continue,
+            FloatNode(_) | AbsoluteNode(_) => continue,
+            _ if z_index(child) < 0 => continue,
             _ => render_layout_box(list, child),
         };
     }
 
     for child in layout_box.children.iter() {
         if let FloatNode(style_node) = child.box_type {
-            render_layout_box(list, child);
+            if z_index(child) >= 0 {
+                render_layout_box(list, child);
+            }
+        }
+    }
+
+    // Absolutely positioned boxes paint on top of the normal flow and floats.
+    for child in layout_box.children.iter() {
+        if let AbsoluteNode(_) = child.box_type {
+            if z_index(child) >= 0 {
+                render_layout_box(list, child);
+            }
         }
     }
 }
 
+/// The z-index of a box, which only applies to positioned boxes.
+fn z_index(layout_box: &LayoutBox) -> i32 {
+    match layout_box.box_type {
+        BlockNode(style) | InlineNode(style) | InlineBlockNode(style) | FloatNode(style) | AbsoluteNode(style) => {
+            if style.position() == Position::Static { 0 } else { style.z_index() }
+        }
+        TextNode(_) | AnonymousBlock => 0
+    }
+}
+
+/// The box's `filter`.  Only a single `grayscale()` or `brightness()` function is supported; its
+/// amount is a number or a percentage, and defaults to 1.
+fn filter(layout_box: &LayoutBox) -> Option<Filter> {
+    match get_value(layout_box, "filter") {
+        Some(Value::Function(ref name, ref args)) => {
+            let amount = match args.first() {
+                Some(&Value::Length(f, Unit::Percent)) => f / 100.0,
+                Some(&Value::Length(f, _)) => f,
+                _ => 1.0
+            };
+            match name.as_slice() {
+                "grayscale" => Some(Filter::Grayscale(amount.max(0.0).min(1.0))),
+                "brightness" => Some(Filter::Brightness(amount.max(0.0))),
+                _ => None
+            }
+        }
+        _ => None
+    }
+}
+
 fn render_background(list: &mut DisplayList, layout_box: &LayoutBox) {
-    get_color(layout_box, "background-color").map(|color|
-        list.push(DisplayCommand::SolidColor(color, layout_box.dimensions.border_box())));
+    // Paint the base color, then any layers above it from the bottom up.
+    let mut colors: Vec<Color> = get_color(layout_box, "background-color").into_iter().collect();
+    if let Some(Value::List(layers)) = get_value(layout_box, "background-layers") {
+        for layer in layers.into_iter().rev() {
+            if let Value::ColorValue(color) = layer {
+                colors.push(color);
+            }
+        }
+    }
+
+    for color in colors.iter() {
+        for &(border_box, _) in border_boxes(layout_box).iter() {
+            list.push(DisplayCommand::SolidColor(*color, border_box));
+        }
+    }
 }
 
 fn render_borders(list: &mut DisplayList, layout_box: &LayoutBox) {
@@ -81,55 +231,175 @@ fn render_borders(list: &mut DisplayList, layout_box: &LayoutBox) {
         _ => return
     };
 
+    for &(border_box, border) in border_boxes(layout_box).iter() {
+        // Left border
+        list.push(DisplayCommand::SolidColor(color, Rect {
+            x: border_box.x,
+            y: border_box.y,
+            width: border.left,
+            height: border_box.height,
+        }));
+
+        // Right border
+        list.push(DisplayCommand::SolidColor(color, Rect {
+            x: border_box.x + border_box.width - border.right,
+            y: border_box.y,
+            width: border.right,
+            height: border_box.height,
+        }));
+
+        // Top border
+        list.push(DisplayCommand::SolidColor(color, Rect {
+            x: border_box.x,
+            y: border_box.y,
+            width: border_box.width,
+            height: border.top,
+        }));
+
+        // Bottom border
+        list.push(DisplayCommand::SolidColor(color, Rect {
+            x: border_box.x,
+            y: border_box.y + border_box.height - border.bottom,
+            width: border_box.width,
+            height: border.bottom,
+        }));
+    }
+}
+
+/// The border boxes to paint for a box, with the border widths of each.
+///
+/// An inline box that wraps across lines is painted as one fragment per line.  Its left padding
+/// and border only appear on the first fragment, and its right padding and border on the last.
+fn border_boxes(layout_box: &LayoutBox) -> Vec<(Rect, EdgeSizes)> {
     let d = &layout_box.dimensions;
-    let border_box = d.border_box();
-
-    // Left border
-    list.push(DisplayCommand::SolidColor(color, Rect {
-        x: border_box.x,
-        y: border_box.y,
-        width: d.border.left,
-        height: border_box.height,
-    }));
-
-    // Right border
-    list.push(DisplayCommand::SolidColor(color, Rect {
-        x: border_box.x + border_box.width - d.border.right,
-        y: border_box.y,
-        width: d.border.right,
-        height: border_box.height,
-    }));
-
-    // Top border
-    list.push(DisplayCommand::SolidColor(color, Rect {
-        x: border_box.x,
-        y: border_box.y,
-        width: border_box.width,
-        height: d.border.top,
-    }));
-
-    // Bottom border
-    list.push(DisplayCommand::SolidColor(color, Rect {
-        x: border_box.x,
-        y: border_box.y + border_box.height - d.border.bottom,
-        width: border_box.width,
-        height: d.border.bottom,
-    }));
+    let fragments = &layout_box.fragments;
+    if fragments.is_empty() {
+        return vec![(d.border_box(), d.border)];
+    }
+
+    fragments.iter().enumerate().map(|(i, content)| {
+        let mut padding = d.padding;
+        let mut border = d.border;
+        if i > 0 {
+            padding.left = 0.0;
+            border.left = 0.0;
+        }
+        if i < fragments.len() - 1 {
+            padding.right = 0.0;
+            border.right = 0.0;
+        }
+        (content.expanded_by(padding).expanded_by(border), border)
+    }).collect()
 }
 
 fn render_text(list: &mut DisplayList, layout_box: &LayoutBox) {
     if let TextNode(ref text) = layout_box.box_type {
-        list.push(DisplayCommand::Text(text.clone(), layout_box.dimensions.content, layout_box.font_info));
+        list.push(DisplayCommand::Text(text.clone(), layout_box.dimensions.content, layout_box.font_info.clone()));
+    }
+}
+
+impl<'a> LayoutBox<'a> {
+    /// Export the layout tree as an SVG document of size `bounds`.
+    ///
+    /// Each display command becomes one `<rect>` or `<text>` element, so the output is
+    /// resolution-independent and easy to inspect.
+    pub fn to_svg(&self, bounds: Rect) -> String {
+        let mut svg = format!("<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{}\" height=\"{}\">\n",
+                              bounds.width, bounds.height);
+        for item in build_display_list(self).iter() {
+            svg.push_str(svg_element(item).as_slice());
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+fn svg_element(item: &DisplayCommand) -> String {
+    match *item {
+        DisplayCommand::SolidColor(color, rect) => {
+            format!("<rect x=\"{}\" y=\"{}\" width=\"{}\" height=\"{}\" fill=\"rgb({},{},{})\" fill-opacity=\"{}\"/>\n",
+                    rect.x, rect.y, rect.width, rect.height, color.r, color.g, color.b, color.a as f32 / 255.0)
+        }
+        DisplayCommand::Text(ref text, rect, ref font_info) => {
+            // Text is painted vertically centered in its line box.
+            let bearing = (font_info.line_height - font_info.size) / 2;
+            let decoration = match font_info.deco {
+                TextDecoration::Underline => " text-decoration=\"underline\"",
+                TextDecoration::Overline => " text-decoration=\"overline\"",
+                TextDecoration::LineThrough => " text-decoration=\"line-through\"",
+                TextDecoration::Normal => "",
+            };
+            format!("<text x=\"{}\" y=\"{}\" dominant-baseline=\"text-before-edge\" font-size=\"{}\" fill=\"rgb({},{},{})\"{}>{}</text>\n",
+                    rect.x, rect.y + bearing as f32, font_info.size,
+                    font_info.color.r, font_info.color.g, font_info.color.b, decoration, escape_xml(text.as_slice()))
+        }
+        DisplayCommand::Filtered(filter, ref items) => {
+            let mut group = format!("<g style=\"filter: {}\">\n", filter.to_css());
+            for item in items.iter() {
+                group.push_str(svg_element(item).as_slice());
+            }
+            group.push_str("</g>\n");
+            group
+        }
+    }
+}
+
+/// Describe the commands that painting `layout_root` would run as a JSON array, in painting
+/// order.  Rectangles are in page coordinates.
+pub fn display_list_json(layout_root: &LayoutBox) -> String {
+    items_json(build_display_list(layout_root).as_slice())
+}
+
+fn items_json(items: &[DisplayCommand]) -> String {
+    let items: Vec<String> = items.iter().map(|item| command_json(item)).collect();
+    format!("[{}]", items.connect(", "))
+}
+
+fn command_json(item: &DisplayCommand) -> String {
+    match *item {
+        DisplayCommand::SolidColor(color, rect) =>
+            format!("{{\"type\": \"solid-color\", \"color\": {}, \"rect\": {}}}", color_json(color), rect_json(rect)),
+        DisplayCommand::Text(ref text, rect, ref font_info) =>
+            format!("{{\"type\": \"text\", \"text\": {}, \"rect\": {}, \"font-size\": {}, \"color\": {}}}",
+                    json_string(text.as_slice()), rect_json(rect), font_info.size, color_json(font_info.color)),
+        DisplayCommand::Filtered(filter, ref items) =>
+            format!("{{\"type\": \"filtered\", \"filter\": {}, \"items\": {}}}",
+                    json_string(filter.to_css().as_slice()), items_json(items.as_slice())),
     }
 }
 
+fn color_json(color: Color) -> String {
+    format!("[{}, {}, {}, {}]", color.r, color.g, color.b, color.a)
+}
+
+fn rect_json(rect: Rect) -> String {
+    format!("{{\"x\": {}, \"y\": {}, \"width\": {}, \"height\": {}}}", rect.x, rect.y, rect.width, rect.height)
+}
+
+fn escape_xml(text: &str) -> String {
+    let mut escaped = String::new();
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 /// Return the specified color for CSS property `name`, or None if no color was specified.
 fn get_color(layout_box: &LayoutBox, name: &str) -> Option<Color> {
+    match get_value(layout_box, name) {
+        Some(Value::ColorValue(color)) => Some(color),
+        _ => None
+    }
+}
+
+fn get_value(layout_box: &LayoutBox, name: &str) -> Option<Value> {
     match layout_box.box_type {
-        BlockNode(style) | InlineNode(style) | FloatNode(style) => match style.value(name) {
-            Some(Value::ColorValue(color)) => Some(color),
-            _ => None
-        },
+        BlockNode(style) | InlineNode(style) | InlineBlockNode(style) | FloatNode(style) | AbsoluteNode(style) => style.value(name),
         TextNode(_) | AnonymousBlock => None
     }
 }
@@ -141,90 +411,155 @@ impl Canvas {
             pixels: repeat(background_color).take(width * height).collect(),
             width: width,
             height: height,
+            gamma_correct: false,
+        }
+    }
+
+    /// Copy the part of the canvas inside `rect` into a new canvas.
+    ///
+    /// The rectangle is clamped to the canvas boundaries, so cropping outside them gives a smaller
+    /// (possibly empty) canvas rather than failing.
+    ///
+    /// `paint_cropped` doesn't use this, because it paints only the chosen subtree; cropping keeps
+    /// everything else the page painted in the area, as a screenshot would.  Nothing in robinson
+    /// needs that yet, so it is only exercised by tests for now.
+    #[allow(dead_code)]
+    pub fn crop(&self, rect: Rect) -> Canvas {
+        let x0 = rect.x.clamp(0.0, self.width as f32) as usize;
+        let y0 = rect.y.clamp(0.0, self.height as f32) as usize;
+        let x1 = max(x0, (rect.x + rect.width).clamp(0.0, self.width as f32) as usize);
+        let y1 = max(y0, (rect.y + rect.height).clamp(0.0, self.height as f32) as usize);
+
+        let mut cropped = Canvas::new(x1 - x0, y1 - y0, Default::default());
+        cropped.gamma_correct = self.gamma_correct;
+        for y in range(y0, y1) {
+            for x in range(x0, x1) {
+                cropped.pixels[(y - y0) * cropped.width + (x - x0)] = self.pixels[y * self.width + x];
+            }
+        }
+        return cropped;
+    }
+
+    /// Cover the whole canvas with a gray checkerboard, so that transparent areas painted over it
+    /// stay visible.
+    pub fn fill_checkerboard(&mut self) {
+        let light = Color::new(204, 204, 204, 255);
+        let dark = Color::new(153, 153, 153, 255);
+        for y in range(0, self.height) {
+            for x in range(0, self.width) {
+                let square = (x / CHECKERBOARD_SQUARE + y / CHECKERBOARD_SQUARE) % 2;
+                self.pixels[y * self.width + x] = if square == 0 { light } else { dark };
+            }
+        }
+    }
+
+    /// Composite `color` over `rect`.  Parts of the rectangle outside the canvas are clipped.
+    pub fn fill_rect(&mut self, rect: Rect, color: Color) {
+        if color.a == 0 {
+            return;
+        }
+
+        let x0 = rect.x.clamp(0.0, self.width as f32) as usize;
+        let y0 = rect.y.clamp(0.0, self.height as f32) as usize;
+        let x1 = (rect.x + rect.width).clamp(0.0, self.width as f32) as usize;
+        let y1 = (rect.y + rect.height).clamp(0.0, self.height as f32) as usize;
+
+        for y in range(y0, y1) {
+            for x in range(x0, x1) {
+                let idx = y * self.width + x;
+                self.pixels[idx] = color.blend_over(self.pixels[idx], self.gamma_correct);
+            }
         }
     }
 
     fn paint_item(&mut self, item: &DisplayCommand) {
         match item {
             &DisplayCommand::SolidColor(color, rect) => {
-                // Clip the rectangle to the canvas boundaries.
-                let x0 = rect.x.clamp(0.0, self.width as f32) as usize;
-                let y0 = rect.y.clamp(0.0, self.height as f32) as usize;
-                let x1 = (rect.x + rect.width).clamp(0.0, self.width as f32) as usize;
-                let y1 = (rect.y + rect.height).clamp(0.0, self.height as f32) as usize;
-
-                for y in range(y0, y1) {
-                    for x in range(x0, x1) {
-                        // TODO: alpha compositing with existing pixel
-                        self.pixels[y * self.width + x] = color;
-                    }
-                }
+                self.fill_rect(rect, color);
             },
             &DisplayCommand::Text(ref string, ref rect, ref font_info) => {
                 self.paint_text(string.as_slice(), rect, font_info);
             }
+            &DisplayCommand::Filtered(filter, ref items) => {
+                let mut layer = Canvas::new(self.width, self.height, Default::default());
+                layer.gamma_correct = self.gamma_correct;
+                for item in items.iter() {
+                    layer.paint_item(item);
+                }
+                for pixel in layer.pixels.iter_mut() {
+                    *pixel = filter.apply(*pixel);
+                }
+                self.composite(&layer);
+            }
         }
     }
 
-    fn paint_text(&mut self, string: &str, rect: &Rect, font_info: &FontInfo) {
-        let handle = FontContextHandle::new();
-        let start_idx = rect.y as usize * self.width + rect.x as usize;
-
-        unsafe {
-            let mut face: FT_Face = ptr::null_mut();
-            let mut error: FT_Error;
-            let filename = "./examples/verdana.ttf".as_ptr() as *mut i8;
-            error = FT_New_Face(handle.ctx.ctx, filename, 0, &mut face);
-
-            if error != 0 || face.is_null() {
-                println!("failed to new face");
-                return;
-            }
+    /// Composite `layer`, a canvas of the same size, over this one.
+    pub fn composite(&mut self, layer: &Canvas) {
+        let gamma_correct = self.gamma_correct;
+        for (pixel, &src) in self.pixels.iter_mut().zip(layer.pixels.iter()) {
+            *pixel = src.blend_over(*pixel, gamma_correct);
+        }
+    }
 
-            error = FT_Set_Pixel_Sizes(face, 0, font_info.size as u32);
-            if error != 0 {
-                println!("failed to set pixel size: {}", font_info.size);
-                return;
-            }
+    fn paint_text(&mut self, string: &str, rect: &Rect, font_info: &FontInfo) {
+        let face = match cached_face(font_info) {
+            Some(face) => face,
+            None => return
+        };
 
-            let mut text_dimension = calculate_text_dimension(string.as_slice(), &face);
-            text_dimension.height = font_info.size;
-            text_dimension.baseline = calculate_text_dimension("g", &face).baseline;
+        let mut text_dimension = calculate_text_dimension(string.as_slice(), &face);
+        text_dimension.height = font_info.size;
+        text_dimension.baseline = calculate_text_dimension("g", &face).baseline;
 
-            let mut pen = struct_FT_Vector_ { x: 0, y: 0 };
-            let mut c: char;
-            let mut pc: char = 0 as char;
+        let mut pen = struct_FT_Vector_ { x: 0, y: 0 };
+        let mut c: char;
+        let mut pc: char = 0 as char;
 
-            let mut text_canvas = Canvas::new(text_dimension.width as usize, font_info.line_height as usize, Color { r: 0, g: 0, b: 0, a: 0 });
+        let mut text_canvas = Canvas::new(text_dimension.width as usize, font_info.line_height as usize, Color { r: 0, g: 0, b: 0, a: 0 });
+        let bearing = (font_info.line_height - text_dimension.height) / 2;
 
-            for c in string.chars() {
-                let glyph = get_glyph(c, &face, true);
+        for c in string.chars() {
+            let glyph = get_glyph(c, &face, true);
 
-                pen.x += kerning_offset(c, pc, &face) as i64;
+            pen.x += kerning_offset(c, pc, &face) as i64;
 
-                let bearing = (font_info.line_height - text_dimension.height) / 2;
-                pen.y = (font_info.line_height - glyph.ascent - text_dimension.baseline - bearing) as i64;
+            pen.y = (font_info.line_height - glyph.ascent - text_dimension.baseline - bearing) as i64;
 
-                text_canvas.paint_char(&glyph, pen.x, pen.y, &text_dimension);
+            text_canvas.paint_char(&glyph, pen.x, pen.y, &text_dimension);
 
-                pen.x += glyph.advance_width as i64;
+            pen.x += glyph.advance_width as i64;
 
-                pc = c;
-            }
-
-            text_canvas.paint_text_decoration(font_info);
+            pc = c;
+        }
 
-            for y in range(0, text_canvas.height) {
-                for x in range(0, text_canvas.width) {
-                    let src_col = text_canvas.pixels[y * text_canvas.width + x];
-                    let dst_col = self.pixels[start_idx + y * self.width + x];
+        // An overline runs along the top of the text, and a line-through halfway up its
+        // lowercase letters.
+        let baseline_y = font_info.line_height - text_dimension.baseline - bearing;
+        let x_height = get_glyph('x', &face, false).ascent;
+        text_canvas.paint_text_decoration(font_info, bearing, baseline_y - x_height / 2);
 
-                    let dst: &mut Color = &mut self.pixels[start_idx + y * self.width + x];
+        // The shadow is the same glyphs in another color, painted underneath.
+        if let Some(shadow) = font_info.shadow {
+            self.blend_coverage(&text_canvas, rect.x as i32 + shadow.offset_x, rect.y as i32 + shadow.offset_y, shadow.color);
+        }
+        self.blend_coverage(&text_canvas, rect.x as i32, rect.y as i32, font_info.color);
+    }
 
-                    dst.r = ((dst_col.r as f32 * (255 - src_col.a) as f32 / 255.0) + (font_info.color.r as f32 * src_col.a as f32 / 255.0)) as u8;
-                    dst.g = ((dst_col.g as f32 * (255 - src_col.a) as f32 / 255.0) + (font_info.color.g as f32 * src_col.a as f32 / 255.0)) as u8;
-                    dst.b = ((dst_col.b as f32 * (255 - src_col.a) as f32 / 255.0) + (font_info.color.b as f32 * src_col.a as f32 / 255.0)) as u8;
+    /// Blend `color` into the canvas at (`x`, `y`), using the alpha channel of `mask` as coverage.
+    /// Parts of the mask outside the canvas are clipped.
+    fn blend_coverage(&mut self, mask: &Canvas, x: i32, y: i32, color: Color) {
+        for my in range(0, mask.height) {
+            for mx in range(0, mask.width) {
+                let (dx, dy) = (x + mx as i32, y + my as i32);
+                if dx < 0 || dy < 0 || dx as usize >= self.width || dy as usize >= self.height {
+                    continue;
                 }
+
+                let coverage = mask.pixels[my * mask.width + mx].a;
+                let src = Color { a: coverage, ..color };
+                let idx = dy as usize * self.width + dx as usize;
+                self.pixels[idx] = src.blend_over(self.pixels[idx], self.gamma_correct);
             }
         }
     }
@@ -246,12 +581,20 @@ impl Canvas {
         }
     }
 
-    fn paint_text_decoration(&mut self, font_info: &FontInfo) {
-        if font_info.deco != TextDecoration::Underline {
+    /// Draw the text's decoration line.  `overline_y` and `line_through_y` are the rows of the
+    /// overline and line-through; an underline is drawn near the bottom of the line.
+    fn paint_text_decoration(&mut self, font_info: &FontInfo, overline_y: i32, line_through_y: i32) {
+        let y = match font_info.deco {
+            TextDecoration::Underline => self.height as i32 - 2,
+            TextDecoration::Overline => overline_y,
+            TextDecoration::LineThrough => line_through_y,
+            TextDecoration::Normal => return,
+        };
+        if y < 0 || y >= self.height as i32 {
             return;
         }
 
-        let pos = (self.width * (self.height - 2)) as usize;
+        let pos = self.width * y as usize;
         for i in range(0, self.width) {
             self.pixels[pos + i] = font_info.color;
         }
@@ -264,3 +607,289 @@ trait FloatClamp : Float {
     }
 }
 impl<T: Float> FloatClamp for T {}
+
+#[cfg(test)]
+mod tests {
+    use color::Color;
+    use css;
+    use font::{cached_face, normal_line_height};
+    use layout::{Rect, LayoutBox, TextNode};
+    use render::{render, RenderOptions, RenderResult};
+    use std::iter::range;
+    use super::{paint_cropped, build_display_list, Canvas, DisplayCommand, CHECKERBOARD_SQUARE};
+
+    /// Render `html` with `css` in a 200x100 viewport.
+    fn render_page(html: &str, css: &str) -> RenderResult {
+        let mut options = RenderOptions::new(Rect { x: 0.0, y: 0.0, width: 200.0, height: 100.0 });
+        options.css = Some(css.to_string());
+        render(html.to_string(), &options)
+    }
+
+    /// The first text box under `layout_box`.
+    fn first_text_box<'b>(layout_box: &'b LayoutBox<'b>) -> Option<&'b LayoutBox<'b>> {
+        match layout_box.box_type {
+            TextNode(_) => Some(layout_box),
+            _ => layout_box.children.iter().filter_map(|child| first_text_box(child)).next()
+        }
+    }
+
+    /// The rows of the first line of text on which every pixel is the text's color, as offsets
+    /// from the top of the line.
+    fn solid_rows(result: &RenderResult) -> Vec<usize> {
+        let layout = result.layout();
+        let text = first_text_box(&layout).unwrap();
+        let rect = text.dimensions.content;
+        let (x0, x1) = (rect.x as usize, (rect.x + rect.width) as usize);
+        range(rect.y as usize, (rect.y + rect.height) as usize).filter(|&y| {
+            range(x0, x1).all(|x| result.canvas.pixels[y * result.canvas.width + x] == text.font_info.color)
+        }).map(|y| y - rect.y as usize).collect()
+    }
+
+    #[test]
+    fn svg_has_a_rect_for_a_background_and_text_for_text() {
+        let result = render_page("<html><p>Hello & bye</p></html>",
+                                 "p { display: block; font-size: 16px; background-color: #00ff00 }");
+        let layout = result.layout();
+        let svg = layout.to_svg(Rect { x: 0.0, y: 0.0, width: 200.0, height: 100.0 });
+        assert!(svg.as_slice().starts_with("<svg "));
+        assert!(svg.as_slice().contains("<rect "));
+        assert!(svg.as_slice().contains("fill=\"rgb(0,255,0)\""));
+        assert!(svg.as_slice().contains("<text "));
+        assert!(svg.as_slice().contains("Hello &amp; bye"));
+    }
+
+    #[test]
+    fn negative_z_index_child_is_hidden_by_its_parent_background() {
+        let css = "div { display: block } \
+                   #parent { width: 100px; height: 50px; background-color: red } \
+                   #child { width: 20px; height: 20px; background-color: blue; position: relative }";
+        let html = "<html><div id=\"parent\"><div id=\"child\"></div></div></html>";
+        let red = Color::new(255, 0, 0, 255);
+        let blue = Color::new(0, 0, 255, 255);
+
+        let behind = render_page(html, format!("{} #child {{ z-index: -1 }}", css).as_slice());
+        assert_eq!(behind.canvas.pixels[10 * behind.canvas.width + 10], red);
+
+        let in_front = render_page(html, css);
+        assert_eq!(in_front.canvas.pixels[10 * in_front.canvas.width + 10], blue);
+    }
+
+    #[test]
+    fn cropped_canvas_is_the_size_of_the_selected_box() {
+        let result = render_page("<html><div></div><div class=\"card\"></div></html>",
+                                 "div { display: block; height: 10px } \
+                                  .card { width: 100px; height: 40px; margin: 5px; padding: 2px; background-color: blue }");
+        let selectors = css::parse_selectors(".card".to_string()).unwrap();
+        let layout = result.layout();
+        let card = layout.find_box(&selectors[0]).unwrap();
+        let canvas = paint_cropped(card, Color::new(255, 255, 255, 255), false, false);
+        assert_eq!((canvas.width, canvas.height), (114, 54));
+        // The card's margin is left unpainted around its background.
+        assert_eq!(canvas.pixels[0], Color::new(255, 255, 255, 255));
+        assert_eq!(canvas.pixels[5 * canvas.width + 5], Color::new(0, 0, 255, 255));
+    }
+
+    #[test]
+    fn text_shadow_is_painted_at_its_offset() {
+        let result = render_page("<html><p>I</p></html>",
+                                 "html { background-color: white } \
+                                  p { display: block; font-size: 16px; color: black; text-shadow: 30px 0px red }");
+        // The leftmost columns where the glyph and its shadow cover more than half a pixel.
+        let (mut text_x, mut shadow_x) = (None, None);
+        for x in range(0, result.canvas.width).rev() {
+            for y in range(0, result.canvas.height) {
+                let pixel = result.canvas.pixels[y * result.canvas.width + x];
+                if pixel.r < 128 && pixel.g < 128 {
+                    text_x = Some(x);
+                }
+                if pixel.r > 200 && pixel.g < 128 {
+                    shadow_x = Some(x);
+                }
+            }
+        }
+        assert!(text_x.is_some());
+        assert_eq!(shadow_x, Some(text_x.unwrap() + 30));
+    }
+
+    #[test]
+    fn fill_rect_layers_overlapping_rects_and_clips_them() {
+        let white = Color::new(255, 255, 255, 255);
+        let red = Color::new(255, 0, 0, 255);
+        let blue = Color::new(0, 0, 255, 255);
+        let mut canvas = Canvas::new(10, 10, white);
+        canvas.fill_rect(Rect { x: 0.0, y: 0.0, width: 6.0, height: 6.0 }, red);
+        canvas.fill_rect(Rect { x: 4.0, y: 4.0, width: 20.0, height: 20.0 }, blue);
+        // A transparent fill changes nothing.
+        canvas.fill_rect(Rect { x: 0.0, y: 0.0, width: 10.0, height: 10.0 }, Color::new(0, 255, 0, 0));
+
+        assert_eq!(canvas.pixels[1 * 10 + 1], red);
+        assert_eq!(canvas.pixels[5 * 10 + 5], blue);
+        assert_eq!(canvas.pixels[9 * 10 + 9], blue);
+        assert_eq!(canvas.pixels[1 * 10 + 8], white);
+        assert_eq!(canvas.pixels[8 * 10 + 1], white);
+    }
+
+    #[test]
+    fn translucent_box_blends_with_the_background() {
+        let result = render_page("<html><div></div></html>",
+                                 "html { background-color: white } \
+                                  div { display: block; width: 20px; height: 20px; background-color: #ff000080 }");
+        assert_eq!(result.canvas.pixels[10 * result.canvas.width + 10], Color::new(255, 127, 127, 255));
+        assert_eq!(result.canvas.pixels[10 * result.canvas.width + 50], Color::new(255, 255, 255, 255));
+    }
+
+    #[test]
+    fn background_layers_are_painted_over_the_base_color() {
+        let result = render_page("<html><div></div></html>",
+                                 "html { background-color: black } \
+                                  div { display: block; width: 20px; height: 20px; background: #ff000080, white }");
+        assert_eq!(result.canvas.pixels[10 * result.canvas.width + 10], Color::new(255, 127, 127, 255));
+        assert_eq!(result.canvas.pixels[10 * result.canvas.width + 50], Color::new(0, 0, 0, 255));
+    }
+
+    #[test]
+    fn single_value_background_clears_earlier_layers() {
+        let result = render_page("<html><div class=\"plain\"></div></html>",
+                                 "div { display: block; width: 20px; height: 20px; background: red, blue } \
+                                  .plain { background: #00ff00 }");
+        let layout = result.layout();
+        let div = &layout.children[0];
+        assert_eq!(div.get_style_node().value("background-layers"), Some(css::Value::List(vec![])));
+        assert_eq!(result.canvas.pixels[10 * result.canvas.width + 10], Color::new(0, 255, 0, 255));
+    }
+
+    #[test]
+    fn crop_copies_the_corners_and_clamps_to_the_canvas() {
+        let mut canvas = Canvas::new(10, 10, Color::new(255, 255, 255, 255));
+        for y in range(0, 10us) {
+            for x in range(0, 10us) {
+                canvas.pixels[y * 10 + x] = Color::new(x as u8, y as u8, 0, 255);
+            }
+        }
+
+        let cropped = canvas.crop(Rect { x: 2.0, y: 3.0, width: 4.0, height: 5.0 });
+        assert_eq!((cropped.width, cropped.height), (4, 5));
+        assert_eq!(cropped.pixels[0], Color::new(2, 3, 0, 255));
+        assert_eq!(cropped.pixels[3], Color::new(5, 3, 0, 255));
+        assert_eq!(cropped.pixels[4 * 4], Color::new(2, 7, 0, 255));
+        assert_eq!(cropped.pixels[4 * 4 + 3], Color::new(5, 7, 0, 255));
+
+        // Only the part inside the canvas is kept.
+        let clamped = canvas.crop(Rect { x: -5.0, y: 8.0, width: 8.0, height: 10.0 });
+        assert_eq!((clamped.width, clamped.height), (3, 2));
+        assert_eq!(clamped.pixels[0], Color::new(0, 8, 0, 255));
+        assert_eq!(clamped.pixels[2 * 3 - 1], Color::new(2, 9, 0, 255));
+
+        let outside = canvas.crop(Rect { x: 20.0, y: 20.0, width: 5.0, height: 5.0 });
+        assert_eq!((outside.width, outside.height), (0, 0));
+    }
+
+    #[test]
+    fn span_color_wins_over_the_paragraph_color_it_inherits_from() {
+        let result = render_page("<html><p><span>xxxx</span></p></html>",
+                                 "html { background-color: white } \
+                                  p { display: block; font-size: 16px; color: black } \
+                                  span { color: red }");
+        let layout = result.layout();
+        let text = first_text_box(&layout).unwrap();
+        assert_eq!(text.font_info.color, Color::new(255, 0, 0, 255));
+
+        let rect = text.dimensions.content;
+        let mut pixels = Vec::new();
+        for y in range(rect.y as usize, (rect.y + rect.height) as usize) {
+            for x in range(rect.x as usize, (rect.x + rect.width) as usize) {
+                pixels.push(result.canvas.pixels[y * result.canvas.width + x]);
+            }
+        }
+        // Red text blended over white keeps full red, and nothing is as dark as black text would be.
+        assert!(pixels.iter().any(|pixel| pixel.r == 255 && pixel.g < 128));
+        assert!(pixels.iter().all(|pixel| pixel.r == 255));
+    }
+
+    #[test]
+    fn text_without_line_height_uses_the_fonts_natural_one() {
+        let result = render_page("<html><p>Hello</p></html>",
+                                 "html { background-color: white } p { display: block; font-size: 16px; color: black }");
+        let layout = result.layout();
+        let text = first_text_box(&layout).unwrap();
+        let natural = normal_line_height(&cached_face(&text.font_info).unwrap());
+        assert!(natural > 0);
+        assert_eq!(text.dimensions.content.height, natural as f32);
+
+        let rect = text.dimensions.content;
+        let white = Color::new(255, 255, 255, 255);
+        let mut painted = 0us;
+        for y in range(rect.y as usize, (rect.y + rect.height) as usize) {
+            for x in range(rect.x as usize, (rect.x + rect.width) as usize) {
+                if result.canvas.pixels[y * result.canvas.width + x] != white {
+                    painted += 1;
+                }
+            }
+        }
+        assert!(painted > 0);
+    }
+
+    #[test]
+    fn checkerboard_alternates_where_nothing_is_painted() {
+        let mut options = RenderOptions::new(Rect { x: 0.0, y: 0.0, width: 40.0, height: 40.0 });
+        options.css = Some("div { display: block; margin-top: 20px; width: 10px; height: 10px; background-color: red }".to_string());
+        options.checkerboard = true;
+        let result = render("<html><div></div></html>".to_string(), &options);
+        let pixel = |&: x: usize, y: usize| result.canvas.pixels[y * result.canvas.width + x];
+
+        let square = CHECKERBOARD_SQUARE;
+        assert!(pixel(0, 0) != pixel(square, 0));
+        assert!(pixel(0, 0) != pixel(0, square));
+        assert_eq!(pixel(0, 0), pixel(square, square));
+        assert_eq!(pixel(0, 0), pixel(square - 1, square - 1));
+        assert_eq!(pixel(5, 25), Color::new(255, 0, 0, 255));
+    }
+
+    #[test]
+    fn overline_and_line_through_are_drawn_above_the_underline() {
+        let css = "p { display: block; font-size: 16px; line-height: 24px; color: black }";
+        let decorated = |&: decoration: &str| -> Vec<usize> {
+            let result = render_page("<html><p>xxxx</p></html>",
+                                     format!("{} p {{ text-decoration: {} }}", css, decoration).as_slice());
+            solid_rows(&result)
+        };
+        assert!(decorated("none").is_empty());
+
+        let overline = decorated("overline");
+        let line_through = decorated("line-through");
+        let underline = decorated("underline");
+        assert_eq!((overline.len(), line_through.len(), underline.len()), (1, 1, 1));
+        assert!(overline[0] < line_through[0]);
+        assert!(line_through[0] < underline[0]);
+    }
+
+    #[test]
+    fn text_command_has_the_resolved_font_size() {
+        let result = render_page("<html><div><p>em</p><p id=\"pct\">percent</p></div></html>",
+                                 "div { display: block; font-size: 16px } p { display: block; font-size: 1.5em } \
+                                  #pct { font-size: 50% }");
+        let layout = result.layout();
+        let sizes: Vec<i32> = build_display_list(&layout).iter().filter_map(|item| match *item {
+            DisplayCommand::Text(_, _, ref font_info) => Some(font_info.size),
+            _ => None
+        }).collect();
+        assert_eq!(sizes, vec![24, 8]);
+    }
+
+    #[test]
+    fn grayscale_filter_turns_a_box_gray() {
+        let result = render_page("<html><div id=\"gray\"></div><div id=\"dim\"></div><div></div></html>",
+                                 "div { display: block; width: 20px; height: 10px; background-color: #ff8000 } \
+                                  #gray { filter: grayscale(1) } #dim { filter: brightness(50%) }");
+        let pixel = |&: y: usize| result.canvas.pixels[y * result.canvas.width + 5];
+
+        let gray = pixel(5);
+        assert_eq!(gray.r, gray.g);
+        assert_eq!(gray.g, gray.b);
+        assert!(gray.r > 0 && gray.r < 255);
+
+        assert_eq!(pixel(15), Color::new(128, 64, 0, 255));
+        // The unfiltered box keeps its color.
+        assert_eq!(pixel(25), Color::new(255, 128, 0, 255));
+    }
+}
@@ -0,0 +1,66 @@
+//! The CSS properties that robinson understands.
+
+/// Every longhand property used by styling, layout, or painting, and whether it is inherited by
+/// default.  Shorthands are expanded into these when they are parsed.
+pub static PROPERTIES: [(&'static str, bool); 54] = [
+    ("display", false),
+    ("position", false),
+    ("float", false),
+    ("clear", false),
+    ("z-index", false),
+    ("overflow", false),
+    ("width", false),
+    ("height", false),
+    ("min-width", false),
+    ("max-width", false),
+    ("min-height", false),
+    ("max-height", false),
+    ("box-sizing", false),
+    ("top", false),
+    ("left", false),
+    ("margin-top", false),
+    ("margin-bottom", false),
+    ("margin-left", false),
+    ("margin-right", false),
+    ("padding-top", false),
+    ("padding-bottom", false),
+    ("padding-left", false),
+    ("padding-right", false),
+    ("border-top-width", false),
+    ("border-bottom-width", false),
+    ("border-left-width", false),
+    ("border-right-width", false),
+    ("border-style", false),
+    ("border-color", false),
+    ("background-color", false),
+    ("background-layers", false),
+    ("filter", false),
+    ("color", true),
+    ("font-size", true),
+    ("font-family", true),
+    ("font-weight", true),
+    ("font-style", true),
+    ("font-variant-ligatures", true),
+    ("font-feature-settings", true),
+    ("overflow-wrap", true),
+    ("white-space", true),
+    // Inherited here, unlike in CSS, because text boxes only see their inherited values.
+    ("text-overflow", true),
+    ("line-height", true),
+    ("text-align", true),
+    ("direction", true),
+    ("caption-side", true),
+    ("text-decoration", true),
+    ("text-shadow-offset-x", true),
+    ("text-shadow-offset-y", true),
+    ("text-shadow-blur", true),
+    ("text-shadow-color", true),
+    ("pointer-events", true),
+    ("animation-name", false),
+    ("animation-duration", false),
+];
+
+/// Is `name` a property that is inherited by default?
+pub fn is_inherited(name: &str) -> bool {
+    PROPERTIES.iter().any(|&(property, inherited)| inherited && property == name)
+}
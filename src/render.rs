@@ -0,0 +1,259 @@
+//! Rendering a whole page in one call.
+//!
+//! `RenderResult` owns the document and its styles alongside the painted canvas, for embedders
+//! that want to draw overlays or hit-test the page.  The layout tree borrows the style tree, so
+//! rather than being stored next to it, it is built again when asked for.
+
+use std::collections::HashMap;
+use std::default::Default;
+use std::io::stderr;
+use std::rc::Rc;
+use std::time::Duration;
+
+use animation;
+use color::Color;
+use css;
+use css::{Stylesheet, LengthContext};
+use dom;
+use dom::Node;
+use font::FontConfig;
+use html;
+use layout;
+use layout::{LayoutBox, Dimensions, Rect};
+use painting;
+use painting::Canvas;
+use style;
+use style::StyledNode;
+
+/// How to render a page.
+pub struct RenderOptions {
+    /// The size of the viewport, which is also the size of the canvas.
+    pub viewport: Rect,
+    /// An external stylesheet, applied before the page's own `<style>` element.
+    pub css: Option<String>,
+    /// A stylesheet applied after the page's own styles, so that it wins over them, such as one
+    /// that simulates `:hover`.
+    pub state_css: Option<String>,
+    /// Render animations as they appear this many seconds after they started.
+    pub time: Option<f32>,
+    /// The canvas background color, instead of the root element's background.
+    pub background: Option<Color>,
+    pub gamma_correct: bool,
+    pub checkerboard: bool,
+    /// Print the time spent in each phase of rendering to stderr.
+    pub profile: bool,
+    pub fonts: Rc<FontConfig>,
+}
+
+impl RenderOptions {
+    /// The default options for a viewport of this size.
+    pub fn new(viewport: Rect) -> RenderOptions {
+        RenderOptions {
+            viewport: viewport,
+            css: None,
+            state_css: None,
+            time: None,
+            background: None,
+            gamma_correct: false,
+            checkerboard: false,
+            profile: false,
+            fonts: Rc::new(Default::default()),
+        }
+    }
+}
+
+/// A rendered page: its pixels, and the document, styles, and boxes they were painted from.
+pub struct RenderResult {
+    pub canvas: Canvas,
+    pub document: Rc<Node>,
+    pub stylesheet: Stylesheet,
+    style_root: StyledNode<'static>,
+    viewport: Rect,
+    fonts: Rc<FontConfig>,
+}
+
+impl RenderResult {
+    /// Lay out the page again from its styles.  This gives the same boxes that were painted.
+    pub fn layout<'b>(&'b self) -> LayoutBox<'b> {
+        layout_page(&self.style_root, self.viewport, self.fonts.clone())
+    }
+
+    /// The root of the style tree.
+    pub fn style_root<'b>(&'b self) -> &'b StyledNode<'b> {
+        &self.style_root
+    }
+}
+
+/// Parse, style, lay out, and paint `html`.
+///
+/// The page is styled with `options.css`, if any, followed by its own `<style>` element and then
+/// `options.state_css`.
+pub fn render(html: String, options: &RenderOptions) -> RenderResult {
+    let (document, stylesheet) = timed(options.profile, "parse", || {
+        let document = html::parse(html);
+        dom::set_parents(&document);
+        let mut stylesheet = page_stylesheet(&options.css, dom::find_style(&document).as_slice());
+        if let Some(ref state_css) = options.state_css {
+            stylesheet.merge(parse_stylesheet(state_css.clone()));
+        }
+        (document, stylesheet)
+    });
+
+    let style_root = timed(options.profile, "style", || {
+        let context = LengthContext::new(options.viewport.width, options.viewport.height);
+        let mut style_root = style::style_tree(&document, &stylesheet, &HashMap::new(), &context);
+        if let Some(time) = options.time {
+            animation::apply_animations(&mut style_root, stylesheet.keyframes.as_slice(), time, &context);
+        }
+        style_root
+    });
+
+    let background = options.background.unwrap_or_else(|| style_root.background_color());
+    let canvas = {
+        let layout = timed(options.profile, "layout", || {
+            layout_page(&style_root, options.viewport, options.fonts.clone())
+        });
+        timed(options.profile, "paint", || {
+            painting::paint(&layout, options.viewport, background, options.gamma_correct, options.checkerboard)
+        })
+    };
+    RenderResult {
+        canvas: canvas,
+        document: document,
+        stylesheet: stylesheet,
+        style_root: style_root,
+        viewport: options.viewport,
+        fonts: options.fonts.clone(),
+    }
+}
+
+/// Lay out a styled page in a viewport.
+fn layout_page<'a>(style_root: &'a StyledNode<'a>, viewport: Rect, fonts: Rc<FontConfig>) -> LayoutBox<'a> {
+    let mut containing_block: Dimensions = Default::default();
+    containing_block.content = viewport;
+    layout::layout_tree(style_root, containing_block, fonts)
+}
+
+/// Run one phase of rendering.  With `profile`, print how long it took to stderr.
+fn timed<T, F: FnOnce() -> T>(profile: bool, phase: &str, f: F) -> T {
+    let mut result = None;
+    let duration = Duration::span(|| result = Some(f()));
+    if profile {
+        let _ = stderr().write_line(format!("{}: {} ms", phase, duration.num_milliseconds()).as_slice());
+    }
+    result.unwrap()
+}
+
+/// Parse the page's styles: the external stylesheet, if any, followed by the document's own
+/// `<style>` element, which wins over it on equal specificity.
+pub fn page_stylesheet(external_css: &Option<String>, style_element: &str) -> css::Stylesheet {
+    let mut stylesheet = match *external_css {
+        Some(ref source) => parse_stylesheet(source.clone()),
+        None => return parse_stylesheet(style_element.to_string())
+    };
+    stylesheet.merge(parse_stylesheet(style_element.to_string()));
+    stylesheet
+}
+
+/// Parse a stylesheet, printing its syntax errors to stderr.  The rules around an error are
+/// still used.
+pub fn parse_stylesheet(source: String) -> css::Stylesheet {
+    let (stylesheet, errors) = css::parse(source);
+    for error in errors.iter() {
+        let _ = stderr().write_line(format!("CSS error at byte {}: {}", error.position, error.message).as_slice());
+    }
+    stylesheet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{render, RenderOptions};
+    use color;
+    use color::Color;
+    use dom;
+    use layout::Rect;
+
+    #[test]
+    fn hit_test_finds_the_box_painted_at_a_pixel() {
+        let mut options = RenderOptions::new(Rect { x: 0.0, y: 0.0, width: 100.0, height: 100.0 });
+        options.css = Some("html { background-color: white } \
+                            #target { display: block; margin-left: 20px; width: 30px; height: 10px; background-color: red }".to_string());
+        let result = render("<html><div id=\"target\"></div></html>".to_string(), &options);
+
+        let (x, y) = (25us, 5us);
+        assert_eq!(result.canvas.pixels[y * result.canvas.width + x], Color::new(255, 0, 0, 255));
+        let layout = result.layout();
+        let hit = layout.hit_test(x as f32, y as f32).unwrap();
+        assert!(dom::same_node(&hit.get_style_node().node, &result.document.children[0]));
+
+        // Left of the box, the page shows through.
+        assert_eq!(result.canvas.pixels[y * result.canvas.width + 10], Color::new(255, 255, 255, 255));
+        let miss = layout.hit_test(10.0, y as f32).unwrap();
+        assert!(dom::same_node(&miss.get_style_node().node, &result.document));
+    }
+
+    #[test]
+    fn transparent_background_leaves_unpainted_pixels_clear() {
+        let mut options = RenderOptions::new(Rect { x: 0.0, y: 0.0, width: 100.0, height: 100.0 });
+        options.css = Some("html { background-color: white } \
+                            div { display: block; width: 30px; height: 10px; background-color: red }".to_string());
+        options.background = color::parse("transparent");
+        let result = render("<html><div></div></html>".to_string(), &options);
+
+        assert_eq!(result.canvas.pixels[5 * result.canvas.width + 5], Color::new(255, 0, 0, 255));
+        assert_eq!(result.canvas.pixels[50 * result.canvas.width + 50].a, 0);
+    }
+
+    #[test]
+    fn hit_test_passes_through_pointer_events_none() {
+        let mut options = RenderOptions::new(Rect { x: 0.0, y: 0.0, width: 100.0, height: 100.0 });
+        options.css = Some("div { display: block; width: 50px; height: 50px } \
+                            #overlay { position: absolute; top: 0px; left: 0px; pointer-events: none }".to_string());
+        let result = render("<html><div id=\"below\"></div><div id=\"overlay\">text</div></html>".to_string(), &options);
+
+        let layout = result.layout();
+        let hit = layout.hit_test(10.0, 10.0).unwrap();
+        assert!(dom::same_node(&hit.get_style_node().node, &result.document.children[0]));
+
+        // Without `pointer-events: none`, the overlay is hit.
+        options.css = Some("div { display: block; width: 50px; height: 50px } \
+                            #overlay { position: absolute; top: 0px; left: 0px }".to_string());
+        let result = render("<html><div id=\"below\"></div><div id=\"overlay\"></div></html>".to_string(), &options);
+        let layout = result.layout();
+        let hit = layout.hit_test(10.0, 10.0).unwrap();
+        assert!(dom::same_node(&hit.get_style_node().node, &result.document.children[1]));
+    }
+
+    #[test]
+    fn time_halfway_through_an_animation_gives_the_midpoint_width() {
+        let mut options = RenderOptions::new(Rect { x: 0.0, y: 0.0, width: 400.0, height: 100.0 });
+        options.css = Some("@keyframes grow { from { width: 100px } to { width: 300px } } \
+                            div { display: block; height: 10px; animation-name: grow; animation-duration: 2s }".to_string());
+        options.time = Some(1.0);
+        let result = render("<html><div></div></html>".to_string(), &options);
+        let layout = result.layout();
+        assert_eq!(layout.children[0].dimensions.content.width, 200.0);
+
+        options.time = Some(5.0);
+        let result = render("<html><div></div></html>".to_string(), &options);
+        let layout = result.layout();
+        assert_eq!(layout.children[0].dimensions.content.width, 300.0);
+    }
+
+    #[test]
+    fn state_stylesheet_changes_only_the_state_render() {
+        let mut options = RenderOptions::new(Rect { x: 0.0, y: 0.0, width: 100.0, height: 100.0 });
+        options.css = Some("div { display: block; width: 20px; height: 20px; background-color: red }".to_string());
+        let html = "<html><div></div></html>";
+        let normal = render(html.to_string(), &options);
+
+        options.state_css = Some("div { background-color: blue }".to_string());
+        let state = render(html.to_string(), &options);
+
+        assert_eq!(normal.canvas.pixels[10 * normal.canvas.width + 10], Color::new(255, 0, 0, 255));
+        assert_eq!(state.canvas.pixels[10 * state.canvas.width + 10], Color::new(0, 0, 255, 255));
+        // Properties it doesn't mention are left alone.
+        let state_layout = state.layout();
+        assert_eq!(state_layout.children[0].dimensions.content.width, 20.0);
+    }
+}
@@ -1,12 +1,14 @@
 
 use std::cmp::min;
-use css::{Value, Declaration};
+use css::{Value, Declaration, ParseError};
+use properties::PROPERTIES;
 
-static SHORTHAND: [&'static str; 4] = ["border", "border-width", "margin", "padding"];
+static SHORTHAND: [&'static str; 7] = ["all", "background", "border", "border-width", "margin", "padding", "text-shadow"];
 
 static BORDER_WIDTH_PROPERTIES: [&'static str; 4] = ["border-top-width", "border-bottom-width", "border-left-width", "border-right-width"];
 static MARGIN_PROPERTIES: [&'static str; 4] = ["margin-top", "margin-bottom", "margin-left", "margin-right"];
 static PADDING_PROPERTIES: [&'static str; 4] = ["padding-top", "padding-bottom", "padding-left", "padding-right"];
+static TEXT_SHADOW_LENGTHS: [&'static str; 3] = ["text-shadow-offset-x", "text-shadow-offset-y", "text-shadow-blur"];
 static ORDER: [[usize; 4]; 4] = [[0, 0, 0, 0], [0, 0, 1, 1], [0, 2, 1, 1], [0, 2, 3, 1]];
 
 
@@ -14,35 +16,107 @@ pub fn is_shorthand(name: &str) -> bool {
     SHORTHAND.contains(&name)
 }
 
-pub fn parse_shorthand(name: &str, values: Vec<Value>) -> Vec<Declaration> {
-    match name {
+/// Expand a shorthand into longhand declarations.  `groups` holds the comma-separated groups of
+/// values; only `background` accepts more than one.
+///
+/// A group with no values, as in `margin: ;`, is an error at `position`, where the value starts.
+pub fn parse_shorthand(name: &str, mut groups: Vec<Vec<Value>>, position: usize) -> Result<Vec<Declaration>, ParseError> {
+    if groups.iter().any(|group| group.is_empty()) {
+        return Err(ParseError { position: position, message: format!("Expected a value for {}", name) });
+    }
+    if name == "background" {
+        return Ok(parse_background_shorthand(groups));
+    }
+    let values = groups.swap_remove(0);
+    Ok(match name {
+        "all" => parse_all_shorthand(values),
         "border" => parse_border_shorthand(values),
         "border-width" => parse_direction_shorthand(values, &BORDER_WIDTH_PROPERTIES),
         "margin" => parse_direction_shorthand(values, &MARGIN_PROPERTIES),
         "padding" => parse_direction_shorthand(values, &PADDING_PROPERTIES),
+        "text-shadow" => parse_text_shadow_shorthand(values),
         _ => panic!("Not shorthand"),
+    })
+}
+
+/// `all: initial | inherit | unset` resets every known property except `direction`, which `all`
+/// never affects.  Other values are ignored.
+fn parse_all_shorthand(values: Vec<Value>) -> Vec<Declaration> {
+    match values.as_slice() {
+        [Value::Keyword(ref keyword)] if ["initial", "inherit", "unset"].contains(&keyword.as_slice()) => {
+            PROPERTIES.iter().filter(|&&(name, _)| name != "direction").map(|&(name, _)| {
+                Declaration { name: name.to_string(), value: Value::Keyword(keyword.clone()), important: false }
+            }).collect()
+        }
+        _ => Vec::new()
+    }
+}
+
+/// `background: <layer>, ..., <final layer>`, where the first layer listed is painted on top.
+///
+/// Only colors are supported in each layer.  The final layer's color becomes `background-color`,
+/// and the layers above it are kept in order as a list in `background-layers`.
+fn parse_background_shorthand(groups: Vec<Vec<Value>>) -> Vec<Declaration> {
+    let mut colors: Vec<Value> = groups.into_iter().filter_map(|group| {
+        group.into_iter().find(|value| match *value {
+            Value::ColorValue(_) => true,
+            _ => false
+        })
+    }).collect();
+
+    let mut declarations = Vec::new();
+    if let Some(base) = colors.pop() {
+        declarations.push(Declaration { name: "background-color".to_string(), value: base, important: false });
     }
+    declarations.push(Declaration { name: "background-layers".to_string(), value: Value::List(colors), important: false });
+    return declarations;
 }
 
 fn parse_border_shorthand(values: Vec<Value>) -> Vec<Declaration> {
     let mut declaration = Vec::new();
     for val in values.into_iter() {
         let decl_name = match val {
-            Value::Length(_, _) => "border-width",
+            // Expand the width into longhands, so that it overrides an earlier `border-*-width`
+            // declaration in the same block instead of being shadowed by it.
+            Value::Length(_, _) => {
+                declaration.extend(parse_direction_shorthand(vec![val], &BORDER_WIDTH_PROPERTIES).into_iter());
+                continue;
+            }
             Value::Keyword(_) => "border-style",
             Value::ColorValue(_) => "border-color",
+            Value::List(_) | Value::Function(_, _) => continue,
         };
-        declaration.push(Declaration { name: decl_name.to_string(), value: val });
+        declaration.push(Declaration { name: decl_name.to_string(), value: val, important: false });
     }
     return declaration;
 }
 
+/// `text-shadow: <offset-x> <offset-y> [<blur>] [<color>]`, or `none`.
+fn parse_text_shadow_shorthand(values: Vec<Value>) -> Vec<Declaration> {
+    let mut declarations = Vec::new();
+    let mut lengths = TEXT_SHADOW_LENGTHS.iter();
+    for val in values.into_iter() {
+        let decl_name = match val {
+            Value::Length(_, _) => match lengths.next() {
+                Some(name) => *name,
+                None => continue,
+            },
+            // A color, or the keyword `none`.
+            Value::Keyword(_) | Value::ColorValue(_) => "text-shadow-color",
+            Value::List(_) | Value::Function(_, _) => continue,
+        };
+        declarations.push(Declaration { name: decl_name.to_string(), value: val, important: false });
+    }
+    return declarations;
+}
+
+/// Expand one to four values into the top, bottom, left, and right longhands in `property`.
+/// `values` must not be empty.
 fn parse_direction_shorthand(values: Vec<Value>, property: &[&str]) -> Vec<Declaration> {
-    assert!(!values.is_empty());
     let idx = ORDER[min(4, values.len()) - 1];
     let mut declarations = Vec::new();
     for i in range(0, 4) {
-        declarations.push(Declaration { name: property[i].to_string(), value: values[idx[i]].clone()});
+        declarations.push(Declaration { name: property[i].to_string(), value: values[idx[i]].clone(), important: false });
     }
     return declarations;
 }
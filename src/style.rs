@@ -4,7 +4,7 @@
 //! complicated if I add support for compound selectors.
 
 use dom::{Node, NodeType, ElementData};
-use css::{Stylesheet, Rule, Selector, SimpleSelector, Value, Specificity};
+use css::{Stylesheet, Rule, Selector, SimpleSelector, PseudoClass, Combinator, Declaration, Value, Specificity, Origin, LengthContext};
 use std::collections::HashMap;
 use std::rc::Rc;
 use std::rc::Weak;
@@ -12,6 +12,7 @@ use std::rc::Weak;
 use dom;
 use css;
 use color::{Color};
+use properties::is_inherited;
 
 /// Map from CSS property names to values.
 pub type PropertyMap =  HashMap<String, Value>;
@@ -21,12 +22,17 @@ pub struct StyledNode<'a> {
     pub node: Rc<Node>,
     pub specified_values: PropertyMap,
     pub children: Vec<StyledNode<'a>>,
+    /// The properties declared for the `::first-line` pseudo-element of a block, if any rules
+    /// target it.
+    pub first_line: Option<PropertyMap>,
 }
 
 #[derive(PartialEq)]
 pub enum Display {
     Inline,
+    InlineBlock,
     Block,
+    Contents,
     None,
 }
 
@@ -43,10 +49,47 @@ pub enum Clear {
     ClearBoth,
 }
 
+#[derive(PartialEq)]
+pub enum Position {
+    Static,
+    Relative,
+    Absolute,
+    Fixed,
+}
+
+#[derive(PartialEq)]
+pub enum BoxSizing {
+    ContentBox,
+    BorderBox,
+}
+
+#[derive(PartialEq)]
+pub enum Overflow {
+    Visible,
+    Hidden,
+    Clip,
+    Scroll,
+    Auto,
+}
+
+#[derive(PartialEq)]
+pub enum WhiteSpace {
+    Normal,
+    NoWrap,
+    Pre,
+    PreLine,
+}
+
+#[derive(PartialEq)]
+pub enum OverflowWrap {
+    Normal,
+    BreakWord,
+    Anywhere,
+}
+
 static NONE_DISPLAY: [&'static str; 4] = ["head", "meta", "title", "style"];
 static DEFAULT_BLOCK: [&'static str; 11] =
 ["address", "blockquote", "dd", "div", "dl", "form", "p", "ul", "h1", "html", "body"];
-static DEFAULT_INHERIT: [&'static str; 3] = ["color", "font-size", "line-height"];
 
 impl<'a> StyledNode<'a> {
     /// Return the specified value of a property if it exists, otherwise `None`.
@@ -66,7 +109,9 @@ impl<'a> StyledNode<'a> {
         match self.value("display") {
             Some(Value::Keyword(s)) => match s.as_slice() {
                 "block" => Display::Block,
+                "inline-block" => Display::InlineBlock,
                 "none" => Display::None,
+                "contents" => Display::Contents,
                 _ => Display::Inline
             },
             _ => self.default_display()
@@ -103,6 +148,74 @@ impl<'a> StyledNode<'a> {
         }
     }
 
+    /// The value of the `position` property (defaults to static).
+    pub fn position(&self) -> Position {
+        match self.value("position") {
+            Some(Value::Keyword(s)) => match s.as_slice() {
+                "relative" => Position::Relative,
+                "absolute" => Position::Absolute,
+                "fixed" => Position::Fixed,
+                _ => Position::Static
+            },
+            _ => Position::Static
+        }
+    }
+
+    /// The value of the `overflow` property (defaults to visible).
+    pub fn overflow(&self) -> Overflow {
+        match self.value("overflow") {
+            Some(Value::Keyword(s)) => match s.as_slice() {
+                "hidden" => Overflow::Hidden,
+                "clip" => Overflow::Clip,
+                "scroll" => Overflow::Scroll,
+                "auto" => Overflow::Auto,
+                _ => Overflow::Visible
+            },
+            _ => Overflow::Visible
+        }
+    }
+
+    /// The value of the `white-space` property (defaults to normal).
+    pub fn white_space(&self) -> WhiteSpace {
+        match self.value("white-space") {
+            Some(Value::Keyword(s)) => match s.as_slice() {
+                "nowrap" => WhiteSpace::NoWrap,
+                "pre" => WhiteSpace::Pre,
+                "pre-line" => WhiteSpace::PreLine,
+                _ => WhiteSpace::Normal
+            },
+            _ => WhiteSpace::Normal
+        }
+    }
+
+    /// The value of the `overflow-wrap` property (defaults to normal).
+    pub fn overflow_wrap(&self) -> OverflowWrap {
+        match self.value("overflow-wrap") {
+            Some(Value::Keyword(s)) => match s.as_slice() {
+                "break-word" => OverflowWrap::BreakWord,
+                "anywhere" => OverflowWrap::Anywhere,
+                _ => OverflowWrap::Normal
+            },
+            _ => OverflowWrap::Normal
+        }
+    }
+
+    /// The value of the `box-sizing` property (defaults to content-box).
+    pub fn box_sizing(&self) -> BoxSizing {
+        match self.value("box-sizing") {
+            Some(Value::Keyword(ref s)) if s.as_slice() == "border-box" => BoxSizing::BorderBox,
+            _ => BoxSizing::ContentBox
+        }
+    }
+
+    /// The value of the `z-index` property, or zero for `auto`.
+    pub fn z_index(&self) -> i32 {
+        match self.value("z-index") {
+            Some(Value::Length(z, _)) => z as i32,
+            _ => 0
+        }
+    }
+
     pub fn background_color(&self) -> Color {
         assert!(self.tag_name() == "html");
         if let Some(Value::ColorValue(color)) = self.value("background-color") {
@@ -148,42 +261,227 @@ impl<'a> StyledNode<'a> {
 /// Apply a stylesheet to an entire DOM tree, returning a StyledNode tree.
 ///
 /// This finds only the specified values at the moment. Eventually it should be extended to find the
-/// computed values too, including inherited values.
-pub fn style_tree<'a>(root: &'a Rc<Node>, stylesheet: &'a Stylesheet, inherits: &PropertyMap) -> StyledNode<'a> {
+/// computed values too, including inherited values.  The styled nodes own what they refer to, so
+/// the tree doesn't borrow from `root` or `stylesheet`.  Lengths relative to the root font size or
+/// the viewport are already computed, using `context`.
+pub fn style_tree<'a, 'b>(root: &'b Rc<Node>, stylesheet: &'b Stylesheet, inherits: &PropertyMap, context: &LengthContext) -> StyledNode<'a> {
     let values = match root.node_type {
-        NodeType::Element(ref elem) => specified_values(root.clone(), elem, stylesheet, inherits),
+        NodeType::Element(ref elem) => specified_values(root.clone(), elem, stylesheet, inherits, context),
         NodeType::Text(_) => HashMap::new()
     };
+    // Rems are relative to the root element's font size, except in the root's own `font-size`.
+    let child_context = if root.parent().is_none() {
+        context.below_root(values.get("font-size"))
+    } else {
+        *context
+    };
     let new_inherits = get_inherit_style(&values);
 
     let mut new_style_node = StyledNode {
         node: root.clone(),
         specified_values: values,
-        children: root.children.iter().map(|child| style_tree(child, stylesheet, &new_inherits)).collect(),
+        children: root.children.iter().map(|child| style_tree(child, stylesheet, &new_inherits, &child_context)).collect(),
+        first_line: None,
     };
 
     new_style_node.check_none_diplay_node();
+
+    if new_style_node.display() == Display::Block {
+        if let NodeType::Element(ref elem) = root.node_type {
+            if let Some(values) = pseudo_element_values(root.clone(), elem, stylesheet, &new_inherits, &child_context, "first-letter") {
+                split_first_letter(&mut new_style_node.children, values);
+            }
+            new_style_node.first_line = declared_pseudo_element_values(root.clone(), elem, stylesheet, &new_inherits, &child_context, "first-line");
+        }
+    }
     new_style_node
 }
 
 /// Apply styles to a single element, returning the specified styles.
+fn specified_values(node: Rc<Node>, elem: &ElementData, stylesheet: &Stylesheet, inherits: &PropertyMap, context: &LengthContext) -> PropertyMap {
+    let inline_style = parse_style_attribute(elem);
+    let mut values = cascade(matching_rules(node, elem, stylesheet, None), inline_style.as_slice());
+    apply_inherit_style(&mut values, inherits);
+    apply_css_wide_keywords(&mut values, inherits);
+    compute_lengths(&mut values, context);
+    compute_font_size(&mut values, inherits, context);
+    return values;
+}
+
+/// Apply styles to the pseudo-element `name` of an element, which inherits from the element
+/// itself.  Returns `None` if no rules target the pseudo-element.
+fn pseudo_element_values(node: Rc<Node>, elem: &ElementData, stylesheet: &Stylesheet, inherits: &PropertyMap, context: &LengthContext, name: &str) -> Option<PropertyMap> {
+    let rules = matching_rules(node, elem, stylesheet, Some(name));
+    if rules.is_empty() {
+        return None;
+    }
+    let mut values = cascade(rules, &[]);
+    apply_inherit_style(&mut values, inherits);
+    apply_css_wide_keywords(&mut values, inherits);
+    compute_lengths(&mut values, context);
+    compute_font_size(&mut values, inherits, context);
+    Some(values)
+}
+
+/// The properties that the rules for the pseudo-element `name` of an element declare, without
+/// the values it would inherit.  Returns `None` if no rules target the pseudo-element.
 ///
-/// To do: Allow multiple UA/author/user stylesheets, and implement the cascade.
-fn specified_values(node: Rc<Node>, elem: &ElementData, stylesheet: &Stylesheet, inherits: &PropertyMap) -> PropertyMap {
-    let mut values = HashMap::new();
-    let mut rules = matching_rules(node, elem, stylesheet);
+/// This is for `::first-line`, which restyles text that may already have styles of its own from
+/// inline elements on the line.  Only the properties it declares should replace them.
+fn declared_pseudo_element_values(node: Rc<Node>, elem: &ElementData, stylesheet: &Stylesheet, inherits: &PropertyMap, context: &LengthContext, name: &str) -> Option<PropertyMap> {
+    let rules = matching_rules(node, elem, stylesheet, Some(name));
+    if rules.is_empty() {
+        return None;
+    }
+    let mut values = cascade(rules, &[]);
+    apply_css_wide_keywords(&mut values, inherits);
+    compute_lengths(&mut values, context);
+    Some(values)
+}
+
+/// The position of a declaration in the cascade: whether it is `!important`, the precedence of
+/// its origin, whether it comes from a `style` attribute, the specificity of its selector, and its
+/// position in the stylesheet.
+type CascadeOrder = (bool, usize, bool, Specificity, usize);
+
+/// The `CascadeOrder` of a declaration.  Author declarations win over user agent ones, but
+/// `!important` reverses the order of origins, so that user agent `!important` rules win.
+fn cascade_order(important: bool, origin: Origin, inline: bool, specificity: Specificity, order: usize) -> CascadeOrder {
+    let origin_precedence = match (important, origin) {
+        (false, Origin::UserAgent) | (true, Origin::Author) => 0,
+        (false, Origin::Author) | (true, Origin::UserAgent) => 1,
+    };
+    (important, origin_precedence, inline, specificity, order)
+}
 
-    // Go through the rules from lowest to highest specificity.
-    rules.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
-    for &(_, rule) in rules.iter() {
+/// Combine the declarations of the matched rules and of the element's `style` attribute.
+///
+/// This is the only place where the cascade is resolved: every declaration is sorted by its
+/// `CascadeOrder` and applied in turn, so that later declarations win.  `rules` must be in
+/// stylesheet order.
+fn cascade(rules: Vec<MatchedRule>, inline_style: &[Declaration]) -> PropertyMap {
+    let mut declarations: Vec<(CascadeOrder, &Declaration)> = Vec::new();
+    for (order, &(specificity, rule)) in rules.iter().enumerate() {
         for declaration in rule.declarations.iter() {
-            values.insert(declaration.name.clone(), declaration.value.clone());
+            declarations.push((cascade_order(declaration.important, rule.origin, false, specificity, order), declaration));
         }
     }
+    // Declarations in a `style` attribute beat any selector of the same importance.
+    for declaration in inline_style.iter() {
+        declarations.push((cascade_order(declaration.important, Origin::Author, true, (0, 0, 0), 0), declaration));
+    }
 
-    apply_inline_style(&mut values, elem);
-    apply_inherit_style(&mut values, inherits);
-    return values;
+    // The sort is stable, so declarations within a rule keep their order.
+    declarations.sort_by(|&(a, _), &(b, _)| a.cmp(&b));
+
+    let mut values = HashMap::new();
+    for &(_, declaration) in declarations.iter() {
+        values.insert(declaration.name.clone(), declaration.value.clone());
+    }
+    values
+}
+
+/// Move the first letter of a block's text, along with any punctuation around it, into an inline
+/// `::first-letter` box styled with `values`.
+///
+/// Only text directly inside the block is handled, not text nested in an inline element.
+fn split_first_letter<'a>(children: &mut Vec<StyledNode<'a>>, values: PropertyMap) {
+    let (letter, rest) = match children.first() {
+        Some(child) => match child.node.node_type {
+            NodeType::Text(ref text) => match first_letter(text.as_slice()) {
+                Some((letter, rest)) => (letter.to_string(), rest.to_string()),
+                None => return
+            },
+            _ => return
+        },
+        None => return
+    };
+
+    let letter_text = dom::text(letter);
+    let letter_box = StyledNode {
+        node: Rc::new(dom::elem("::first-letter".to_string(), HashMap::new(), vec![letter_text.clone()])),
+        specified_values: values,
+        first_line: None,
+        children: vec![StyledNode { node: letter_text, specified_values: HashMap::new(), children: Vec::new(), first_line: None }],
+    };
+
+    children.remove(0);
+    if !rest.is_empty() {
+        children.insert(0, StyledNode { node: dom::text(rest), specified_values: HashMap::new(), children: Vec::new(), first_line: None });
+    }
+    children.insert(0, letter_box);
+}
+
+/// Split `text` after its first letter, including any punctuation just before or after it.
+/// Leading whitespace is left out, but whitespace between leading punctuation and the letter is
+/// kept with them.
+fn first_letter(text: &str) -> Option<(&str, &str)> {
+    let text = text.trim_left();
+    let is_punctuation = |&: c: char| !c.is_alphanumeric() && !c.is_whitespace();
+
+    let mut end = 0;
+    let mut found_letter = false;
+    for (i, c) in text.char_indices() {
+        if is_punctuation(c) || (!found_letter && c.is_whitespace()) {
+            end = i + c.len_utf8();
+        } else if !found_letter && !c.is_whitespace() {
+            end = i + c.len_utf8();
+            found_letter = true;
+        } else {
+            break;
+        }
+    }
+
+    if found_letter { Some((text.slice_to(end), text.slice_from(end))) } else { None }
+}
+
+/// Resolve the keywords `inherit`, `initial`, and `unset`, which apply to any property.
+///
+/// `unset` means `inherit` for inherited properties and `initial` for the rest.  Only inherited
+/// values are passed down from the parent, so `inherit` on other properties acts like `initial`.
+/// Initial values are the defaults used when a property is absent, so they are removed.
+fn apply_css_wide_keywords(values: &mut PropertyMap, inherits: &PropertyMap) {
+    let names: Vec<String> = values.keys().map(|name| name.clone()).collect();
+    for name in names.iter() {
+        let inherit = match values.get(name) {
+            Some(&Value::Keyword(ref keyword)) => match keyword.as_slice() {
+                "inherit" => true,
+                "initial" => false,
+                "unset" => is_inherited(name.as_slice()),
+                _ => continue
+            },
+            _ => continue
+        };
+
+        values.remove(name);
+        if inherit {
+            if let Some(value) = inherits.get(name) {
+                values.insert(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// Convert lengths relative to the root font size or the viewport to px, so that they are
+/// inherited as computed values.
+fn compute_lengths(values: &mut PropertyMap, context: &LengthContext) {
+    for (_, value) in values.iter_mut() {
+        *value = value.compute_lengths(context);
+    }
+}
+
+/// Resolve a relative `font-size` against the parent's, so that children inherit it in px instead
+/// of compounding the relative size.  Without a parent size, it resolves against the root font
+/// size in `context`.
+fn compute_font_size(values: &mut PropertyMap, inherits: &PropertyMap, context: &LengthContext) {
+    let parent_size = inherits.get("font-size")
+        .and_then(|size| size.to_px(context.root_font_size))
+        .unwrap_or(context.root_font_size);
+    let size = match values.get("font-size") {
+        Some(value @ &Value::Length(..)) | Some(value @ &Value::Function(..)) => value.length_to_px(parent_size, parent_size),
+        _ => return
+    };
+    values.insert("font-size".to_string(), Value::Length(size, css::Unit::Px));
 }
 
 fn apply_inherit_style(values: &mut PropertyMap, inherits: &PropertyMap) {
@@ -197,7 +495,7 @@ fn apply_inherit_style(values: &mut PropertyMap, inherits: &PropertyMap) {
 fn get_inherit_style(values: &PropertyMap) -> PropertyMap {
     let mut inherits = HashMap::new();
     for (name, value) in values.iter() {
-        if DEFAULT_INHERIT.contains(&name.as_slice()) {
+        if is_inherited(name.as_slice()) {
             inherits.insert(name.clone(), value.clone());
         }
     }
@@ -208,29 +506,76 @@ fn get_inherit_style(values: &PropertyMap) -> PropertyMap {
 type MatchedRule<'a> = (Specificity, &'a Rule);
 
 /// Find all CSS rules that match the given element.
-fn matching_rules<'a>(node: Rc<Node>, elem: &ElementData, stylesheet: &'a Stylesheet) -> Vec<MatchedRule<'a>> {
+///
+/// With a `pseudo_element` name, find the rules that target that pseudo-element of the element
+/// instead of the element itself.
+fn matching_rules<'a>(node: Rc<Node>, elem: &ElementData, stylesheet: &'a Stylesheet, pseudo_element: Option<&str>) -> Vec<MatchedRule<'a>> {
     // For now, we just do a linear scan of all the rules.  For large
     // documents, it would be more efficient to store the rules in hash tables
     // based on tag name, id, class, etc.
-    stylesheet.rules.iter().filter_map(|rule| match_rule(node.clone(), elem, rule)).collect()
+    stylesheet.rules.iter().filter_map(|rule| match_rule(node.clone(), elem, rule, pseudo_element)).collect()
 }
 
 /// If `rule` matches `elem`, return a `MatchedRule`. Otherwise return `None`.
-fn match_rule<'a>(node: Rc<Node>, elem: &ElementData, rule: &'a Rule) -> Option<MatchedRule<'a>> {
-    // Find the first (most specific) matching selector.
-    rule.selectors.iter().find(|selector| matches(node.clone(), elem, *selector))
-        .map(|selector| (selector.specificity(), rule))
+fn match_rule<'a>(node: Rc<Node>, elem: &ElementData, rule: &'a Rule, pseudo_element: Option<&str>) -> Option<MatchedRule<'a>> {
+    matching_selector(node, elem, rule, pseudo_element).map(|selector| (selector.specificity(), rule))
+}
+
+/// The first (most specific) selector of `rule` that matches `elem`, if any.
+fn matching_selector<'a>(node: Rc<Node>, elem: &ElementData, rule: &'a Rule, pseudo_element: Option<&str>) -> Option<&'a Selector> {
+    rule.selectors.iter()
+        .find(|selector| selector.pseudo_element() == pseudo_element && matches(node.clone(), elem, *selector))
+}
+
+impl Stylesheet {
+    /// The rules that match `node`, each with its most specific matching selector, like the
+    /// "matched rules" view of a browser's developer tools.
+    ///
+    /// The rules that win in the cascade come first, sorted by the same `CascadeOrder` as
+    /// `cascade`: `!important` rules, then by origin, then higher specificity before lower, and
+    /// later rules before earlier ones.  A rule counts as `!important` if any of its declarations
+    /// is.
+    ///
+    /// This is for tools built on robinson, such as an inspector; the robinson binary itself never
+    /// asks which rules matched, so only the tests call it here.
+    #[allow(dead_code)]
+    pub fn rules_matching(&self, node: &Rc<Node>) -> Vec<(&Selector, &Rule)> {
+        let elem = match node.node_type {
+            NodeType::Element(ref elem) => elem,
+            NodeType::Text(_) => return Vec::new(),
+        };
+
+        let mut matched: Vec<(CascadeOrder, &Selector, &Rule)> = self.rules.iter().enumerate()
+            .filter_map(|(order, rule)| matching_selector(node.clone(), elem, rule, None).map(|selector| {
+                let important = rule.declarations.iter().any(|declaration| declaration.important);
+                (cascade_order(important, rule.origin, false, selector.specificity(), order), selector, rule)
+            }))
+            .collect();
+        matched.sort_by(|&(a, _, _), &(b, _, _)| b.cmp(&a));
+        matched.into_iter().map(|(_, selector, rule)| (selector, rule)).collect()
+    }
+}
+
+/// Does `node` match `selector`? Text nodes, and selectors for pseudo-elements, never match.
+pub fn matches_node(node: &Rc<Node>, selector: &Selector) -> bool {
+    if selector.pseudo_element().is_some() {
+        return false;
+    }
+    match node.node_type {
+        NodeType::Element(ref elem) => matches(node.clone(), elem, selector),
+        NodeType::Text(_) => false,
+    }
 }
 
 /// Selector matching:
 fn matches(node: Rc<Node>, elem: &ElementData, selector: &Selector) -> bool {
     match *selector {
-        Selector::Simple(ref simple_selector) => matches_simple_selector(elem, simple_selector),
+        Selector::Simple(ref simple_selector) => matches_simple_selector(&node, elem, simple_selector),
         Selector::Descendant(ref descendant_selector) => matches_descendant_selector(node, elem, descendant_selector.as_slice())
     }
 }
 
-fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> bool {
+fn matches_simple_selector(node: &Rc<Node>, elem: &ElementData, selector: &SimpleSelector) -> bool {
     // Check type selector
     if selector.tag_name.iter().any(|name| elem.tag_name != *name) {
         return false;
@@ -247,57 +592,92 @@ fn matches_simple_selector(elem: &ElementData, selector: &SimpleSelector) -> boo
         return false;
     }
 
+    // Check attribute selectors
+    if selector.attributes.iter().any(|attr| match elem.get_attribute(attr.name.as_slice()) {
+        Some(value) => !attr.operator.matches(value),
+        None => true
+    }) {
+        return false;
+    }
+
+    // Check pseudo-classes
+    if selector.pseudo_classes.iter().any(|pseudo_class| !matches_pseudo_class(node, elem, pseudo_class)) {
+        return false;
+    }
+
     // We didn't find any non-matching selector components.
     return true;
 }
 
-fn matches_descendant_selector(node: Rc<Node>, elem: &ElementData, selector: &[SimpleSelector]) -> bool {
+fn matches_pseudo_class(node: &Rc<Node>, elem: &ElementData, pseudo_class: &PseudoClass) -> bool {
+    match *pseudo_class {
+        PseudoClass::Is(ref selectors) | PseudoClass::Where(ref selectors) =>
+            selectors.iter().any(|selector| matches(node.clone(), elem, selector)),
+        PseudoClass::FirstChild => node.element_position().0 == 1,
+        PseudoClass::LastChild => {
+            let (index, count) = node.element_position();
+            index == count
+        }
+        PseudoClass::NthChild(a, b) => {
+            let index = node.element_position().0 as i32;
+            if a == 0 {
+                index == b
+            } else {
+                (index - b) % a == 0 && (index - b) / a >= 0
+            }
+        }
+    }
+}
+
+fn matches_descendant_selector(node: Rc<Node>, elem: &ElementData, selector: &[(Combinator, SimpleSelector)]) -> bool {
     assert!(selector.len() > 1);
 
-    if !matches_simple_selector(elem, selector.last().unwrap()) {
+    let (combinator, ref subject) = *selector.last().unwrap();
+    if !matches_simple_selector(&node, elem, subject) {
         return false;
     }
 
     let current_selector = selector.slice(0, selector.len() - 1);
-    return matches_ancestor(node, current_selector);
-}
-
-fn matches_ancestor(node: Rc<Node>, selector: &[SimpleSelector]) -> bool {
-    let mut current_node = node;
-    let mut matching_node: Option<Rc<Node>> = None;
-    loop {
-        match get_parent(&current_node) {
-            Some(parent_node) => {
-                if let NodeType::Element(ref parent_elem) = parent_node.node_type {
-                    if matches_simple_selector(parent_elem, selector.last().unwrap()) {
-                        matching_node = Some(parent_node.clone());
-                        break;
-                    }
-                    current_node = parent_node.clone();
-                }
-            },
-            None => break,
-        }
-    }
+    return matches_related(node, combinator, current_selector);
+}
 
-    match matching_node {
-        Some(_) => if selector.len() == 1 {
-            return true
-        },
-        None => return false,
-    }
+/// Does an element related to `node` by `combinator` match the rest of `selector`?  Every
+/// candidate is tried, in case a nearer match fails further along the chain.
+fn matches_related(node: Rc<Node>, combinator: Combinator, selector: &[(Combinator, SimpleSelector)]) -> bool {
+    let (next_combinator, ref simple) = *selector.last().unwrap();
+    let rest = selector.slice(0, selector.len() - 1);
 
-    return matches_ancestor(matching_node.unwrap(), selector.slice(0, selector.len() - 1));
+    let candidates: Vec<Rc<Node>> = match combinator {
+        Combinator::Descendant => ancestors(&node),
+        Combinator::Child => get_parent(&node).into_iter().collect(),
+        Combinator::NextSibling => node.previous_element_siblings().into_iter().take(1).collect(),
+        Combinator::SubsequentSibling => node.previous_element_siblings(),
+    };
+    candidates.into_iter().any(|candidate| match candidate.node_type {
+        NodeType::Element(ref candidate_elem) =>
+            matches_simple_selector(&candidate, candidate_elem, simple) &&
+            (rest.is_empty() || matches_related(candidate.clone(), next_combinator, rest)),
+        NodeType::Text(_) => false,
+    })
 }
 
-fn get_parent(node: &Rc<Node>) -> Option<Rc<Node>> {
-    if node.parent.borrow().is_empty() {
-        return None;
+/// The ancestors of `node`, nearest first.
+fn ancestors(node: &Rc<Node>) -> Vec<Rc<Node>> {
+    let mut result = Vec::new();
+    let mut current_node = get_parent(node);
+    while let Some(parent_node) = current_node {
+        current_node = get_parent(&parent_node);
+        result.push(parent_node);
     }
-    node.parent.borrow().last().unwrap().upgrade()
+    result
 }
 
-fn apply_inline_style(values: &mut PropertyMap, elem: &ElementData) {
+fn get_parent(node: &Rc<Node>) -> Option<Rc<Node>> {
+    node.parent()
+}
+
+/// Parse the declarations in an element's `style` attribute.
+fn parse_style_attribute(elem: &ElementData) -> Vec<Declaration> {
     if let Some(style_string) = elem.attributes.get("style") {
         let mut last_idx;
         let mut source = style_string.clone();
@@ -310,32 +690,396 @@ fn apply_inline_style(values: &mut PropertyMap, elem: &ElementData) {
         last_idx = source.len();
         source.insert(last_idx, '}');
 
-        for decl in css::parse_inline_style(source).into_iter() {
-            values.insert(decl.name, decl.value);
+        return css::parse_inline_style(source);
+    }
+    Vec::new()
+}
+
+/// Describe a styled node and all its descendants, listing each node's specified values in order
+/// of name beneath it.
+pub fn dump(style_node: &StyledNode, depth: usize) -> String {
+    let mut indent = String::new();
+    for _ in range(0us, depth) {
+        indent.push_str("--");
+    }
+    let mut result = format!("{}{}", indent, dom::dump(&style_node.node));
+
+    let mut values: Vec<(&String, &Value)> = style_node.specified_values.iter().collect();
+    values.sort_by(|a, b| a.0.cmp(b.0));
+    for (key, value) in values.into_iter() {
+        let line = match *value {
+            Value::Keyword(ref value_string) => format!("{}: {}\n", key, value_string),
+            Value::Length(ref len, ref unit) => format!("{}: {}{}\n", key, len, unit_string(unit)),
+            Value::ColorValue(ref col) => format!("{}: {} {} {}\n", key, col.r, col.g, col.b),
+            Value::List(ref values) => format!("{}: {:?}\n", key, values),
+            Value::Function(ref name, ref args) => format!("{}: {}({:?})\n", key, name, args),
+        };
+        result.push_str(format!("{}  {}", indent, line).as_slice());
+    }
+
+    for i in style_node.children.iter() {
+        result.push_str(dump(i, depth + 1).as_slice());
+    }
+    result
+}
+
+fn unit_string(unit: &css::Unit) -> &'static str {
+    match *unit {
+        css::Unit::Px => "px",
+        css::Unit::Em => "em",
+        css::Unit::Rem => "rem",
+        css::Unit::Pt => "pt",
+        css::Unit::Vw => "vw",
+        css::Unit::Vh => "vh",
+        css::Unit::Percent => "%",
+        css::Unit::S => "s",
+        css::Unit::Ms => "ms",
+        css::Unit::Default => "",
+    }
+}
+
+/// Write a value back out as CSS text.
+fn css_text(value: &Value) -> String {
+    match *value {
+        Value::Keyword(ref keyword) => keyword.clone(),
+        Value::Length(len, ref unit) => format!("{}{}", len, unit_string(unit)),
+        Value::ColorValue(color) =>
+            format!("rgba({}, {}, {}, {})", color.r, color.g, color.b, color.a as f32 / 255.0),
+        Value::List(ref values) => {
+            let values: Vec<String> = values.iter().map(|value| css_text(value)).collect();
+            values.connect(", ")
+        }
+        Value::Function(ref name, ref args) => {
+            let args: Vec<String> = args.iter().map(|arg| css_text(arg)).collect();
+            format!("{}({})", name, args.connect(", "))
         }
     }
 }
 
-pub fn show(style_node: &StyledNode, depth: usize) {
-    dom::show(&style_node.node);
+/// Describe a styled node and all its descendants as JSON, for tools that inspect the rendered
+/// output.  Each element has its tag name, its specified values, and its children; each text node
+/// has its text.
+///
+/// With `data_attributes`, elements also list their `data-*` attributes, so that the output can be
+/// matched up with the source document.
+pub fn to_json(style_node: &StyledNode, data_attributes: bool) -> String {
+    let elem = match style_node.node.node_type {
+        NodeType::Element(ref elem) => elem,
+        NodeType::Text(ref text) => return format!("{{\"text\": {}}}", json_string(text.as_slice())),
+    };
 
-    for (key, value) in style_node.specified_values.iter() {
-        match *value {
-            Value::Keyword(ref value_string) => println!("{}: {}", key, value_string),
-            Value::Length(ref len, ref unit) => {
-                let unit_string = match unit {
-                    &css::Unit::Px => "px",
-                    &css::Unit::Em => "em",
-                    &css::Unit::Percent => "%",
-                    &css::Unit::Default => "",
-                };
-                println!("{}: {}{}", key, len, unit_string);
-            }
-            Value::ColorValue(ref col) => println!("{}: {} {} {}", key, col.r, col.g, col.b),
+    let mut names: Vec<&String> = style_node.specified_values.keys().collect();
+    names.sort();
+    let styles: Vec<String> = names.iter().map(|name| {
+        format!("{}: {}", json_string(name.as_slice()), json_string(css_text(style_node.specified_values.get(*name).unwrap()).as_slice()))
+    }).collect();
+
+    let mut json = format!("{{\"tag\": {}, \"styles\": {{{}}}", json_string(elem.tag_name.as_slice()), styles.connect(", "));
+
+    if data_attributes {
+        let mut names: Vec<&String> = elem.attributes.keys().filter(|name| name.starts_with("data-")).collect();
+        names.sort();
+        let data: Vec<String> = names.iter().map(|name| {
+            format!("{}: {}", json_string(name.as_slice()), json_string(elem.attributes.get(*name).unwrap().as_slice()))
+        }).collect();
+        json.push_str(format!(", \"data\": {{{}}}", data.connect(", ")).as_slice());
+    }
+
+    let children: Vec<String> = style_node.children.iter().map(|child| to_json(child, data_attributes)).collect();
+    json.push_str(format!(", \"children\": [{}]}}", children.connect(", ")).as_slice());
+    json
+}
+
+/// Quote and escape a string for JSON.
+pub fn json_string(string: &str) -> String {
+    let mut quoted = String::from_str("\"");
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(format!("\\u{:04x}", c as u32).as_slice()),
+            c => quoted.push(c),
         }
     }
+    quoted.push('"');
+    quoted
+}
 
-    for i in style_node.children.iter() {
-        show(i, depth + 1);
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::rc::Rc;
+    use super::{style_tree, first_letter, to_json, dump, StyledNode, Display};
+    use css;
+    use css::{Value, Unit, LengthContext, Origin};
+    use color::Color;
+    use dom;
+    use dom::Node;
+    use html;
+
+    /// Parse an HTML document and link each node to its parent.
+    fn document(source: &str) -> Rc<Node> {
+        let root = html::parse(source.to_string());
+        dom::set_parents(&root);
+        root
+    }
+
+    #[test]
+    fn first_line_keeps_only_declared_properties() {
+        let root = document("<p><span>text</span></p>");
+        let (stylesheet, _) = css::parse("p { color: blue } p::first-line { text-decoration: underline }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let first_line = styled.first_line.as_ref().unwrap();
+        assert_eq!(first_line.get("text-decoration"), Some(&Value::Keyword("underline".to_string())));
+        assert!(first_line.get("color").is_none());
+    }
+
+    #[test]
+    fn rem_and_viewport_lengths_are_computed() {
+        let root = document("<div><p></p></div>");
+        let (stylesheet, _) = css::parse("div { font-size: 2rem } p { width: 2rem; height: 10vh; margin-left: 1.5em }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let p = &styled.children[0];
+        // The root's own rems are relative to the default font size.
+        assert_eq!(styled.value("font-size"), Some(Value::Length(20.0, Unit::Px)));
+        assert_eq!(p.value("width"), Some(Value::Length(40.0, Unit::Px)));
+        assert_eq!(p.value("height"), Some(Value::Length(60.0, Unit::Px)));
+        // Ems depend on the element's font size, so they are left for layout.
+        assert_eq!(p.value("margin-left"), Some(Value::Length(1.5, Unit::Em)));
+    }
+
+    #[test]
+    fn structural_pseudo_classes_count_only_elements() {
+        let root = document("<ul><li></li> text <li></li><li></li><li></li></ul>");
+        let (stylesheet, _) = css::parse("li:first-child { width: 1px } li:last-child { height: 1px } \
+                                          li:nth-child(2n) { margin-left: 1px }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let items: Vec<&StyledNode> = styled.children.iter().filter(|child| child.node.is_element()).collect();
+        let has = |&: item: &StyledNode, name: &str| item.value(name).is_some();
+        let first: Vec<bool> = items.iter().map(|item| has(*item, "width")).collect();
+        let last: Vec<bool> = items.iter().map(|item| has(*item, "height")).collect();
+        let even: Vec<bool> = items.iter().map(|item| has(*item, "margin-left")).collect();
+        assert_eq!(first, vec![true, false, false, false]);
+        assert_eq!(last, vec![false, false, false, true]);
+        assert_eq!(even, vec![false, true, false, true]);
+    }
+
+    #[test]
+    fn important_beats_specificity() {
+        let root = document("<p id=\"a\"></p>");
+        let (stylesheet, _) = css::parse("p { color: red !important } #a { color: blue }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        assert_eq!(styled.value("color"), Some(Value::ColorValue(Color::new(255, 0, 0, 255))));
+    }
+
+    #[test]
+    fn user_agent_important_beats_author_important() {
+        let root = document("<p></p>");
+        let (mut stylesheet, _) = css::parse("p { color: red !important } p { color: blue !important }".to_string());
+        let first = stylesheet.rules.iter().position(|rule| rule.origin == Origin::Author).unwrap();
+        stylesheet.rules[first].origin = Origin::UserAgent;
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        assert_eq!(styled.value("color"), Some(Value::ColorValue(Color::new(255, 0, 0, 255))));
+    }
+
+    #[test]
+    fn rules_matching_sorts_by_cascade_order() {
+        let root = document("<p id=\"a\" class=\"b\"></p>");
+        let (stylesheet, _) = css::parse("#a { width: 1px } .b { width: 2px } p { width: 3px } \
+                                          p { width: 4px !important } .b { width: 5px }".to_string());
+        let widths: Vec<Value> = stylesheet.rules_matching(&root).iter()
+            .map(|&(_, rule)| rule.declarations[0].value.clone())
+            .collect();
+        let px = |&: width: f32| Value::Length(width, Unit::Px);
+        assert_eq!(widths, vec![px(4.0), px(1.0), px(5.0), px(2.0), px(3.0)]);
+    }
+
+    #[test]
+    fn first_letter_skips_whitespace_and_keeps_punctuation() {
+        assert_eq!(first_letter("\n\t Hello"), Some(("H", "ello")));
+        assert_eq!(first_letter("\u{201C} Hi,\u{201D} she said"), Some(("\u{201C} H", "i,\u{201D} she said")));
+        assert_eq!(first_letter("A. B"), Some(("A.", " B")));
+        assert_eq!(first_letter(" \t...\n"), None);
+    }
+
+    #[test]
+    fn important_shorthand_beats_a_normal_longhand_on_every_side() {
+        let root = document("<p id=\"a\"></p>");
+        let (stylesheet, _) = css::parse("p { margin: 0px !important } #a { margin-left: 5px; margin-top: 5px }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        for side in ["margin-top", "margin-right", "margin-bottom", "margin-left"].iter() {
+            assert_eq!(styled.value(*side), Some(Value::Length(0.0, Unit::Px)));
+        }
+    }
+
+    #[test]
+    fn later_declaration_in_a_block_wins() {
+        let root = document("<p></p>");
+        let (stylesheet, _) = css::parse("p { color: red; color: blue }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        assert_eq!(styled.value("color"), Some(Value::ColorValue(Color::new(0, 0, 255, 255))));
+    }
+
+    #[test]
+    fn shorthand_and_longhand_apply_in_declaration_order() {
+        let root = document("<div><p></p></div>");
+        let (stylesheet, _) = css::parse("div { margin-left: 5px; margin: 1px } p { margin: 1px; margin-left: 5px }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        assert_eq!(styled.value("margin-left"), Some(Value::Length(1.0, Unit::Px)));
+        let p = &styled.children[0];
+        assert_eq!(p.value("margin-left"), Some(Value::Length(5.0, Unit::Px)));
+        assert_eq!(p.value("margin-right"), Some(Value::Length(1.0, Unit::Px)));
+    }
+
+    #[test]
+    fn display_unset_gives_each_element_its_default_display() {
+        let root = document("<div><div></div><span></span></div>");
+        let (stylesheet, _) = css::parse("div { display: inline-block } span { display: block } \
+                                          div div, span { display: unset }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        assert!(styled.display() == Display::InlineBlock);
+        assert!(styled.children[0].display() == Display::Block);
+        assert!(styled.children[1].display() == Display::Inline);
+    }
+
+    #[test]
+    fn cascade_weighs_importance_then_origin_then_specificity() {
+        let root = document("<p id=\"a\"></p>");
+        let context = LengthContext::new(800.0, 600.0);
+        let width = |&: css: &str, user_agent_rules: usize| -> Option<Value> {
+            let (mut stylesheet, _) = css::parse(css.to_string());
+            // The first `user_agent_rules` of the sheet's own rules come from the user agent.
+            let first = stylesheet.rules.iter().position(|rule| rule.origin == Origin::Author).unwrap();
+            for rule in stylesheet.rules.slice_mut(first, first + user_agent_rules).iter_mut() {
+                rule.origin = Origin::UserAgent;
+            }
+            let styled = style_tree(&root, &stylesheet, &HashMap::new(), &context);
+            styled.value("width")
+        };
+        let px = |&: f: f32| Some(Value::Length(f, Unit::Px));
+
+        // A normal author rule beats a more specific normal user agent rule.
+        assert_eq!(width("#a { width: 1px } p { width: 2px }", 1), px(2.0));
+        // An important user agent rule beats a normal author rule.
+        assert_eq!(width("p { width: 1px !important } #a { width: 2px }", 1), px(1.0));
+        // An important author rule beats a more specific normal one.
+        assert_eq!(width("p { width: 1px !important } #a { width: 2px }", 0), px(1.0));
+        // Within one origin and importance, specificity wins, then order.
+        assert_eq!(width("#a { width: 1px } p { width: 2px }", 0), px(1.0));
+        assert_eq!(width("#a { width: 1px !important } p { width: 2px !important }", 0), px(1.0));
+        assert_eq!(width("p { width: 1px } p { width: 2px }", 0), px(2.0));
+    }
+
+    #[test]
+    fn is_matches_any_of_its_selectors() {
+        let root = document("<div><p class=\"a\"></p><p class=\"b\"></p><p class=\"c\"></p></div>");
+        let (stylesheet, _) = css::parse("p:is(.a, .b) { width: 1px }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        assert_eq!(styled.children[0].value("width"), Some(Value::Length(1.0, Unit::Px)));
+        assert_eq!(styled.children[1].value("width"), Some(Value::Length(1.0, Unit::Px)));
+        assert_eq!(styled.children[2].value("width"), None);
+    }
+
+    #[test]
+    fn where_adds_no_specificity_but_is_adds_its_most_specific_argument() {
+        let root = document("<div><p id=\"x\" class=\"a\"></p></div>");
+        let (stylesheet, _) = css::parse(":where(#x) { width: 1px } p { width: 2px } \
+                                          :is(.a, #x) { height: 1px } p.a { height: 2px }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let p = &styled.children[0];
+        assert_eq!(p.value("width"), Some(Value::Length(2.0, Unit::Px)));
+        assert_eq!(p.value("height"), Some(Value::Length(1.0, Unit::Px)));
+    }
+
+    #[test]
+    fn json_includes_data_attributes_when_asked() {
+        let root = document("<div><p data-testid=\"price\" id=\"a\"></p></div>");
+        let (stylesheet, _) = css::parse("p { color: red }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+
+        let json = to_json(&styled, true);
+        assert!(json.as_slice().contains("\"data\": {\"data-testid\": \"price\"}"));
+        assert!(!json.as_slice().contains("\"id\""));
+
+        assert!(!to_json(&styled, false).as_slice().contains("data-testid"));
+    }
+
+    #[test]
+    fn child_combinator_matches_only_direct_children() {
+        let root = document("<ul><li><ol><li></li></ol></li></ul>");
+        let (stylesheet, _) = css::parse("ul > li { width: 1px } ul li { height: 1px }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let child = &styled.children[0];
+        let grandchild = &child.children[0].children[0];
+        assert_eq!(child.value("width"), Some(Value::Length(1.0, Unit::Px)));
+        assert_eq!(grandchild.value("width"), None);
+        // The descendant combinator still matches both.
+        assert_eq!(grandchild.value("height"), Some(Value::Length(1.0, Unit::Px)));
+    }
+
+    #[test]
+    fn sibling_combinators_match_following_siblings() {
+        let root = document("<div><h1></h1> text <p></p><span></span><p></p></div>");
+        let (stylesheet, _) = css::parse("h1 + p { width: 1px } h1 ~ p { height: 1px } h1 + span { color: red }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let elements: Vec<&StyledNode> = styled.children.iter().filter(|child| child.node.is_element()).collect();
+        let (first_p, span, second_p) = (elements[1], elements[2], elements[3]);
+
+        // Text between siblings doesn't separate them.
+        assert_eq!(first_p.value("width"), Some(Value::Length(1.0, Unit::Px)));
+        assert_eq!(first_p.value("height"), Some(Value::Length(1.0, Unit::Px)));
+
+        // `+` needs the very next element; `~` allows others in between.
+        assert_eq!(second_p.value("width"), None);
+        assert_eq!(second_p.value("height"), Some(Value::Length(1.0, Unit::Px)));
+        assert_eq!(span.value("color"), None);
+    }
+
+    #[test]
+    fn attribute_selectors_check_each_operator() {
+        let root = document("<div><a href=\"https://a\" rel=\"nofollow external\" title=\"x\"></a>\
+                             <a href=\"http://b\" rel=\"externals\"></a></div>");
+        let (stylesheet, _) = css::parse("[title] { width: 1px } [rel~=external] { height: 1px } \
+                                          a[href^=\"https\"] { z-index: 1 } [href=\"http://b\"] { color: red }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let (secure, plain) = (&styled.children[0], &styled.children[1]);
+
+        assert_eq!(secure.value("width"), Some(Value::Length(1.0, Unit::Px)));
+        assert_eq!(plain.value("width"), None);
+
+        assert_eq!(secure.value("height"), Some(Value::Length(1.0, Unit::Px)));
+        assert_eq!(plain.value("height"), None);
+
+        assert_eq!(secure.z_index(), 1);
+        assert_eq!(plain.z_index(), 0);
+
+        assert_eq!(secure.value("color"), Some(Value::ColorValue(Color::new(0, 0, 255, 255))));
+        assert_eq!(plain.value("color"), Some(Value::ColorValue(Color::new(255, 0, 0, 255))));
+    }
+
+    #[test]
+    fn all_initial_resets_inherited_values_but_not_direction() {
+        let root = document("<div><p></p></div>");
+        let (stylesheet, _) = css::parse("div { color: red; font-size: 20px; direction: rtl } p { all: initial }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let p = &styled.children[0];
+        assert_eq!(p.value("color"), None);
+        assert_eq!(p.value("font-size"), None);
+        assert_eq!(p.value("direction"), Some(Value::Keyword("rtl".to_string())));
+    }
+
+    #[test]
+    fn dump_indents_nodes_and_their_values_by_depth() {
+        let root = document("<div><p></p></div>");
+        let (stylesheet, _) = css::parse("div { width: 10px } p { color: red }".to_string());
+        let styled = style_tree(&root, &stylesheet, &HashMap::new(), &LengthContext::new(800.0, 600.0));
+        let dumped = dump(&styled, 0);
+        let lines: Vec<&str> = dumped.as_slice().lines().collect();
+        let position = |&: line: &str| lines.iter().position(|l| *l == line).unwrap();
+        assert_eq!(lines[0], " Element: div -> No parent");
+        assert!(position("  width: 10px") < position("-- Element: p -> parent: div"));
+        assert!(position("-- Element: p -> parent: div") < position("--  color: 255 0 0"));
     }
 }
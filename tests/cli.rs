@@ -0,0 +1,73 @@
+//! Tests that run the robinson binary on the example page.
+
+#![allow(unstable)]
+
+use std::io::TempDir;
+use std::io::process::{Command, ProcessOutput};
+use std::os::self_exe_path;
+
+/// Run robinson with `args`, writing its image into a fresh temporary directory.
+fn run(args: &[&str]) -> ProcessOutput {
+    let dir = TempDir::new("robinson").unwrap();
+    let output = dir.path().join("output.png");
+    let binary = self_exe_path().unwrap().join("robinson");
+    Command::new(binary)
+        .arg("-o").arg(output.as_str().unwrap())
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+#[test]
+fn quiet_mode_prints_nothing_to_stdout() {
+    let output = run(&["-q"]);
+    assert!(output.status.success());
+    assert!(output.output.is_empty());
+
+    // Without it, the trees and the output filename are printed.
+    let output = run(&[]);
+    assert!(output.status.success());
+    assert!(!output.output.is_empty());
+}
+
+#[test]
+fn profile_reports_each_phase_on_stderr() {
+    let output = run(&["-q", "--profile"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.error).unwrap();
+    for phase in ["parse: ", "style: ", "layout: ", "paint: "].iter() {
+        assert!(stderr.as_slice().contains(*phase), "missing {} in {}", phase, stderr);
+    }
+}
+
+#[test]
+fn dump_display_list_prints_the_commands_as_json() {
+    let output = run(&["-q", "--dump-display-list"]);
+    assert!(output.status.success());
+    let stderr = String::from_utf8(output.error).unwrap();
+    assert!(stderr.as_slice().lines().any(|line| line.starts_with("[{\"type\": ")));
+    assert!(stderr.as_slice().contains("\"type\": \"solid-color\""));
+    assert!(stderr.as_slice().contains("\"type\": \"text\""));
+
+    // Without the flag, the display list isn't printed.
+    let output = run(&["-q"]);
+    assert!(!String::from_utf8(output.error).unwrap().as_slice().contains("\"type\": "));
+}
+
+#[test]
+fn invalid_background_is_reported_without_panicking() {
+    let output = run(&["-q", "-b", "notacolor"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.error).unwrap();
+    assert!(stderr.as_slice().contains("Invalid background color: notacolor"));
+    assert!(!stderr.as_slice().contains("panicked"));
+}
+
+#[test]
+fn invalid_time_is_reported_without_panicking() {
+    let output = run(&["-q", "-t", "soon"]);
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.error).unwrap();
+    assert!(stderr.as_slice().contains("Invalid time: soon"));
+    assert!(!stderr.as_slice().contains("panicked"));
+}